@@ -0,0 +1,3336 @@
+//! The Server itself: the connections, the rooms, the moderation and the admin commands.
+//!
+//! Nothing in here owns the Poll or the listeners. src/server.rs wires the Server up to them, and
+//! the connections only need to be a Transport, so the tests can drive the Server in memory.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, Shutdown};
+use std::result;
+use std::io::{Read, Write};
+use std::fmt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Instant, SystemTime, Duration, UNIX_EPOCH};
+use getrandom::getrandom;
+use std::fmt::Write as OtherWrite;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::mem;
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::sync::mpsc::{self, Sender};
+use mio::net::TcpStream;
+use mio::event::Source;
+use mio::{Registry, Interest, Token};
+use rustls::{ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use std::sync::Arc;
+use unicode_width::UnicodeWidthStr;
+use crate::protocol::{FrameReader, FRAME_SEPARATOR, DEFAULT_PORT, ECHO_TAG, PING_TAG, PONG_TAG, DM_TAG, HISTORY_TAG, ERROR_TAG, DISCONNECT_TAG, SEQUENCE_TAG, DEFAULT_ROOM, WELCOME, WIDTH_LIMIT, FRAME_LIMIT, MESSAGE_RATE, sanitize, fit_width, clock, stamp, rfc3339};
+use crate::moderation::{MessageBucket, StrikeReason, Sinner, ban_expired, BAN_LIMIT};
+use crate::sens::{Sens, safe_mode_from_env};
+use crate::tls::fingerprint;
+use crate::websocket::WebSocket;
+use crate::proxy::{parse_header as parse_proxy_header, HEADER_LIMIT as PROXY_HEADER_LIMIT};
+
+type Result<T> = result::Result<T, ()>;
+
+pub const SIGNALS: Token = Token(0);
+/// The listeners take the tokens right after SIGNALS, one for each of the ORIGINS, and the
+/// connections take the rest
+const FIRST_LISTENER: usize = 1;
+const ORIGINS: [Origin; 3] = [Origin::Chat, Origin::WebSocket, Origin::Admin];
+// NOTE: on most systems binding to [::] accepts IPv4 connections too, use `--bind 0.0.0.0` for
+// IPv4 only
+pub const DEFAULT_BIND: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+// NOTE: BAN_LIMIT, MESSAGE_RATE, SLOWLORIS_LIMIT and STRIKE_LIMIT are only the defaults, see Config
+const SLOWLORIS_LIMIT: Duration = Duration::from_millis(200);
+const STRIKE_LIMIT: usize = 10;
+/// How many reconnects of a banned IP are told about the ban before they are just dropped
+const BAN_NOTICE_LIMIT: usize = 3;
+/// The longest ban and the longest duration in Config, so the moment it is over fits into both
+/// the monotonic and the wall clock
+const DURATION_LIMIT: Duration = Duration::from_secs(10*365*24*60*60);
+/// The longest line that is sent to a room, so one message can't blow up the outgoing
+/// queues of all its members
+const BROADCAST_LIMIT: usize = FRAME_LIMIT + NICK_LIMIT + 16;
+const TOKEN_BYTES: usize = 16;
+/// Each byte of the token is encoded as 2 hex digits
+const TOKEN_LEN: usize = TOKEN_BYTES*2;
+/// Whitespace tolerated around the token, like the padding some scripts put there
+const TOKEN_PADDING: usize = 16;
+const NICK_LIMIT: usize = 32;
+/// How many nicks /list shows at most
+const LIST_LIMIT: usize = 50;
+const ROOM_LIMIT: usize = 32;
+const CONNECTION_RATE_LIMIT: usize = 10;
+const CONNECTION_RATE_WINDOW: Duration = Duration::from_secs(10);
+const WAITING_LIMIT: usize = 512;
+const WAITING_PER_IP_LIMIT: usize = 5;
+const OUTGOING_LIMIT: usize = 256*1024;
+/// Whether to tell the room when someone leaves it because of a kick, a ban, etc
+const ANNOUNCE_MODERATION: bool = false;
+const HISTORY_LIMIT: usize = 100;
+const HISTORY_BYTES_LIMIT: usize = 64*1024;
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+/// Clients that did not send anything (including the answers to the pings) for that long are
+/// considered dead
+const IDLE_LIMIT: Duration = Duration::from_secs(5*60);
+/// The event log is moved to `<path>.1` once it grows that big
+const LOG_ROTATE_SIZE: u64 = 10*1024*1024;
+const BANNED_FILE_PATH: &str = "./BANNED";
+const TOKENS_FILE_PATH: &str = "./TOKENS";
+const MOTD_FILE_PATH: &str = "./MOTD";
+const MOTD_LIMIT: usize = 4*1024;
+/// How long the token replaced by /rotate-token keeps working
+const ROTATE_GRACE: Duration = Duration::from_secs(60);
+/// How long the TLS clients get for the handshake on top of Config::slowloris_limit
+const TLS_HANDSHAKE_LIMIT: Duration = Duration::from_secs(5);
+const WEBSOCKET_HANDSHAKE_LIMIT: Duration = Duration::from_secs(5);
+/// Longer WebSocket messages are not even buffered, the shorter ones over FRAME_LIMIT are treated
+/// the same way as from the raw TCP clients
+const WEBSOCKET_MESSAGE_LIMIT: usize = 4*FRAME_LIMIT;
+/// The noisy log lines about an IP are logged at most once per that long, see LogThrottle
+const LOG_THROTTLE_WINDOW: Duration = Duration::from_secs(60);
+/// How many IPs each LogThrottle keeps track of at once
+const LOG_THROTTLE_LIMIT: usize = 1024;
+
+/// The socket under a connection. mio's TcpStream in the Server and an in-memory stream in the
+/// tests. Behaves like a non-blocking socket, so reading and writing return WouldBlock instead of
+/// waiting. The registration in the Poll may do nothing if there is nothing to poll
+pub trait Transport: Read + Write + Source {
+    /// Reads without consuming, like TcpStream::peek()
+    fn peek(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buffer)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// Which listener accepted the connection
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Chat,
+    /// Config::websocket_port
+    WebSocket,
+    /// Config::admin_port
+    Admin,
+}
+
+impl Origin {
+    pub fn token(self) -> Token {
+        Token(FIRST_LISTENER + self as usize)
+    }
+}
+
+struct Connection {
+    stream: Box<dyn Transport>,
+    addr: SocketAddr,
+    origin: Origin,
+    /// The TLS session on top of the stream, if the Server runs with Config::tls
+    tls: Option<Box<ServerConnection>>,
+    /// The WebSocket session on top of the stream (and the TLS) for the browser clients
+    websocket: Option<WebSocket>,
+    frames: FrameReader,
+    /// Bytes waiting for the connection to become writable
+    outgoing: Vec<u8>,
+    /// Sizes of the lines (or WebSocket frames) in the outgoing bytes
+    lines: VecDeque<usize>,
+    /// The first of the lines is partially written already
+    torn: bool,
+    /// Config::outgoing_limit
+    outgoing_limit: usize,
+    /// The outgoing bytes hit the limit, so nothing else is queued until the client is dropped
+    lagging: bool,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn Transport>, addr: SocketAddr, origin: Origin, tls: Option<Box<ServerConnection>>, outgoing_limit: usize) -> Self {
+        Self {
+            stream,
+            addr,
+            origin,
+            tls,
+            websocket: (origin == Origin::WebSocket).then(WebSocket::default),
+            frames: FrameReader::default(),
+            outgoing: Vec::new(),
+            lines: VecDeque::new(),
+            torn: false,
+            outgoing_limit,
+            lagging: false,
+        }
+    }
+
+    /// Reads the bytes sent by the client decrypting and unframing them if needed. Behaves like
+    /// Transport::read(), including returning WouldBlock once there is nothing left to read
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let websocket = if let Some(websocket) = &mut self.websocket {
+            websocket
+        } else {
+            return read_transport(&mut self.stream, &mut self.tls, buffer)
+        };
+        let mut raw = [0; 1024];
+        loop {
+            let n = websocket.read(buffer);
+            if n > 0 {
+                return Ok(n);
+            }
+            if websocket.is_closed() {
+                return Ok(0);
+            }
+            let n = read_transport(&mut self.stream, &mut self.tls, &mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            // NOTE: the handshake response and the pongs are written by Connection::flush()
+            let size = self.outgoing.len();
+            let result = websocket.receive(&raw[..n], &mut self.outgoing, WEBSOCKET_MESSAGE_LIMIT);
+            if self.outgoing.len() > size {
+                self.lines.push_back(self.outgoing.len() - size);
+            }
+            result.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+    }
+
+    /// Queues the line unless the client is lagging. Server::flush() drops the laggards
+    fn send(&mut self, line: &str) {
+        if self.lagging {
+            return;
+        }
+        // NOTE: a line is either queued as a whole or not at all
+        if self.outgoing.len() + line.len() + 1 > self.outgoing_limit {
+            self.lagging = true;
+            return;
+        }
+        let size = self.outgoing.len();
+        if let Some(websocket) = &self.websocket {
+            websocket.send(line, &mut self.outgoing);
+        } else {
+            self.outgoing.extend_from_slice(line.as_bytes());
+            self.outgoing.push(FRAME_SEPARATOR);
+        }
+        if self.outgoing.len() > size {
+            self.lines.push_back(self.outgoing.len() - size);
+        }
+    }
+
+    /// Forgets about the first `n` outgoing bytes once they are written
+    fn consume(&mut self, mut n: usize) {
+        self.outgoing.drain(..n);
+        while n > 0 {
+            let line = if let Some(line) = self.lines.front_mut() {
+                line
+            } else {
+                break
+            };
+            if *line <= n {
+                n -= *line;
+                self.lines.pop_front();
+                self.torn = false;
+            } else {
+                *line -= n;
+                n = 0;
+                self.torn = true;
+            }
+        }
+    }
+
+    /// Drops all the queued lines but the partially written one, so the client never gets a
+    /// torn line. Makes room for the last words of the Server to the laggard
+    fn drop_outgoing(&mut self) {
+        let keep = if self.torn {
+            self.lines.front().copied().unwrap_or(0)
+        } else {
+            0
+        };
+        self.outgoing.truncate(keep);
+        self.lines.truncate(if keep > 0 { 1 } else { 0 });
+        self.lagging = false;
+    }
+
+    /// Writes as much of the outgoing bytes as the connection can take without blocking. Returns
+    /// how many bytes were written
+    fn flush(&mut self) -> io::Result<usize> {
+        if let Some(tls) = &mut self.tls {
+            // NOTE: the TLS session only takes as much as its buffer limit allows, the rest stays
+            // in the outgoing bytes, so the laggards are still detected by Config::outgoing_limit
+            let written = tls.writer().write(&self.outgoing)?;
+            while tls.wants_write() {
+                match tls.write_tls(&mut self.stream) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(_) => {}
+                    Err(err) => match err.kind() {
+                        io::ErrorKind::WouldBlock => break,
+                        io::ErrorKind::Interrupted => continue,
+                        _ => return Err(err),
+                    }
+                }
+            }
+            self.consume(written);
+            return Ok(written);
+        }
+        let mut written = 0;
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.consume(n);
+                    written += n;
+                }
+                Err(err) => match err.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    /// Makes the best effort to deliver the last words to the client before closing the connection
+    fn shutdown(&mut self) {
+        let addr = self.addr;
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+        }
+        let _ = self.flush().map_err(|err| if !hung_up(&err) {
+            eprintln!("ERROR: could not flush socket for {addr}: {err}", addr = Sens(addr), err = Sens(err));
+        });
+        let _ = self.stream.shutdown(Shutdown::Both).map_err(|err| if !hung_up(&err) {
+            eprintln!("ERROR: could not shutdown socket for {addr}: {err}", addr = Sens(addr), err = Sens(err));
+        });
+    }
+}
+
+/// Reads from the stream decrypting the bytes if the connection is encrypted
+fn read_transport(stream: &mut Box<dyn Transport>, tls: &mut Option<Box<ServerConnection>>, buffer: &mut [u8]) -> io::Result<usize> {
+    let tls = if let Some(tls) = tls {
+        tls
+    } else {
+        return stream.read(buffer)
+    };
+    loop {
+        match tls.reader().read(buffer) {
+            Ok(n) => return Ok(n),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        // NOTE: whatever the TLS session has to answer (the handshake, the alerts) is written
+        // by Connection::flush()
+        if tls.read_tls(stream)? == 0 {
+            return Ok(0);
+        }
+        tls.process_new_packets().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+}
+
+/// Connection from the proxy that did not send the PROXY header yet, see Config::proxy_protocol
+struct ProxiedClient {
+    stream: Box<dyn Transport>,
+    /// Address of the proxy itself
+    addr: SocketAddr,
+    origin: Origin,
+    connected_at: Instant,
+}
+
+/// Connection that did not send the token yet
+struct WaitingClient {
+    conn: Connection,
+    connected_at: Instant,
+}
+
+impl WaitingClient {
+    /// When the client becomes a slowloris if it still did not send the token
+    fn deadline(&self, slowloris_limit: Duration) -> Instant {
+        // NOTE: the TLS handshake takes a few round trips before the client can even send the token
+        let mut handshake = Duration::ZERO;
+        if self.conn.tls.is_some() {
+            handshake += TLS_HANDSHAKE_LIMIT;
+        }
+        // NOTE: same goes for the HTTP upgrade of WebSocket and the page running its script
+        if self.conn.websocket.is_some() {
+            handshake += WEBSOCKET_HANDSHAKE_LIMIT;
+        }
+        self.connected_at + handshake + slowloris_limit
+    }
+}
+
+struct Client {
+    conn: Connection,
+    bucket: MessageBucket,
+    /// The last message was dropped because the bucket was empty
+    throttled: bool,
+    /// When anything was received from the client last time
+    last_seen: Instant,
+    nick: String,
+    /// The token the client authorized with
+    token: String,
+    /// Whether the nick was assigned by the token, so the client can't change it
+    identified: bool,
+    /// Authorized with the admin token and may use ADMIN_COMMANDS
+    admin: bool,
+    room: String,
+    /// The reason given to /away, cleared by the next message of the client
+    away: Option<String>,
+}
+
+/// Token replaced by /rotate-token that is still accepted for ROTATE_GRACE
+struct RotatedToken {
+    /// The token that replaced it
+    token: String,
+    until: Instant,
+}
+
+pub struct TokenInfo {
+    /// The nick of everyone who authorizes with the token
+    pub nick: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum DisconnectReason {
+    /// The client closed the connection itself
+    Quit,
+    ReadError,
+    WriteError,
+    /// The client can't keep up with the chat
+    Lagging,
+    Idle,
+    Slowloris,
+    AuthFailure,
+    Oversize,
+    Kicked,
+    Banned(StrikeReason),
+    TokenRevoked,
+    NotAllowed,
+    Shutdown,
+}
+
+impl DisconnectReason {
+    /// Used in the logs
+    fn name(self) -> &'static str {
+        match self {
+            Self::Quit => "quit",
+            Self::ReadError => "read-error",
+            Self::WriteError => "write-error",
+            Self::Lagging => "lagging",
+            Self::Idle => "idle",
+            Self::Slowloris => "slowloris",
+            Self::AuthFailure => "auth-failure",
+            Self::Oversize => "oversize",
+            Self::Kicked => "kicked",
+            Self::Banned(_) => "banned",
+            Self::TokenRevoked => "token-revoked",
+            Self::NotAllowed => "not-allowed",
+            Self::Shutdown => "shutdown",
+        }
+    }
+
+    /// The last line the client gets, if the connection is still good enough to deliver it
+    fn notice(self) -> Option<String> {
+        match self {
+            Self::Quit | Self::ReadError | Self::WriteError => None,
+            Self::Lagging => Some("disconnected: too slow to keep up".to_string()),
+            Self::Idle => Some("disconnected: idle timeout".to_string()),
+            Self::Slowloris => Some("disconnected: did not authorize in time".to_string()),
+            Self::AuthFailure => Some("disconnected: invalid token! Bruh!".to_string()),
+            Self::Oversize => Some("disconnected: message is too long".to_string()),
+            Self::Kicked => Some("disconnected: kicked by an admin".to_string()),
+            Self::Banned(reason) => Some(format!("disconnected: banned: {description}", description = reason.description())),
+            Self::TokenRevoked => Some("disconnected: token was revoked".to_string()),
+            Self::NotAllowed => Some("disconnected: IP is not allowed anymore".to_string()),
+            Self::Shutdown => Some("disconnected: server is shutting down".to_string()),
+        }
+    }
+
+    /// Whether the room of the client is told that the client left
+    fn announced(self) -> bool {
+        match self {
+            Self::Quit | Self::ReadError | Self::WriteError | Self::Lagging | Self::Idle | Self::NotAllowed => true,
+            Self::Kicked | Self::Banned(_) | Self::TokenRevoked | Self::Oversize => ANNOUNCE_MODERATION,
+            // NOTE: the waiting clients are not in any room and everyone is leaving on shutdown anyway
+            Self::Slowloris | Self::AuthFailure | Self::Shutdown => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Room {
+    members: HashSet<Token>,
+    /// Number of the last line broadcast to the room, see SEQUENCE_TAG
+    sequence: u64,
+}
+
+struct HistoryEntry {
+    time: SystemTime,
+    room: String,
+    line: String,
+}
+
+/// The most recent messages that are shown to the newly authorized clients
+#[derive(Default)]
+struct History {
+    entries: VecDeque<HistoryEntry>,
+    /// Total length of the lines in the entries
+    bytes: usize,
+}
+
+impl History {
+    fn push(&mut self, entry: HistoryEntry) {
+        self.bytes += entry.line.len();
+        self.entries.push_back(entry);
+        while self.entries.len() > HISTORY_LIMIT || self.bytes > HISTORY_BYTES_LIMIT {
+            if let Some(entry) = self.entries.pop_front() {
+                self.bytes -= entry.line.len();
+            }
+        }
+    }
+}
+
+/// Appends the events to a file. The writing happens on a separate thread, so a slow disk
+/// does not stall the Server
+pub struct EventLog {
+    sender: Sender<String>,
+    writer: JoinHandle<()>,
+}
+
+impl EventLog {
+    pub fn open(file_path: String) -> Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&file_path).map_err(|err| {
+            eprintln!("ERROR: could not open log file {file_path}: {err}");
+        })?;
+        let mut size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        let (sender, receiver) = mpsc::channel::<String>();
+        let writer = thread::spawn(move || {
+            // NOTE: reporting only the first error of a streak, so a full disk does not flood stderr
+            let mut failing = false;
+            for line in receiver {
+                if size + line.len() as u64 > LOG_ROTATE_SIZE {
+                    let rotated = format!("{file_path}.1");
+                    match fs::rename(&file_path, &rotated).and_then(|()| OpenOptions::new().create(true).append(true).open(&file_path)) {
+                        Ok(new_file) => {
+                            file = new_file;
+                            size = 0;
+                        }
+                        Err(err) => if !failing {
+                            eprintln!("ERROR: could not rotate log file {file_path}: {err}");
+                            failing = true;
+                        }
+                    }
+                }
+                match file.write_all(line.as_bytes()).and_then(|()| file.flush()) {
+                    Ok(()) => {
+                        size += line.len() as u64;
+                        failing = false;
+                    }
+                    Err(err) => if !failing {
+                        eprintln!("ERROR: could not write to log file {file_path}: {err}");
+                        failing = true;
+                    }
+                }
+            }
+        });
+        Ok(Self { sender, writer })
+    }
+
+    fn write(&self, event: &str) {
+        // NOTE: the writer only stops after the sender is dropped, so this can't fail
+        let _ = self.sender.send(format!("{time} {event}\n", time = rfc3339(SystemTime::now())));
+    }
+
+    /// Waits until everything is written
+    fn close(self) {
+        drop(self.sender);
+        if self.writer.join().is_err() {
+            eprintln!("ERROR: log writer thread panicked");
+        }
+    }
+}
+
+/// An IP address or a CIDR range like `192.168.0.0/16`
+struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    fn parse(range: &str) -> Option<Self> {
+        let (addr, prefix) = match range.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (range, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The only IPs that are allowed to connect to the Server
+pub struct Allowlist {
+    file_path: String,
+    ranges: Vec<IpRange>,
+}
+
+impl Allowlist {
+    pub fn load(file_path: String) -> Result<Self> {
+        let ranges = load_allowlist(&file_path)?;
+        println!("INFO: only {n} IP ranges from {file_path} are allowed to connect", n = ranges.len());
+        Ok(Self { file_path, ranges })
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+}
+
+/// Counters for the /stats admin command. All of them only grow since the start of the Server
+#[derive(Default)]
+struct Stats {
+    /// Accepted connections including the refused ones
+    connections: u64,
+    /// Messages sent to the rooms
+    messages: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Indexed by StrikeReason
+    strikes: [u64; StrikeReason::ALL.len()],
+    bans: u64,
+}
+
+/// Connection attempts made from an IP within the current CONNECTION_RATE_WINDOW
+struct Attempts {
+    window_start: Instant,
+    count: usize,
+}
+
+/// Log lines that were not logged within the current LOG_THROTTLE_WINDOW
+struct Suppressed {
+    window_start: Instant,
+    count: usize,
+}
+
+/// Keeps a noisy kind of log lines (like the rejections of the banned IPs that keep reconnecting)
+/// down to one per LOG_THROTTLE_WINDOW per IP. How many lines were suppressed is reported once
+/// the window is over, so the logs can't be used to DoS the Server
+struct LogThrottle {
+    /// What happened to the IP when the line was suppressed, like "failed authorization"
+    what: &'static str,
+    ips: HashMap<IpAddr, Suppressed>,
+    /// The lines about the IPs that did not fit into LOG_THROTTLE_LIMIT
+    overflow: Suppressed,
+}
+
+impl LogThrottle {
+    fn new(what: &'static str) -> Self {
+        Self {
+            what,
+            ips: HashMap::new(),
+            overflow: Suppressed {
+                window_start: Instant::now(),
+                count: 0,
+            },
+        }
+    }
+
+    /// Returns true if the line about the IP should be logged
+    fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if let Some(suppressed) = self.ips.get_mut(&ip) {
+            suppressed.count += 1;
+            return false;
+        }
+        if self.ips.len() >= LOG_THROTTLE_LIMIT {
+            self.overflow.count += 1;
+            return false;
+        }
+        self.ips.insert(ip, Suppressed {
+            window_start: now,
+            count: 0,
+        });
+        true
+    }
+
+    /// Reports and forgets the windows that are over
+    fn sweep(&mut self, now: Instant) {
+        let what = self.what;
+        let secs = LOG_THROTTLE_WINDOW.as_secs();
+        self.ips.retain(|ip, suppressed| {
+            if now.saturating_duration_since(suppressed.window_start) < LOG_THROTTLE_WINDOW {
+                return true;
+            }
+            if suppressed.count > 0 {
+                println!("INFO: IP {ip} {what} {count} more times in the last {secs}s", ip = Sens(ip), count = suppressed.count);
+            }
+            false
+        });
+        if now.saturating_duration_since(self.overflow.window_start) >= LOG_THROTTLE_WINDOW {
+            if self.overflow.count > 0 {
+                println!("INFO: other IPs {what} {count} times in the last {secs}s", count = self.overflow.count);
+            }
+            self.overflow = Suppressed {
+                window_start: now,
+                count: 0,
+            };
+        }
+    }
+
+    /// When the next summary is due
+    fn deadline(&self) -> Option<Instant> {
+        self.ips
+            .values()
+            .chain(Some(&self.overflow))
+            .filter(|suppressed| suppressed.count > 0)
+            .map(|suppressed| suppressed.window_start + LOG_THROTTLE_WINDOW)
+            .min()
+    }
+}
+
+pub struct Server {
+    proxied: HashMap<Token, ProxiedClient>,
+    waiting: HashMap<Token, WaitingClient>,
+    clients: HashMap<Token, Client>,
+    sinners: HashMap<IpAddr, Sinner>,
+    attempts: HashMap<IpAddr, Attempts>,
+    rejections: LogThrottle,
+    auth_failures: LogThrottle,
+    tokens: HashMap<String, TokenInfo>,
+    /// The old tokens replaced by /rotate-token
+    rotated: HashMap<String, RotatedToken>,
+    admin_token: String,
+    /// Empty rooms are removed
+    rooms: HashMap<String, Room>,
+    history: History,
+    events: Option<EventLog>,
+    allowlist: Option<Allowlist>,
+    tls: Option<Arc<ServerConfig>>,
+    config: Config,
+    registry: Registry,
+    /// Tokens of the removed connections that can be given to the new ones
+    free_tokens: Vec<Token>,
+    /// The biggest token ever given to a connection
+    last_token: Token,
+    /// When the clients must be pinged next time
+    next_ping: Instant,
+    stats: Stats,
+    /// The id of the next authorized client. Unlike the mio tokens the ids are never reused
+    next_id: u64,
+    /// Set by /shutdown, the Server is shut down once the current events are handled
+    pub quit: bool,
+}
+
+impl Server {
+    pub fn new(tokens: HashMap<String, TokenInfo>, admin_token: String, registry: Registry, events: Option<EventLog>, allowlist: Option<Allowlist>, tls: Option<Arc<ServerConfig>>, config: Config) -> Self {
+        Self {
+            proxied: HashMap::new(),
+            waiting: HashMap::new(),
+            clients: HashMap::new(),
+            sinners: load_bans(&config.banned_file),
+            attempts: HashMap::new(),
+            rejections: LogThrottle::new("was rejected as banned"),
+            auth_failures: LogThrottle::new("failed authorization"),
+            tokens,
+            rotated: HashMap::new(),
+            admin_token,
+            rooms: HashMap::new(),
+            history: History::default(),
+            events,
+            allowlist,
+            tls,
+            config,
+            registry,
+            free_tokens: Vec::new(),
+            // NOTE: the tokens of SIGNALS and the listeners are reserved for the Server itself
+            last_token: Token(FIRST_LISTENER + ORIGINS.len() - 1),
+            next_ping: Instant::now() + PING_INTERVAL,
+            stats: Stats::default(),
+            next_id: 1,
+            quit: false,
+        }
+    }
+
+    fn log_event(&self, event: &str) {
+        if let Some(events) = &self.events {
+            events.write(event);
+        }
+    }
+
+    fn allocate_token(&mut self) -> Token {
+        self.free_tokens.pop().unwrap_or_else(|| {
+            self.last_token = Token(self.last_token.0 + 1);
+            self.last_token
+        })
+    }
+
+    fn connection_mut(&mut self, token: Token) -> Option<&mut Connection> {
+        if let Some(client) = self.clients.get_mut(&token) {
+            Some(&mut client.conn)
+        } else {
+            self.waiting.get_mut(&token).map(|waiting| &mut waiting.conn)
+        }
+    }
+
+    /// Forgets about the connection (waiting or authorized) deregistering it from the Poll object
+    fn remove_connection(&mut self, token: Token) -> Option<Connection> {
+        let mut conn = if let Some(client) = self.clients.remove(&token) {
+            self.exit_room(token, &client.room);
+            client.conn
+        } else {
+            self.waiting.remove(&token)?.conn
+        };
+        let _ = self.registry.deregister(&mut conn.stream).map_err(|err| {
+            eprintln!("ERROR: could not deregister socket of {addr}: {err}", addr = Sens(conn.addr), err = Sens(err));
+        });
+        self.free_tokens.push(token);
+        Some(conn)
+    }
+
+    /// Closes the connection letting the room, the logs and the client itself know why
+    fn disconnect(&mut self, token: Token, reason: DisconnectReason) {
+        if reason.announced() {
+            self.announce_left(token);
+        }
+        if let Some(mut conn) = self.remove_connection(token) {
+            println!("INFO: Client {addr} disconnected: {reason}", addr = Sens(conn.addr), reason = reason.name());
+            self.log_event(&format!("DISCONNECT {addr} {reason}", addr = Sens(conn.addr), reason = reason.name()));
+            if let Some(notice) = reason.notice() {
+                conn.send(&format!("{DISCONNECT_TAG}{notice}"));
+            }
+            // NOTE: there is no point in saying goodbye over the connection that is already broken
+            if !matches!(reason, DisconnectReason::ReadError | DisconnectReason::WriteError) {
+                conn.shutdown();
+            }
+        }
+    }
+
+    /// Overwrites Config::banned_file with all the bans that are still in effect
+    fn save_bans(&self) {
+        let content = format_bans(&self.sinners, Instant::now());
+        let file_path = &self.config.banned_file;
+        let _ = fs::write(file_path, content).map_err(|err| {
+            eprintln!("ERROR: could not save bans to {file_path}: {err}");
+        });
+    }
+
+    /// Returns the amount of connection attempts from the IP within the current window including this one
+    fn record_attempt(&mut self, ip: IpAddr, now: Instant) -> usize {
+        let attempts = self.attempts.entry(ip).or_insert(Attempts {
+            window_start: now,
+            count: 0,
+        });
+        if attempts_expired(attempts, now) {
+            attempts.window_start = now;
+            attempts.count = 0;
+        }
+        attempts.count += 1;
+        attempts.count
+    }
+
+    pub fn client_connected(&mut self, author: impl Transport + 'static, author_addr: SocketAddr, origin: Origin) {
+        let mut author: Box<dyn Transport> = Box::new(author);
+        // NOTE: dual-stack sockets report IPv4 clients as ::ffff:a.b.c.d, but they must be banned,
+        // rate limited, etc the same way regardless of how they connected
+        let author_addr = SocketAddr::new(author_addr.ip().to_canonical(), author_addr.port());
+        self.stats.connections += 1;
+        // NOTE: the admin listener is only reachable from the machine itself, so nothing comes
+        // through the proxy there
+        if self.config.proxy_protocol && origin != Origin::Admin {
+            // NOTE: the bans, the allowlist and the rate limits are all about the address from the
+            // PROXY header, so none of them can be checked until it arrives
+            if self.proxied.len() >= WAITING_LIMIT {
+                println!("INFO: Client {author_addr} refused: too many connections without PROXY header", author_addr = Sens(author_addr));
+                drop(author);
+                return;
+            }
+            let token = self.allocate_token();
+            if let Err(err) = self.registry.register(&mut author, token, Interest::READABLE) {
+                eprintln!("ERROR: could not register client socket of {author_addr} in the Poll object: {err}", author_addr = Sens(author_addr), err = Sens(err));
+                self.free_tokens.push(token);
+                return;
+            }
+            self.proxied.insert(token, ProxiedClient {
+                stream: author,
+                addr: author_addr,
+                origin,
+                connected_at: Instant::now(),
+            });
+            return;
+        }
+        self.admit(author, author_addr, origin);
+    }
+
+    /// Reads the PROXY header of the connection and lets the client in as if it connected from
+    /// the address in the header
+    fn proxy_read(&mut self, token: Token) {
+        let proxied = if let Some(proxied) = self.proxied.get_mut(&token) {
+            proxied
+        } else {
+            return
+        };
+        let mut buffer = [0; PROXY_HEADER_LIMIT];
+        // NOTE: only the header itself is consumed, whatever the client sent after it is left for
+        // the Connection (the token, the TLS handshake, etc)
+        let n = loop {
+            match proxied.stream.peek(&mut buffer) {
+                Ok(n) => break n,
+                Err(err) => match err.kind() {
+                    io::ErrorKind::WouldBlock => return,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => {
+                        eprintln!("ERROR: could not read PROXY header from {addr}: {err}", addr = Sens(proxied.addr), err = Sens(err));
+                        self.remove_proxied(token);
+                        return;
+                    }
+                }
+            }
+        };
+        if n == 0 {
+            self.remove_proxied(token);
+            return;
+        }
+        match parse_proxy_header(&buffer[..n]) {
+            Ok(None) => {}
+            Ok(Some((size, addr))) => {
+                if let Some(mut proxied) = self.remove_proxied(token) {
+                    if let Err(err) = proxied.stream.read_exact(&mut buffer[..size]) {
+                        eprintln!("ERROR: could not read PROXY header from {addr}: {err}", addr = Sens(proxied.addr), err = Sens(err));
+                        return;
+                    }
+                    let addr = addr.unwrap_or(proxied.addr);
+                    let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                    self.admit(proxied.stream, addr, proxied.origin);
+                }
+            }
+            Err(err) => {
+                println!("INFO: Client {addr} dropped: {err}", addr = Sens(proxied.addr));
+                self.remove_proxied(token);
+            }
+        }
+    }
+
+    fn remove_proxied(&mut self, token: Token) -> Option<ProxiedClient> {
+        let mut proxied = self.proxied.remove(&token)?;
+        let _ = self.registry.deregister(&mut proxied.stream).map_err(|err| {
+            eprintln!("ERROR: could not deregister socket of {addr}: {err}", addr = Sens(proxied.addr), err = Sens(err));
+        });
+        self.free_tokens.push(token);
+        Some(proxied)
+    }
+
+    /// Lets the client in unless it is banned, not allowed, connecting too often, etc
+    fn admit(&mut self, mut author: Box<dyn Transport>, author_addr: SocketAddr, origin: Origin) {
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.allows(author_addr.ip()) {
+                // NOTE: strangers don't deserve even a message
+                drop(author);
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        let attempts = self.record_attempt(author_addr.ip(), now);
+
+        if let Some(sinner) = self.sinners.get_mut(&author_addr.ip()) {
+            match sinner {
+                Sinner::Banned(until, reason, notices) => {
+                    if !ban_expired(*until, now) {
+                        let secs = until.saturating_duration_since(now).as_secs_f32();
+                        // NOTE: banned MFs may keep connecting and overflow us with logs
+                        if self.config.verbose || self.rejections.allow(author_addr.ip(), now) {
+                            println!("INFO: Client {author_addr} tried to connected, but that MF is banned for {secs} secs", author_addr = Sens(author_addr));
+                        }
+                        // NOTE: the legit users only need to be told once or twice what happened,
+                        // everyone else is dropped as cheaply as possible
+                        if *notices >= BAN_NOTICE_LIMIT {
+                            drop(author);
+                            return;
+                        }
+                        *notices += 1;
+                        match reason {
+                            Some(reason) => refuse(author, author_addr, &format!("You are banned MF: {description}, {secs} secs left", description = reason.description())),
+                            None => refuse(author, author_addr, &format!("You are banned MF: {secs} secs left")),
+                        }
+                        return;
+                    } else {
+                        sinner.forgive();
+                        self.save_bans();
+                    }
+                }
+                Sinner::Striked(..) | Sinner::Shadowed(..) => {}
+            }
+        }
+
+        if attempts > CONNECTION_RATE_LIMIT {
+            if attempts == CONNECTION_RATE_LIMIT + 1 {
+                println!("INFO: Client {author_addr} is connecting too often", author_addr = Sens(author_addr));
+            }
+            // NOTE: not wasting any more resources on the connection, just dropping it
+            drop(author);
+            self.strike_ip(author_addr.ip(), StrikeReason::ConnectFlood);
+            return;
+        }
+
+        if self.waiting.len() >= WAITING_LIMIT {
+            println!("INFO: Client {author_addr} refused: too many waiting connections", author_addr = Sens(author_addr));
+            refuse(author, author_addr, "Server is busy. Try again later.");
+            return;
+        }
+
+        let waiting_from_ip = self.waiting.values().filter(|waiting| waiting.conn.addr.ip() == author_addr.ip()).count();
+        if waiting_from_ip >= WAITING_PER_IP_LIMIT {
+            println!("INFO: Client {author_addr} refused: too many waiting connections from the same IP", author_addr = Sens(author_addr));
+            refuse(author, author_addr, "Too many connections. Authorize the ones you already have.");
+            // NOTE: nobody needs that many unauthorized connections at once unless they are
+            // trying to exhaust our file descriptors
+            self.strike_ip(author_addr.ip(), StrikeReason::WaitingFlood);
+            return;
+        }
+
+        // NOTE: the admin console never leaves the machine, so there is nothing to encrypt
+        let tls = if let (Some(tls), false) = (&self.tls, origin == Origin::Admin) {
+            match ServerConnection::new(tls.clone()) {
+                Ok(mut session) => {
+                    session.set_buffer_limit(Some(self.config.outgoing_limit));
+                    Some(Box::new(session))
+                }
+                Err(err) => {
+                    eprintln!("ERROR: could not start TLS session with {author_addr}: {err}", author_addr = Sens(author_addr));
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        let token = self.allocate_token();
+        if let Err(err) = self.registry.register(&mut author, token, Interest::READABLE | Interest::WRITABLE) {
+            eprintln!("ERROR: could not register client socket of {author_addr} in the Poll object: {err}", author_addr = Sens(author_addr), err = Sens(err));
+            self.free_tokens.push(token);
+            return;
+        }
+
+        match origin {
+            Origin::Chat => println!("INFO: Client {author_addr} connected", author_addr = Sens(author_addr)),
+            Origin::WebSocket => println!("INFO: Client {author_addr} connected over WebSocket", author_addr = Sens(author_addr)),
+            Origin::Admin => println!("INFO: Client {author_addr} connected to the admin console", author_addr = Sens(author_addr)),
+        }
+        self.waiting.insert(token, WaitingClient {
+            conn: Connection::new(author, author_addr, origin, tls, self.config.outgoing_limit),
+            connected_at: now,
+        });
+    }
+
+    pub fn client_read(&mut self, token: Token) {
+        if self.proxied.contains_key(&token) {
+            self.proxy_read(token);
+            return;
+        }
+        let mut buffer = [0; 64];
+        loop {
+            let author = if let Some(author) = self.connection_mut(token) {
+                author
+            } else {
+                return
+            };
+            let author_addr: SocketAddr = author.addr;
+            match author.read(&mut buffer) {
+                Ok(0) => {
+                    // NOTE: leaving without authorizing is what the constantly connecting/disconnecting
+                    // clients do, so it is counted as one more connection attempt
+                    if self.waiting.contains_key(&token) {
+                        self.record_attempt(author_addr.ip(), Instant::now());
+                    }
+                    self.disconnect(token, DisconnectReason::Quit);
+                    return;
+                }
+                Ok(n) => {
+                    author.frames.extend(&buffer[0..n]);
+                    self.stats.bytes_in += n as u64;
+                }
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::WouldBlock {
+                        eprintln!("ERROR: could not read message from {author_addr}: {err}", author_addr = Sens(author_addr), err = Sens(err));
+                        self.disconnect(token, DisconnectReason::ReadError);
+                    }
+                    return;
+                }
+            }
+
+            if let Some(client) = self.clients.get_mut(&token) {
+                client.last_seen = Instant::now();
+            }
+
+            // NOTE: the frames that come after the token must be handled as messages of the
+            // authorized client, so the maps are checked again on each frame
+            loop {
+                if let Some(frame) = self.waiting.get_mut(&token).and_then(|waiting| waiting.conn.frames.next_frame()) {
+                    self.waiting_message(token, &frame);
+                } else if let Some(frame) = self.clients.get_mut(&token).and_then(|client| client.conn.frames.next_frame()) {
+                    self.client_message(token, &frame);
+                } else {
+                    break;
+                }
+            }
+
+            // NOTE: nothing but the token is expected from waiting clients, so there is
+            // no reason to buffer more than that while they are figuring out how to send it
+            if let Some(waiting) = self.waiting.get(&token) {
+                let longest = self.tokens.keys().chain([&self.admin_token]).map(|token| token.len()).max().unwrap_or(TOKEN_LEN);
+                let limit = longest + TOKEN_PADDING + "\r\n".len();
+                if waiting.conn.frames.pending() > limit {
+                    self.disconnect(token, DisconnectReason::Oversize);
+                    self.strike_ip(author_addr.ip(), StrikeReason::Oversize);
+                    return;
+                }
+            }
+            if let Some(client) = self.clients.get_mut(&token) {
+                // NOTE: the rest of the oversized message is dropped as it arrives, so neither
+                // the memory nor the strikes grow while the client keeps sending it
+                if client.conn.frames.discard_oversized(FRAME_LIMIT) {
+                    client.conn.send(&format!("{ERROR_TAG}message is too long: the limit is {FRAME_LIMIT} bytes"));
+                    self.strike_ip(author_addr.ip(), StrikeReason::Oversize);
+                }
+            }
+        }
+    }
+
+    fn waiting_message(&mut self, token: Token, frame: &[u8]) {
+        if let Some(waiting) = self.waiting.get_mut(&token) {
+            let author_addr: SocketAddr = waiting.conn.addr;
+            let text = sanitize(frame).unwrap_or_default();
+            // NOTE: `nc` and friends are not always careful about the whitespace around the token
+            let text = text.trim_ascii();
+            // NOTE: not stopping at the first match, so the time does not depend on which token matched
+            let mut authorized = None;
+            for (token, info) in self.tokens.iter() {
+                if constant_time_eq(text.as_bytes(), token.as_bytes()) {
+                    authorized = Some((token.clone(), info.nick.clone()));
+                }
+            }
+            let now = Instant::now();
+            for (old_token, rotated) in self.rotated.iter() {
+                if constant_time_eq(text.as_bytes(), old_token.as_bytes()) && rotated.until > now {
+                    if let Some(info) = self.tokens.get(&rotated.token) {
+                        authorized = Some((rotated.token.clone(), info.nick.clone()));
+                    }
+                }
+            }
+            let mut admin = constant_time_eq(text.as_bytes(), self.admin_token.as_bytes());
+            // NOTE: with the admin listener the admin token is accepted only there and nothing
+            // else is accepted there, so the admin token never has to travel over the network
+            if self.config.admin_port.is_some() {
+                if waiting.conn.origin == Origin::Admin {
+                    authorized = None;
+                } else {
+                    admin = false;
+                }
+            }
+            let (client_token, identity) = if let Some(authorized) = authorized {
+                authorized
+            } else if admin {
+                (self.admin_token.clone(), None)
+            } else {
+                if self.config.verbose || self.auth_failures.allow(author_addr.ip(), Instant::now()) {
+                    println!("INFO: {} failed authorization!", Sens(author_addr));
+                }
+                self.log_event(&format!("AUTH_FAILURE {}", Sens(author_addr)));
+                // NOTE: the ban disconnects everyone from the IP including this client
+                if !self.strike_ip(author_addr.ip(), StrikeReason::AuthFailure) {
+                    self.disconnect(token, DisconnectReason::AuthFailure);
+                }
+                return;
+            };
+
+            if let Some(mut waiting) = self.waiting.remove(&token) {
+                if admin {
+                    println!("INFO: {} authorized as admin!", Sens(author_addr));
+                    self.log_event(&format!("AUTH_ADMIN {}", Sens(author_addr)));
+                } else {
+                    println!("INFO: {} authorized!", Sens(author_addr));
+                    self.log_event(&format!("AUTH {}", Sens(author_addr)));
+                }
+                self.sinners.entry(author_addr.ip()).or_default().forgive();
+                for entry in self.history.entries.iter().filter(|entry| entry.room == DEFAULT_ROOM) {
+                    waiting.conn.send(&format!("{HISTORY_TAG}{line}", line = stamp(entry.time, &entry.line)));
+                }
+                waiting.conn.send(WELCOME);
+                // NOTE: reading the file on each authorization, so the MOTD can be changed without restarting
+                for line in load_motd(MOTD_FILE_PATH) {
+                    waiting.conn.send(&line);
+                }
+                // NOTE: the anonymous clients are told apart by the ids, which have nothing to do
+                // with their addresses, so they are safe to show to everyone
+                let nick = identity.clone().unwrap_or_else(|| {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    format!("#{id}")
+                });
+                waiting.conn.send(&format!("You are {nick}"));
+                // NOTE: announcing before entering the room, so the client itself does not get it.
+                // Nobody else learns about the shadowed clients either
+                if !self.sinners.get(&author_addr.ip()).is_some_and(Sinner::is_shadowed) {
+                    self.room_broadcast(DEFAULT_ROOM, &format!("* {nick} joined"));
+                }
+                self.clients.insert(token, Client {
+                    conn: waiting.conn,
+                    bucket: MessageBucket::new(Instant::now(), self.config.message_rate),
+                    throttled: false,
+                    last_seen: Instant::now(),
+                    identified: identity.is_some(),
+                    nick,
+                    token: client_token,
+                    admin,
+                    room: DEFAULT_ROOM.to_string(),
+                    away: None,
+                });
+                self.enter_room(token, DEFAULT_ROOM);
+            }
+        }
+    }
+
+    fn client_message(&mut self, token: Token, frame: &[u8]) {
+        if let Some(author) = self.clients.get_mut(&token) {
+            let author_addr: SocketAddr = author.conn.addr;
+
+            // NOTE: the answers to the pings only matter for Client::last_seen
+            if frame == PING_TAG.to_string().as_bytes() {
+                return;
+            }
+
+            let now = Instant::now();
+            if !author.bucket.take(now) {
+                author.conn.send(&format!("{ERROR_TAG}rate-limited: wait {secs:.1}s", secs = author.bucket.wait().as_secs_f64()));
+                // NOTE: an occasional overflow of the bucket is not a crime, but continuing to
+                // send after being told to slow down is
+                if author.throttled {
+                    self.strike_ip(author_addr.ip(), StrikeReason::RateLimit);
+                } else {
+                    author.throttled = true;
+                }
+                return;
+            }
+            author.throttled = false;
+            let text = if let Some(text) = sanitize(frame) {
+                text
+            } else {
+                self.strike_ip(author_addr.ip(), StrikeReason::InvalidUtf8);
+                return
+            };
+            // NOTE: a blank message is not hostile, just useless. It is dropped without a strike,
+            // but it still took its token from the bucket, so it can't keep anyone active for free
+            let text = fit_width(text.trim_end(), WIDTH_LIMIT);
+            if text.is_empty() {
+                return;
+            }
+            self.sinners.entry(author_addr.ip()).or_default().forgive();
+            if let Some(nick) = text.strip_prefix("/nick").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                self.client_nick(token, nick.trim());
+                return;
+            }
+            if let Some(rest) = text.strip_prefix("/msg").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                self.client_dm(token, rest.trim());
+                return;
+            }
+            if let Some(room) = text.strip_prefix("/join").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                self.client_join(token, room.trim());
+                return;
+            }
+            // NOTE: the ping took its token from the bucket like any message, so it can't be used
+            // to flood the Server
+            if let Some(nonce) = text.strip_prefix("/ping").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                author.conn.send(&format!("{PONG_TAG}{nonce}", nonce = nonce.trim()));
+                return;
+            }
+            if let Some(reason) = text.strip_prefix("/away").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                self.client_away(token, reason.trim());
+                return;
+            }
+            if text == "/leave" {
+                if author.room == DEFAULT_ROOM {
+                    author.conn.send(&format!("You can't leave #{DEFAULT_ROOM}"));
+                } else {
+                    self.client_join(token, DEFAULT_ROOM);
+                }
+                return;
+            }
+            if author.admin {
+                if let Some(command) = text.strip_prefix('/') {
+                    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+                    if let Some(command) = ADMIN_COMMANDS.iter().find(|command| command.name == name) {
+                        if command.secret {
+                            println!("INFO: Admin {author_addr} issued command \"/{name}\"", author_addr = Sens(author_addr));
+                        } else {
+                            println!("INFO: Admin {author_addr} issued command {text:?}", author_addr = Sens(author_addr));
+                        }
+                        (command.run)(self, token, argument.trim());
+                        return;
+                    }
+                }
+            }
+            if text == "/list" {
+                self.client_list(token);
+                return;
+            }
+            println!("INFO: Client {author_addr} sent message {text:?}", author_addr = Sens(author_addr));
+            let nick = author.nick.clone();
+            let room = author.room.clone();
+            // NOTE: coming back costs nothing on top of the message itself, so it is never
+            // what gets the client rate-limited
+            if author.away.take().is_some() {
+                self.announce(token, &room, &format!("* {nick} is back"));
+            }
+            if self.shadowed(token) {
+                self.log_event(&format!("SHADOWED_MESSAGE {author_addr} #{room} <{nick}> {text}", author_addr = Sens(author_addr)));
+                let line = format!("<{nick}> {text}");
+                let line = stamp(SystemTime::now(), truncate(&line, BROADCAST_LIMIT));
+                let sequence = self.shadow_sequence(&room);
+                self.reply(token, &format!("{SEQUENCE_TAG}{sequence} {ECHO_TAG}{line}"));
+                return;
+            }
+            self.log_event(&format!("MESSAGE {author_addr} #{room} <{nick}> {text}", author_addr = Sens(author_addr)));
+            self.stats.messages += 1;
+            let line = format!("<{nick}> {text}");
+            let line = truncate(&line, BROADCAST_LIMIT);
+            let now = SystemTime::now();
+            self.history.push(HistoryEntry {
+                time: now,
+                room: room.clone(),
+                line: line.to_string(),
+            });
+            let line = &stamp(now, line);
+            if let Some(room) = self.rooms.get_mut(&room) {
+                room.sequence += 1;
+                let sequence = room.sequence;
+                for member in room.members.iter() {
+                    if let Some(client) = self.clients.get_mut(member) {
+                        // NOTE: the author receives the message back as a confirmation of the delivery
+                        if *member == token {
+                            client.conn.send(&format!("{SEQUENCE_TAG}{sequence} {ECHO_TAG}{line}"));
+                        } else {
+                            client.conn.send(&format!("{SEQUENCE_TAG}{sequence} {line}"));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the message only to the client with the given nick. The argument is `<nick> <text>`
+    fn client_dm(&mut self, token: Token, argument: &str) {
+        let (nick, text) = argument.split_once(' ').map(|(nick, text)| (nick, text.trim())).unwrap_or((argument, ""));
+        if nick.is_empty() || text.is_empty() {
+            self.reply(token, "Usage: /msg <nick> <text>");
+            return;
+        }
+        let author = if let Some(author) = self.clients.get(&token) {
+            author
+        } else {
+            return
+        };
+        let author_addr: SocketAddr = author.conn.addr;
+        let author_nick = author.nick.clone();
+        let shadowed = self.shadowed(token);
+        if let Some(target) = self.clients.values_mut().find(|client| client.nick == nick) {
+            println!("INFO: Client {author_addr} sent direct message to {nick}", author_addr = Sens(author_addr));
+            if !shadowed {
+                target.conn.send(&format!("{DM_TAG}[DM from {author_nick}] {text}"));
+            }
+            self.reply(token, &format!("{DM_TAG}[DM to {nick}] {text}"));
+        } else {
+            self.reply(token, &format!("No such user: {nick}"));
+        }
+    }
+
+    /// Marks the client as away until it sends a message. The reason is a message of its own,
+    /// so it is logged and kept from the others of a shadowed client the same way
+    fn client_away(&mut self, token: Token, reason: &str) {
+        let shadowed = self.shadowed(token);
+        if let Some(author) = self.clients.get_mut(&token) {
+            let author_addr: SocketAddr = author.conn.addr;
+            println!("INFO: Client {author_addr} is away", author_addr = Sens(author_addr));
+            author.away = Some(reason.to_string());
+            let nick = author.nick.clone();
+            let room = author.room.clone();
+            let event = if shadowed { "SHADOWED_AWAY" } else { "AWAY" };
+            self.log_event(&format!("{event} {author_addr} #{room} <{nick}> {reason}", author_addr = Sens(author_addr)));
+            if reason.is_empty() {
+                self.announce(token, &room, &format!("* {nick} is away"));
+            } else {
+                self.announce(token, &room, &format!("* {nick} is away ({reason})"));
+            }
+        }
+    }
+
+    /// Tells the client who is online
+    fn client_list(&mut self, token: Token) {
+        let mut nicks: Vec<String> = self.clients.iter().map(|(other, client)| {
+            let away = if *other == token || !self.shadowed(*other) {
+                client.away.as_deref()
+            } else {
+                None
+            };
+            match away {
+                Some("") => format!("{nick} (away)", nick = client.nick),
+                Some(reason) => format!("{nick} (away: {reason})", nick = client.nick),
+                None => client.nick.clone(),
+            }
+        }).collect();
+        nicks.sort();
+        let mut lines = vec![format!("{n} clients online", n = nicks.len())];
+        lines.extend(nicks.iter().take(LIST_LIMIT).cloned());
+        if nicks.len() > LIST_LIMIT {
+            lines.push(format!("...and {n} more", n = nicks.len() - LIST_LIMIT));
+        }
+        for line in lines.iter() {
+            self.reply(token, line);
+        }
+    }
+
+    /// Moves the client from its current room to another one
+    fn client_join(&mut self, token: Token, room: &str) {
+        let error = if room.is_empty() {
+            Some("Usage: /join <room>")
+        } else {
+            room_error(room)
+        };
+
+        if let Some(author) = self.clients.get_mut(&token) {
+            let author_addr: SocketAddr = author.conn.addr;
+            if let Some(error) = error {
+                author.conn.send(error);
+                return;
+            }
+            if author.room == room {
+                author.conn.send(&format!("You are already in #{room}"));
+                return;
+            }
+            let old_room = mem::replace(&mut author.room, room.to_string());
+            let nick = author.nick.clone();
+            println!("INFO: Client {author_addr} moved from #{old_room} to #{room}", author_addr = Sens(author_addr));
+            self.exit_room(token, &old_room);
+            self.announce(token, &old_room, &format!("* {nick} left #{old_room}"));
+            self.enter_room(token, room);
+            self.announce(token, room, &format!("* {nick} joined #{room}"));
+            let count = self.rooms.get(room).map(|room| room.members.len()).unwrap_or(0);
+            self.reply(token, &format!("There are {count} members in #{room}"));
+        }
+    }
+
+    /// Lets the room of the client know that the client is gone
+    fn announce_left(&mut self, token: Token) {
+        if let Some(client) = self.clients.get(&token) {
+            let nick = client.nick.clone();
+            let room = client.room.clone();
+            self.exit_room(token, &room);
+            self.announce(token, &room, &format!("* {nick} left"));
+        }
+    }
+
+    /// Tells the client where the room is at, so the Client counts the missed lines from there
+    fn enter_room(&mut self, token: Token, room: &str) {
+        let room = self.rooms.entry(room.to_string()).or_default();
+        room.members.insert(token);
+        let sequence = room.sequence;
+        self.reply(token, &format!("{SEQUENCE_TAG}{sequence}"));
+    }
+
+    fn exit_room(&mut self, token: Token, room: &str) {
+        if let Some(entry) = self.rooms.get_mut(room) {
+            entry.members.remove(&token);
+            if entry.members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Whether the client is connected from a shadow banned IP
+    fn shadowed(&self, token: Token) -> bool {
+        self.clients
+            .get(&token)
+            .and_then(|client| self.sinners.get(&client.conn.addr.ip()))
+            .is_some_and(Sinner::is_shadowed)
+    }
+
+    /// The number for the lines only the shadowed clients see
+    fn shadow_sequence(&self, room: &str) -> u64 {
+        // NOTE: the number is not taken from the room, otherwise everyone else would see
+        // a gap. The Sinner sees the same number twice instead, which is not a gap
+        self.rooms.get(room).map(|room| room.sequence).unwrap_or(0) + 1
+    }
+
+    /// Broadcasts what the author did to the room, unless the author is shadowed. Then only the
+    /// author sees it, provided it is in the room, just like with its messages
+    fn announce(&mut self, author: Token, room: &str, line: &str) {
+        if !self.shadowed(author) {
+            self.room_broadcast(room, line);
+            return;
+        }
+        if self.rooms.get(room).is_some_and(|room| room.members.contains(&author)) {
+            let line = stamp(SystemTime::now(), truncate(line, BROADCAST_LIMIT));
+            let sequence = self.shadow_sequence(room);
+            self.reply(author, &format!("{SEQUENCE_TAG}{sequence} {line}"));
+        }
+    }
+
+    fn room_broadcast(&mut self, room: &str, line: &str) {
+        let line = stamp(SystemTime::now(), truncate(line, BROADCAST_LIMIT));
+        if let Some(room) = self.rooms.get_mut(room) {
+            room.sequence += 1;
+            let line = format!("{SEQUENCE_TAG}{sequence} {line}", sequence = room.sequence);
+            for member in room.members.iter() {
+                if let Some(client) = self.clients.get_mut(member) {
+                    client.conn.send(&line);
+                }
+            }
+        }
+    }
+
+    fn client_nick(&mut self, token: Token, nick: &str) {
+        let error = if self.clients.get(&token).map(|client| client.identified).unwrap_or(false) {
+            Some("Your nick is assigned by your token")
+        } else if nick.is_empty() {
+            Some("Usage: /nick <name>")
+        } else if let Some(error) = nick_error(nick) {
+            Some(error)
+        } else if self.clients.values().any(|client| client.nick == nick) || self.tokens.values().any(|info| info.nick.as_deref() == Some(nick)) {
+            Some("Nick is already taken")
+        } else {
+            None
+        };
+
+        if let Some(author) = self.clients.get_mut(&token) {
+            let author_addr: SocketAddr = author.conn.addr;
+            if let Some(error) = error {
+                author.conn.send(error);
+                return;
+            }
+            let old_nick = mem::replace(&mut author.nick, nick.to_string());
+            println!("INFO: Client {author_addr} changed nick from {old_nick} to {nick}", author_addr = Sens(author_addr));
+            // NOTE: everyone is in some room, so going through the rooms reaches everyone
+            let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+            for room in rooms {
+                self.announce(token, &room, &format!("* {old_nick} is now known as {nick}"));
+            }
+        }
+    }
+
+    /// Reloads the allowlist file disconnecting everyone who is not allowed anymore
+    pub fn reload_allowlist(&mut self) {
+        let allowlist = if let Some(allowlist) = &mut self.allowlist {
+            allowlist
+        } else {
+            return
+        };
+        if let Ok(ranges) = load_allowlist(&allowlist.file_path) {
+            println!("INFO: reloaded {n} allowed IP ranges from {file_path}", n = ranges.len(), file_path = allowlist.file_path);
+            allowlist.ranges = ranges;
+        } else {
+            return
+        }
+        let strangers: Vec<Token> = self.clients
+            .iter()
+            .map(|(token, client)| (token, &client.conn))
+            .chain(self.waiting.iter().map(|(token, waiting)| (token, &waiting.conn)))
+            .filter(|(_, conn)| !self.allowlist.as_ref().map(|allowlist| allowlist.allows(conn.addr.ip())).unwrap_or(true))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in strangers {
+            self.disconnect(token, DisconnectReason::NotAllowed);
+        }
+    }
+
+    /// Reloads Config::token_file disconnecting everyone whose token is not there anymore
+    pub fn reload_tokens(&mut self) {
+        let tokens = if let Ok(tokens) = load_tokens(&self.config.token_file) {
+            tokens
+        } else {
+            return
+        };
+        println!("INFO: reloaded {n} tokens from {file_path}", n = tokens.len(), file_path = self.config.token_file);
+        self.tokens = tokens;
+        let revoked: Vec<Token> = self.clients
+            .iter()
+            .filter(|(_, client)| !client.admin && !self.tokens.contains_key(&client.token))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in revoked {
+            self.disconnect(token, DisconnectReason::TokenRevoked);
+        }
+    }
+
+    /// Returns true if the IP got banned
+    fn strike_ip(&mut self, ip: IpAddr, reason: StrikeReason) -> bool {
+        self.stats.strikes[reason as usize] += 1;
+        let sinner = self.sinners.entry(ip).or_default();
+        if sinner.strike(reason, self.config.strike_limit, self.config.ban_limit, Instant::now()) {
+            self.enforce_ban(ip, reason, self.config.ban_limit);
+            return true;
+        }
+        println!("INFO: IP {ip} got a strike for {reason}", ip = Sens(ip), reason = reason.name());
+        if let Sinner::Striked(strikes, _) | Sinner::Shadowed(strikes, _) = *sinner {
+            // NOTE: warning everyone from the IP, since all of them are going to be banned
+            let warning = format!("{ERROR_TAG}Warning: strike {strikes} of {limit}. Exceed the limit and you are banned for {mins} minutes", limit = self.config.strike_limit, mins = self.config.ban_limit.as_secs()/60);
+            for client in self.clients.values_mut().filter(|client| client.conn.addr.ip() == ip) {
+                client.conn.send(&warning);
+            }
+        }
+        false
+    }
+
+    /// The only place where a ban takes effect, once the IP is made Sinner::Banned for `duration`:
+    /// saves the bans and disconnects everyone connected from the IP, both waiting and authorized.
+    /// Returns how many connections were dropped
+    fn enforce_ban(&mut self, ip: IpAddr, reason: StrikeReason, duration: Duration) -> usize {
+        println!("INFO: IP {ip} got banned for {secs} secs: {reason}", ip = Sens(ip), reason = reason.name(), secs = duration.as_secs());
+        self.stats.bans += 1;
+        self.log_event(&format!("BAN {ip} {secs} {reason}", ip = Sens(ip), secs = duration.as_secs(), reason = reason.name()));
+        self.save_bans();
+        let kicked: Vec<Token> = self.clients
+            .iter()
+            .map(|(token, client)| (token, &client.conn))
+            .chain(self.waiting.iter().map(|(token, waiting)| (token, &waiting.conn)))
+            .filter(|(_, conn)| conn.addr.ip() == ip)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in kicked.iter() {
+            self.disconnect(*token, DisconnectReason::Banned(reason));
+        }
+        kicked.len()
+    }
+
+    /// Says goodbye to everyone and saves everything that must survive the restart
+    pub fn shutdown(&mut self) {
+        let tokens: Vec<Token> = self.clients.keys().chain(self.waiting.keys()).cloned().collect();
+        for token in tokens {
+            self.disconnect(token, DisconnectReason::Shutdown);
+        }
+        self.save_bans();
+        if let Some(events) = self.events.take() {
+            events.write("SHUTDOWN");
+            events.close();
+        }
+    }
+
+    fn reply(&mut self, token: Token, line: &str) {
+        if let Some(client) = self.clients.get_mut(&token) {
+            client.conn.send(line);
+        }
+    }
+
+    /// Takes care of everything that must happen after some time passes regardless of the
+    /// activity of the clients. Must be called at least by the deadline returned from
+    /// Server::timeout()
+    pub fn sweep(&mut self) {
+        let now = Instant::now();
+        // NOTE: the proxy is trusted, so it is not striked for the clients that are slow to send
+        // anything through it
+        let silent: Vec<Token> = self.proxied
+            .iter()
+            .filter(|(_, proxied)| proxied.connected_at + self.config.slowloris_limit <= now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in silent {
+            if let Some(proxied) = self.remove_proxied(token) {
+                println!("INFO: Client {addr} dropped: no PROXY header in time", addr = Sens(proxied.addr));
+            }
+        }
+
+        let mut slowlorises = Vec::new();
+        for (token, waiting) in self.waiting.iter() {
+            if waiting.deadline(self.config.slowloris_limit) <= now {
+                slowlorises.push(*token);
+            }
+        }
+
+        let mut deadbeats = Vec::new();
+        for (token, client) in self.clients.iter() {
+            let diff = now.saturating_duration_since(client.last_seen);
+            if diff >= IDLE_LIMIT {
+                deadbeats.push(*token);
+            }
+        }
+        for token in deadbeats {
+            self.disconnect(token, DisconnectReason::Idle);
+        }
+
+        if self.next_ping <= now {
+            for client in self.clients.values_mut() {
+                client.conn.send(&PING_TAG.to_string());
+            }
+            self.next_ping = now + PING_INTERVAL;
+        }
+
+        let mut bans_changed = false;
+        for token in slowlorises {
+            if let Some(waiting) = self.waiting.get(&token) {
+                let ip = waiting.conn.addr.ip();
+                self.disconnect(token, DisconnectReason::Slowloris);
+                self.strike_ip(ip, StrikeReason::Slowloris);
+            }
+        }
+
+        self.sinners.retain(|ip, sinner| {
+            if !sinner.expired(now) {
+                return true;
+            }
+            if let Sinner::Banned(..) = sinner {
+                println!("INFO: ban of IP {ip} expired", ip = Sens(ip));
+                bans_changed = true;
+            }
+            false
+        });
+
+        if bans_changed {
+            self.save_bans();
+        }
+
+        self.attempts.retain(|_, attempts| !attempts_expired(attempts, now));
+        self.rotated.retain(|_, rotated| rotated.until > now);
+        self.rejections.sweep(now);
+        self.auth_failures.sweep(now);
+    }
+
+    /// How long the Server can wait for the events before Server::sweep() has to be called
+    pub fn timeout(&self) -> Option<Duration> {
+        let proxy_deadlines = self.proxied
+            .values()
+            .map(|proxied| proxied.connected_at + self.config.slowloris_limit);
+        let slowloris_deadlines = self.waiting
+            .values()
+            .map(|waiting| waiting.deadline(self.config.slowloris_limit));
+        let ban_deadlines = self.sinners
+            .values()
+            .filter_map(|sinner| match sinner {
+                Sinner::Banned(until, ..) => Some(*until),
+                Sinner::Striked(..) | Sinner::Shadowed(..) => None,
+            });
+        let idle_deadlines = self.clients
+            .values()
+            .map(|client| client.last_seen + IDLE_LIMIT);
+        let ping_deadline = if self.clients.is_empty() {
+            None
+        } else {
+            Some(self.next_ping)
+        };
+        let summary_deadlines = self.rejections.deadline()
+            .into_iter()
+            .chain(self.auth_failures.deadline());
+        let now = Instant::now();
+        proxy_deadlines
+            .chain(slowloris_deadlines)
+            .chain(ban_deadlines)
+            .chain(idle_deadlines)
+            .chain(ping_deadline)
+            .chain(summary_deadlines)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    /// Sends out everything that was queued up for the connections so far dropping the ones that
+    /// can't keep up
+    pub fn flush(&mut self) {
+        let mut laggards = Vec::new();
+        let conns = self.clients
+            .iter_mut()
+            .map(|(token, client)| (token, &mut client.conn))
+            .chain(self.waiting.iter_mut().map(|(token, waiting)| (token, &mut waiting.conn)));
+        for (token, conn) in conns {
+            let addr: SocketAddr = conn.addr;
+            match conn.flush() {
+                Ok(written) => {
+                    self.stats.bytes_out += written as u64;
+                    if conn.lagging {
+                        laggards.push((*token, DisconnectReason::Lagging));
+                    }
+                }
+                Err(err) => {
+                    eprintln!("ERROR: could not write to {addr}: {err}", addr = Sens(addr), err = Sens(err));
+                    laggards.push((*token, DisconnectReason::WriteError));
+                }
+            }
+        }
+        for (token, reason) in laggards {
+            if let (DisconnectReason::Lagging, Some(conn)) = (reason, self.connection_mut(token)) {
+                // NOTE: whatever did not fit into the socket so far is not going to fit now, so
+                // the best we can do is to finish the current line and say goodbye
+                conn.drop_outgoing();
+            }
+            self.disconnect(token, reason);
+        }
+    }
+}
+
+struct AdminCommand {
+    name: &'static str,
+    signature: &'static str,
+    run: fn(&mut Server, Token, &str),
+    /// The argument may be a token, so it must never get into the logs
+    secret: bool,
+}
+
+const ADMIN_COMMANDS: &[AdminCommand] = &[
+    AdminCommand {
+        name: "kick",
+        signature: "/kick <addr|nick>",
+        run: kick_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "ban",
+        signature: "/ban <ip> [minutes]",
+        run: ban_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "unban",
+        signature: "/unban <ip>",
+        run: unban_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "baninfo",
+        signature: "/baninfo <ip>",
+        run: baninfo_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "shadow",
+        signature: "/shadow <ip>",
+        run: shadow_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "unshadow",
+        signature: "/unshadow <ip>",
+        run: unshadow_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "list",
+        signature: "/list",
+        run: list_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "stats",
+        signature: "/stats",
+        run: stats_command,
+        secret: false,
+    },
+    AdminCommand {
+        name: "rotate-token",
+        signature: "/rotate-token <token|nick>",
+        run: rotate_token_command,
+        secret: true,
+    },
+    AdminCommand {
+        name: "shutdown",
+        signature: "/shutdown",
+        run: shutdown_command,
+        secret: false,
+    },
+];
+
+fn admin_usage(server: &mut Server, admin: Token, name: &str) {
+    if let Some(command) = ADMIN_COMMANDS.iter().find(|command| command.name == name) {
+        server.reply(admin, &format!("Usage: {signature}", signature = command.signature));
+    }
+}
+
+fn kick_command(server: &mut Server, admin: Token, argument: &str) {
+    if argument.is_empty() {
+        admin_usage(server, admin, "kick");
+        return;
+    }
+    let kicked: Vec<Token> = server.clients
+        .iter()
+        .map(|(token, client)| (token, &client.conn, Some(client.nick.as_str())))
+        .chain(server.waiting.iter().map(|(token, waiting)| (token, &waiting.conn, None)))
+        .filter(|(_, conn, nick)| conn.addr.to_string() == argument || *nick == Some(argument))
+        .map(|(token, _, _)| *token)
+        .collect();
+    if kicked.is_empty() {
+        server.reply(admin, &format!("No client {argument}"));
+        return;
+    }
+    for token in kicked.iter() {
+        server.disconnect(*token, DisconnectReason::Kicked);
+    }
+    server.reply(admin, &format!("Kicked {n} clients", n = kicked.len()));
+}
+
+fn ban_command(server: &mut Server, admin: Token, argument: &str) {
+    let mut args = argument.split_whitespace();
+    let ip = args.next().and_then(|ip| ip.parse::<IpAddr>().ok()).map(|ip| ip.to_canonical());
+    let duration = match args.next() {
+        Some(minutes) => minutes.parse::<u64>().ok().map(|minutes| minutes.checked_mul(60).map(Duration::from_secs)),
+        None => Some(Some(server.config.ban_limit)),
+    };
+    if let (Some(ip), Some(duration)) = (ip, duration) {
+        // NOTE: the clocks can't go arbitrarily far into the future, so the absurdly long bans
+        // are refused instead of panicking the whole Server
+        let ban = duration
+            .filter(|duration| *duration <= DURATION_LIMIT)
+            .and_then(|duration| Some((duration, Instant::now().checked_add(duration)?)));
+        let (duration, until) = if let Some(ban) = ban {
+            ban
+        } else {
+            server.reply(admin, "Ban is too long");
+            return;
+        };
+        let reason = StrikeReason::Admin;
+        server.sinners.insert(ip, Sinner::Banned(until, Some(reason), 0));
+        let n = server.enforce_ban(ip, reason, duration);
+        server.reply(admin, &format!("Banned {ip} for {secs} secs. Kicked {n} clients", ip = Sens(ip), secs = duration.as_secs()));
+    } else {
+        admin_usage(server, admin, "ban");
+    }
+}
+
+fn unban_command(server: &mut Server, admin: Token, argument: &str) {
+    if let Ok(ip) = argument.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+        if let Some(Sinner::Banned(..)) = server.sinners.remove(&ip) {
+            server.save_bans();
+            println!("INFO: IP {ip} got unbanned by admin", ip = Sens(ip));
+            server.log_event(&format!("UNBAN {ip}", ip = Sens(ip)));
+            server.reply(admin, &format!("Unbanned {ip}", ip = Sens(ip)));
+        } else {
+            server.reply(admin, &format!("{ip} is not banned", ip = Sens(ip)));
+        }
+    } else {
+        admin_usage(server, admin, "unban");
+    }
+}
+
+fn baninfo_command(server: &mut Server, admin: Token, argument: &str) {
+    let ip = if let Ok(ip) = argument.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+        ip
+    } else {
+        admin_usage(server, admin, "baninfo");
+        return;
+    };
+    let now = Instant::now();
+    let mut lines = Vec::new();
+    match server.sinners.get(&ip) {
+        Some(Sinner::Banned(until, reason, _)) => {
+            let secs = until.saturating_duration_since(now).as_secs();
+            let reason = reason.map(|reason| reason.name()).unwrap_or("unknown");
+            lines.push(format!("{ip} is banned for {secs} more secs, reason: {reason}", ip = Sens(ip)));
+        }
+        Some(Sinner::Shadowed(count, _)) => lines.push(format!("{ip} is shadow banned with {count} of {limit} strikes", ip = Sens(ip), limit = server.config.strike_limit)),
+        Some(Sinner::Striked(count, strikes)) if *count > 0 => {
+            lines.push(format!("{ip} has {count} of {limit} strikes, the most recent:", ip = Sens(ip), limit = server.config.strike_limit));
+            for strike in strikes.iter() {
+                lines.push(format!("[{time}] {reason}", time = clock(to_system_time(strike.time)), reason = strike.reason.name()));
+            }
+        }
+        Some(Sinner::Striked(..)) | None => lines.push(format!("{ip} has no strikes", ip = Sens(ip))),
+    }
+    for line in lines.iter() {
+        server.reply(admin, line);
+    }
+}
+
+fn shadow_command(server: &mut Server, admin: Token, argument: &str) {
+    if let Ok(ip) = argument.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+        server.sinners.insert(ip, Sinner::shadowed());
+        server.save_bans();
+        println!("INFO: IP {ip} got shadow banned by admin", ip = Sens(ip));
+        server.log_event(&format!("SHADOW {ip}", ip = Sens(ip)));
+        server.reply(admin, &format!("Shadow banned {ip}", ip = Sens(ip)));
+    } else {
+        admin_usage(server, admin, "shadow");
+    }
+}
+
+fn unshadow_command(server: &mut Server, admin: Token, argument: &str) {
+    if let Ok(ip) = argument.parse::<IpAddr>().map(|ip| ip.to_canonical()) {
+        if server.sinners.get(&ip).is_some_and(Sinner::is_shadowed) {
+            server.sinners.remove(&ip);
+            server.save_bans();
+            println!("INFO: IP {ip} got unshadowed by admin", ip = Sens(ip));
+            server.log_event(&format!("UNSHADOW {ip}", ip = Sens(ip)));
+            server.reply(admin, &format!("Unshadowed {ip}", ip = Sens(ip)));
+        } else {
+            server.reply(admin, &format!("{ip} is not shadow banned", ip = Sens(ip)));
+        }
+    } else {
+        admin_usage(server, admin, "unshadow");
+    }
+}
+
+fn list_command(server: &mut Server, admin: Token, _argument: &str) {
+    let now = Instant::now();
+    let mut lines = Vec::new();
+    for client in server.clients.values() {
+        let secs = now.saturating_duration_since(client.last_seen).as_secs();
+        lines.push(format!("{addr} {nick} authed in #{room}, last seen {secs} secs ago", addr = Sens(client.conn.addr), nick = client.nick, room = client.room));
+    }
+    for waiting in server.waiting.values() {
+        let secs = now.saturating_duration_since(waiting.connected_at).as_secs();
+        lines.push(format!("{addr} waiting, connected {secs} secs ago", addr = Sens(waiting.conn.addr)));
+    }
+    lines.push(format!("{n} clients, {m} waiting", n = server.clients.len(), m = server.waiting.len()));
+    for line in lines.iter() {
+        server.reply(admin, line);
+    }
+}
+
+fn stats_command(server: &mut Server, admin: Token, _argument: &str) {
+    // NOTE: `name value` lines in the Prometheus text format, so they can be scraped as they are
+    let stats = &server.stats;
+    let mut lines = vec![
+        format!("connections_total {}", stats.connections),
+        format!("clients {}", server.clients.len()),
+        format!("waiting {}", server.waiting.len()),
+        format!("messages_total {}", stats.messages),
+        format!("bytes_in_total {}", stats.bytes_in),
+        format!("bytes_out_total {}", stats.bytes_out),
+    ];
+    for reason in StrikeReason::ALL {
+        lines.push(format!("strikes_total{{reason=\"{name}\"}} {count}", name = reason.name(), count = stats.strikes[reason as usize]));
+    }
+    lines.push(format!("bans_total {}", stats.bans));
+    for line in lines.iter() {
+        server.reply(admin, line);
+    }
+}
+
+/// Replaces the leaked token with a fresh one without kicking anyone who already authorized
+/// with it. The old token keeps working for ROTATE_GRACE, so the legit users have time to switch
+fn rotate_token_command(server: &mut Server, admin: Token, argument: &str) {
+    if argument.is_empty() {
+        admin_usage(server, admin, "rotate-token");
+        return;
+    }
+    let old_token = server.tokens
+        .iter()
+        .find(|(token, info)| token.as_str() == argument || info.nick.as_deref() == Some(argument))
+        .map(|(token, _)| token.clone());
+    let old_token = if let Some(old_token) = old_token {
+        old_token
+    } else {
+        server.reply(admin, &format!("No token {argument}"));
+        return;
+    };
+    let new_token = if let Ok(new_token) = generate_token() {
+        new_token
+    } else {
+        server.reply(admin, "Could not generate a new token");
+        return;
+    };
+    if replace_token(&server.config.token_file, &old_token, &new_token).is_err() {
+        server.reply(admin, &format!("Could not update {file_path}", file_path = server.config.token_file));
+        return;
+    }
+    if let Some(info) = server.tokens.remove(&old_token) {
+        server.tokens.insert(new_token.clone(), info);
+    }
+    // NOTE: the clients are moved to the new token, so the next reload of the tokens does not
+    // disconnect them
+    let mut n = 0;
+    for client in server.clients.values_mut().filter(|client| client.token == old_token) {
+        client.token = new_token.clone();
+        n += 1;
+    }
+    server.rotated.retain(|_, rotated| rotated.token != old_token);
+    server.rotated.insert(old_token, RotatedToken {
+        token: new_token,
+        until: Instant::now() + ROTATE_GRACE,
+    });
+    // NOTE: the tokens never get into the logs
+    println!("INFO: admin rotated a token used by {n} clients");
+    server.log_event(&format!("ROTATE_TOKEN {n}"));
+    server.reply(admin, &format!("Rotated the token, the new one is in {file_path}. The old one works for {secs} more secs", file_path = server.config.token_file, secs = ROTATE_GRACE.as_secs()));
+}
+
+fn shutdown_command(server: &mut Server, admin: Token, _argument: &str) {
+    server.reply(admin, "Shutting down the Server");
+    server.quit = true;
+}
+
+fn nick_error(nick: &str) -> Option<&'static str> {
+    // NOTE: the width, not the amount of characters, so the wide ones count twice
+    if nick.width() > NICK_LIMIT {
+        Some("Nick is too long")
+    } else if nick.width() == 0 {
+        Some("Nick must be visible")
+    } else if nick.chars().any(|x| x.is_whitespace() || x.is_control() || x == '<' || x == '>') {
+        Some("Nick may not contain spaces or angle brackets")
+    } else if nick.starts_with('#') {
+        Some("Nick may not start with #")
+    } else {
+        None
+    }
+}
+
+/// Cuts the line down to at most `limit` bytes without splitting a character
+fn truncate(line: &str, limit: usize) -> &str {
+    let mut end = line.len().min(limit);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+fn room_error(room: &str) -> Option<&'static str> {
+    if room.chars().count() > ROOM_LIMIT {
+        Some("Room name is too long")
+    } else if !room.chars().all(|x| x.is_alphanumeric() || x == '-' || x == '_') {
+        Some("Room name may only contain letters, digits, dashes and underscores")
+    } else {
+        None
+    }
+}
+
+/// Sends a line to a connection that was never registered in the Server and closes it. The line
+/// is not encrypted even with Config::tls, the refused clients are not worth the handshake
+fn refuse(mut stream: Box<dyn Transport>, addr: SocketAddr, message: &str) {
+    let _ = writeln!(stream, "{ERROR_TAG}{message}").map_err(|err| if !hung_up(&err) {
+        eprintln!("ERROR: could not send refusal message to {addr}: {err}", addr = Sens(addr), err = Sens(err));
+    });
+    let _ = stream.shutdown(Shutdown::Both).map_err(|err| if !hung_up(&err) {
+        eprintln!("ERROR: could not shutdown socket for {addr}: {err}", addr = Sens(addr), err = Sens(err));
+    });
+}
+
+/// Whether the error only means that the client is already gone. Not worth logging, since the
+/// clients that connect and hang up right away would flood the logs with it
+fn hung_up(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::NotConnected)
+}
+
+fn attempts_expired(attempts: &Attempts, now: Instant) -> bool {
+    now.saturating_duration_since(attempts.window_start) >= CONNECTION_RATE_WINDOW
+}
+
+/// Converts the moment of the monotonic clock to the wall clock, so it can be saved to a file
+fn to_system_time(instant: Instant) -> SystemTime {
+    let (now, system_now) = (Instant::now(), SystemTime::now());
+    if instant >= now {
+        // NOTE: the bans from the file edited by hand may last longer than the wall clock goes
+        system_now.checked_add(instant - now).unwrap_or(system_now + DURATION_LIMIT)
+    } else {
+        system_now.checked_sub(now - instant).unwrap_or(UNIX_EPOCH)
+    }
+}
+
+/// Converts the time loaded from a file to the monotonic clock. Returns None if the time is too
+/// far in the future for the monotonic clock
+fn to_instant(time: SystemTime) -> Option<Instant> {
+    let (now, system_now) = (Instant::now(), SystemTime::now());
+    match time.duration_since(system_now) {
+        Ok(ahead) => now.checked_add(ahead),
+        Err(err) => Some(now.checked_sub(err.duration()).unwrap_or(now)),
+    }
+}
+
+/// The lines of Config::banned_file for all the bans that are still in effect. Each line is either
+/// `<ip> <until> [reason]` with the unix time the ban expires at or `<ip> shadow`
+fn format_bans(sinners: &HashMap<IpAddr, Sinner>, now: Instant) -> String {
+    let mut content = String::new();
+    for (ip, sinner) in sinners.iter() {
+        match sinner {
+            Sinner::Banned(until, reason, _) => if !ban_expired(*until, now) {
+                let secs = to_system_time(*until).duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs();
+                match reason {
+                    Some(reason) => {
+                        let _ = writeln!(&mut content, "{ip} {secs} {reason}", reason = reason.name());
+                    }
+                    None => {
+                        let _ = writeln!(&mut content, "{ip} {secs}");
+                    }
+                }
+            },
+            Sinner::Shadowed(..) => {
+                let _ = writeln!(&mut content, "{ip} shadow");
+            }
+            Sinner::Striked(..) => {}
+        }
+    }
+    content
+}
+
+/// Loads the bans saved by Server::save_bans() skipping the ones that already expired
+fn load_bans(file_path: &str) -> HashMap<IpAddr, Sinner> {
+    let content = match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                eprintln!("ERROR: could not load bans from {file_path}: {err}");
+            }
+            return HashMap::new();
+        }
+    };
+    let sinners = parse_bans(file_path, &content, Instant::now());
+    println!("INFO: loaded {n} bans from {file_path}", n = sinners.len());
+    sinners
+}
+
+/// Parses the lines written by format_bans(). The file is edited by hand sometimes, so the broken
+/// lines (including the ones with the time the clocks can't represent) are skipped with a warning
+fn parse_bans(file_path: &str, content: &str, now: Instant) -> HashMap<IpAddr, Sinner> {
+    let mut sinners = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let mut fields = line.split_whitespace();
+        let ban = fields.next().zip(fields.next()).and_then(|(ip, until)| {
+            let sinner = if until == "shadow" {
+                Sinner::shadowed()
+            } else {
+                let until = UNIX_EPOCH.checked_add(Duration::from_secs(until.parse::<u64>().ok()?))?;
+                let until = to_instant(until)?;
+                let reason = match fields.next() {
+                    Some(name) => Some(StrikeReason::from_name(name)?),
+                    None => None,
+                };
+                Sinner::Banned(until, reason, 0)
+            };
+            Some((ip.parse::<IpAddr>().ok()?.to_canonical(), sinner))
+        });
+        if let Some((ip, sinner)) = ban {
+            if !sinner.expired(now) {
+                sinners.insert(ip, sinner);
+            }
+        } else {
+            eprintln!("WARNING: {file_path}:{line_number}: skipping invalid ban entry", line_number = index + 1);
+        }
+    }
+    sinners
+}
+
+/// Returns no lines if there is no MOTD
+fn load_motd(file_path: &str) -> Vec<String> {
+    let mut bytes = Vec::new();
+    let result = fs::File::open(file_path).and_then(|file| file.take(MOTD_LIMIT as u64).read_to_end(&mut bytes));
+    match result {
+        Ok(_) => String::from_utf8_lossy(&bytes)
+            .lines()
+            .filter_map(|line| sanitize(line.as_bytes()))
+            .collect(),
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                eprintln!("ERROR: could not read MOTD from {file_path}: {err}");
+            }
+            Vec::new()
+        }
+    }
+}
+
+/// Compares the byte strings in the time that does not depend on the position of the first
+/// mismatch, so the token can't be guessed byte by byte by measuring how fast we reject it
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reads the IPs and CIDR ranges one per line. Everything after `#` is a comment
+fn load_allowlist(file_path: &str) -> Result<Vec<IpRange>> {
+    let content = fs::read_to_string(file_path).map_err(|err| {
+        eprintln!("ERROR: could not load allowlist from {file_path}: {err}");
+    })?;
+    let mut ranges = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split_once('#').map(|(line, _)| line).unwrap_or(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(range) = IpRange::parse(line) {
+            ranges.push(range);
+        } else {
+            eprintln!("ERROR: {file_path}:{line_number}: invalid IP or CIDR range {line:?}", line_number = index + 1);
+        }
+    }
+    Ok(ranges)
+}
+
+/// Loads the tokens from the file where each line is `<token> [nick]`
+pub fn load_tokens(file_path: &str) -> Result<HashMap<String, TokenInfo>> {
+    let mut tokens = HashMap::new();
+    let content = match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                return Ok(tokens);
+            }
+            eprintln!("ERROR: could not load tokens from {file_path}: {err}");
+            return Err(());
+        }
+    };
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (token, nick) = match line.split_once(char::is_whitespace) {
+            Some((token, nick)) => (token, Some(nick.trim().to_string())),
+            None => (line, None),
+        };
+        if let Some(error) = nick.as_deref().and_then(nick_error) {
+            eprintln!("ERROR: {file_path}:{line_number}: {error}", line_number = index + 1);
+            continue;
+        }
+        tokens.insert(token.to_string(), TokenInfo { nick });
+    }
+    Ok(tokens)
+}
+
+/// Puts the new token in place of the old one keeping the rest of the file intact. The file is
+/// replaced atomically, so a crash never leaves it half-written
+fn replace_token(file_path: &str, old_token: &str, new_token: &str) -> Result<()> {
+    let content = fs::read_to_string(file_path).map_err(|err| {
+        eprintln!("ERROR: could not load tokens from {file_path}: {err}");
+    })?;
+    let mut updated = String::new();
+    for line in content.lines() {
+        match line.trim().split_once(char::is_whitespace) {
+            Some((token, nick)) if token == old_token => writeln!(&mut updated, "{new_token} {nick}", nick = nick.trim()),
+            None if line.trim() == old_token => writeln!(&mut updated, "{new_token}"),
+            _ => writeln!(&mut updated, "{line}"),
+        }.expect("writing to a String does not fail");
+    }
+    let tmp_path = format!("{file_path}.tmp");
+    fs::write(&tmp_path, updated).map_err(|err| {
+        eprintln!("ERROR: could not write tokens to {tmp_path}: {err}");
+    })?;
+    fs::rename(&tmp_path, file_path).map_err(|err| {
+        eprintln!("ERROR: could not replace {file_path} with {tmp_path}: {err}");
+    })
+}
+
+pub fn append_token(file_path: &str, token: &str) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(file_path).map_err(|err| {
+        eprintln!("ERROR: could not open tokens file {file_path}: {err}");
+    })?;
+    writeln!(file, "{token}").map_err(|err| {
+        eprintln!("ERROR: could not append token to {file_path}: {err}");
+    })
+}
+
+/// Loads the certificate chain and the private key for Config::tls
+pub fn load_tls(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<result::Result<Vec<_>, _>>())
+        .map_err(|err| {
+            eprintln!("ERROR: could not load TLS certificates from {cert_path}: {err}");
+        })?;
+    let fingerprint = certs.first().map(|cert| fingerprint(cert)).ok_or_else(|| {
+        eprintln!("ERROR: there are no certificates in {cert_path}");
+    })?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| {
+        eprintln!("ERROR: could not load TLS private key from {key_path}: {err}");
+    })?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| {
+            eprintln!("ERROR: invalid TLS certificate {cert_path} or private key {key_path}: {err}");
+        })?;
+    // NOTE: the Client can pin this fingerprint to trust a self-signed certificate
+    println!("INFO: TLS certificate fingerprint: {fingerprint}");
+    Ok(Arc::new(config))
+}
+
+pub fn generate_token() -> Result<String> {
+    let mut buffer = [0; TOKEN_BYTES];
+    getrandom(&mut buffer).map_err(|err| {
+        eprintln!("ERROR: could not generate random access token: {err}");
+    })?;
+
+    let mut token = String::new();
+    for x in buffer.iter() {
+        let _ = write!(&mut token, "{x:02X}");
+    }
+    Ok(token)
+}
+
+/// Settings that can be changed without recompiling. Read from the config file by load_config()
+/// and then overridden by the command line flags
+pub struct Config {
+    pub port: u16,
+    /// Port for the browser clients, they are not accepted at all without it
+    pub websocket_port: Option<u16>,
+    /// Port on 127.0.0.1 for the admin console. Without it the admin token works on any port
+    pub admin_port: Option<u16>,
+    pub bind: IpAddr,
+    pub safe_mode: bool,
+    /// Log every rejection and failed authorization instead of throttling them, see LogThrottle
+    pub verbose: bool,
+    pub token_file: String,
+    /// Where the bans are saved, so they survive the restarts
+    pub banned_file: String,
+    pub log_file: Option<String>,
+    pub allowlist: Option<String>,
+    /// Every connection starts with the PROXY protocol v1 header telling the actual address of
+    /// the client. Only for the Servers behind a proxy like HAProxy
+    pub proxy_protocol: bool,
+    pub tls: bool,
+    /// PEM file with the certificate chain for TLS
+    pub cert: Option<String>,
+    /// PEM file with the private key for TLS
+    pub key: Option<String>,
+    pub ban_limit: Duration,
+    pub message_rate: Duration,
+    /// How many bytes may be queued for a client before it is dropped as a laggard
+    pub outgoing_limit: usize,
+    pub slowloris_limit: Duration,
+    pub strike_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            websocket_port: None,
+            admin_port: None,
+            bind: DEFAULT_BIND,
+            safe_mode: safe_mode_from_env(),
+            verbose: false,
+            token_file: TOKENS_FILE_PATH.to_string(),
+            banned_file: BANNED_FILE_PATH.to_string(),
+            log_file: None,
+            allowlist: None,
+            proxy_protocol: false,
+            tls: false,
+            cert: None,
+            key: None,
+            ban_limit: BAN_LIMIT,
+            message_rate: MESSAGE_RATE,
+            outgoing_limit: OUTGOING_LIMIT,
+            slowloris_limit: SLOWLORIS_LIMIT,
+            strike_limit: STRIKE_LIMIT,
+        }
+    }
+}
+
+impl Config {
+    /// Returns false if there is no such key. The durations are in seconds
+    pub fn set(&mut self, key: &str, value: &str) -> result::Result<bool, String> {
+        fn parse<T: FromStr>(value: &str) -> result::Result<T, String> where T::Err: fmt::Display {
+            value.parse().map_err(|err| format!("invalid value {value:?}: {err}"))
+        }
+        fn parse_secs(value: &str) -> result::Result<Duration, String> {
+            let secs: f64 = parse(value)?;
+            if !(secs.is_finite() && secs > 0.0) {
+                return Err(format!("invalid value {value:?}: must be a positive amount of seconds"));
+            }
+            let duration = Duration::try_from_secs_f64(secs).map_err(|err| format!("invalid value {value:?}: {err}"))?;
+            if duration > DURATION_LIMIT {
+                return Err(format!("invalid value {value:?}: must be at most {limit} seconds", limit = DURATION_LIMIT.as_secs()));
+            }
+            Ok(duration)
+        }
+        match key {
+            "port" => self.port = parse(value)?,
+            "websocket_port" => self.websocket_port = Some(parse(value)?),
+            "admin_port" => self.admin_port = Some(parse(value)?),
+            "bind" => self.bind = parse(value)?,
+            "safe_mode" => self.safe_mode = parse(value)?,
+            "verbose" => self.verbose = parse(value)?,
+            "token_file" => self.token_file = value.to_string(),
+            "banned_file" => self.banned_file = value.to_string(),
+            "log_file" => self.log_file = Some(value.to_string()),
+            "allowlist" => self.allowlist = Some(value.to_string()),
+            "proxy_protocol" => self.proxy_protocol = parse(value)?,
+            "tls" => self.tls = parse(value)?,
+            "cert" => self.cert = Some(value.to_string()),
+            "key" => self.key = Some(value.to_string()),
+            "ban_limit" => self.ban_limit = parse_secs(value)?,
+            "message_rate" => self.message_rate = parse_secs(value)?,
+            "outgoing_limit" => self.outgoing_limit = parse(value)?,
+            "slowloris_limit" => self.slowloris_limit = parse_secs(value)?,
+            "strike_limit" => self.strike_limit = parse(value)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn print(&self) {
+        println!("INFO: config: port = {}", self.port);
+        match self.websocket_port {
+            Some(port) => println!("INFO: config: websocket_port = {port}"),
+            None => println!("INFO: config: websocket_port = none"),
+        }
+        match self.admin_port {
+            Some(port) => println!("INFO: config: admin_port = {port}"),
+            None => println!("INFO: config: admin_port = none"),
+        }
+        println!("INFO: config: bind = {}", Sens(self.bind));
+        println!("INFO: config: safe_mode = {}", self.safe_mode);
+        println!("INFO: config: verbose = {}", self.verbose);
+        println!("INFO: config: token_file = {}", self.token_file);
+        println!("INFO: config: banned_file = {}", self.banned_file);
+        println!("INFO: config: log_file = {}", self.log_file.as_deref().unwrap_or("none"));
+        println!("INFO: config: allowlist = {}", self.allowlist.as_deref().unwrap_or("none"));
+        println!("INFO: config: proxy_protocol = {}", self.proxy_protocol);
+        println!("INFO: config: tls = {}", self.tls);
+        println!("INFO: config: cert = {}", self.cert.as_deref().unwrap_or("none"));
+        println!("INFO: config: key = {}", self.key.as_deref().unwrap_or("none"));
+        println!("INFO: config: ban_limit = {}", self.ban_limit.as_secs_f64());
+        println!("INFO: config: message_rate = {}", self.message_rate.as_secs_f64());
+        println!("INFO: config: outgoing_limit = {}", self.outgoing_limit);
+        println!("INFO: config: slowloris_limit = {}", self.slowloris_limit.as_secs_f64());
+        println!("INFO: config: strike_limit = {}", self.strike_limit);
+    }
+}
+
+/// Reads `key = value` lines. Everything after `#` is a comment. A missing file is the same as
+/// an empty one
+pub fn load_config(file_path: &str) -> Result<Config> {
+    let mut config = Config::default();
+    let content = match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                return Ok(config);
+            }
+            eprintln!("ERROR: could not load config from {file_path}: {err}");
+            return Err(());
+        }
+    };
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split_once('#').map(|(line, _)| line).unwrap_or(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            eprintln!("ERROR: {file_path}:{line_number}: expected key = value");
+        })?;
+        let (key, value) = (key.trim(), value.trim());
+        let known = config.set(key, value).map_err(|err| {
+            eprintln!("ERROR: {file_path}:{line_number}: {key}: {err}");
+        })?;
+        if !known {
+            eprintln!("WARNING: {file_path}:{line_number}: unknown key {key}");
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::net::Ipv4Addr;
+    use std::env;
+    use std::ops::{Deref, DerefMut};
+    use std::rc::Rc;
+    use mio::Poll;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use crate::protocol::{split_sequence, split_stamp};
+    use crate::moderation::MESSAGE_BURST;
+
+    const TOKEN: &str = "user-token";
+    const ADMIN_TOKEN: &str = "admin-token";
+
+    #[derive(Default)]
+    struct Pipe {
+        /// Sent by the client, waiting for the Server to read it
+        incoming: Vec<u8>,
+        /// Written by the Server
+        outgoing: Vec<u8>,
+        /// The client hung up, so the reads return 0 once the incoming bytes are over
+        closed: bool,
+        /// The Server closed the connection
+        shutdown: bool,
+    }
+
+    /// The in-memory socket. The test keeps a clone of it to talk to the Server
+    #[derive(Clone, Default)]
+    struct MemoryStream(Rc<RefCell<Pipe>>);
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let n = self.peek(buffer)?;
+            self.0.borrow_mut().incoming.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().outgoing.extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Source for MemoryStream {
+        fn register(&mut self, _registry: &Registry, _token: Token, _interests: Interest) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reregister(&mut self, _registry: &Registry, _token: Token, _interests: Interest) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn deregister(&mut self, _registry: &Registry) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryStream {
+        fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            let pipe = self.0.borrow();
+            if pipe.incoming.is_empty() {
+                return if pipe.closed { Ok(0) } else { Err(io::ErrorKind::WouldBlock.into()) };
+            }
+            let n = buffer.len().min(pipe.incoming.len());
+            buffer[..n].copy_from_slice(&pipe.incoming[..n]);
+            Ok(n)
+        }
+
+        fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+            self.0.borrow_mut().shutdown = true;
+            Ok(())
+        }
+    }
+
+    /// The client side of a connection to the Server
+    struct Peer {
+        token: Token,
+        addr: SocketAddr,
+        stream: MemoryStream,
+    }
+
+    impl Peer {
+        /// Delivers the bytes to the Server in one read
+        fn write(&self, server: &mut Server, bytes: &[u8]) {
+            self.stream.0.borrow_mut().incoming.extend_from_slice(bytes);
+            server.client_read(self.token);
+            server.flush();
+        }
+
+        fn send(&self, server: &mut Server, line: &str) {
+            self.write(server, format!("{line}\n").as_bytes());
+        }
+
+        /// The lines the Server sent since the last call without the sequence numbers and the stamps
+        fn received(&self) -> Vec<String> {
+            let outgoing = mem::take(&mut self.stream.0.borrow_mut().outgoing);
+            outgoing
+                .split(|x| *x == FRAME_SEPARATOR)
+                .filter(|frame| !frame.is_empty())
+                .map(|frame| {
+                    let (_, frame) = split_sequence(frame);
+                    let line = String::from_utf8_lossy(frame).to_string();
+                    let (echo, line) = match line.strip_prefix(ECHO_TAG) {
+                        Some(line) => (true, line),
+                        None => (false, line.as_str()),
+                    };
+                    let line = split_stamp(line).map(|(_, line)| line).unwrap_or(line);
+                    if echo {
+                        format!("{ECHO_TAG}{line}")
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .filter(|line| !line.is_empty())
+                .collect()
+        }
+
+        /// Hangs up on the Server
+        fn close(&self, server: &mut Server) {
+            self.stream.0.borrow_mut().closed = true;
+            server.client_read(self.token);
+            server.flush();
+        }
+
+        fn is_shut_down(&self) -> bool {
+            self.stream.0.borrow().shutdown
+        }
+    }
+
+    /// Config that keeps the bans of each test in its own file, see TestServer
+    fn test_config(name: &str) -> Config {
+        let banned_file = env::temp_dir().join(format!("4at-{name}-{pid}-BANNED", pid = std::process::id()));
+        Config {
+            banned_file: banned_file.to_string_lossy().to_string(),
+            ..Config::default()
+        }
+    }
+
+    /// The Server of a test, its bans are deleted along with it
+    struct TestServer(Server);
+
+    impl Deref for TestServer {
+        type Target = Server;
+
+        fn deref(&self) -> &Server {
+            &self.0
+        }
+    }
+
+    impl DerefMut for TestServer {
+        fn deref_mut(&mut self) -> &mut Server {
+            &mut self.0
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0.config.banned_file);
+        }
+    }
+
+    fn test_server(config: Config) -> TestServer {
+        let mut tokens = HashMap::new();
+        tokens.insert(TOKEN.to_string(), TokenInfo { nick: None });
+        // NOTE: the Poll itself is not needed, the MemoryStreams ignore the registrations anyway
+        let registry = Poll::new().and_then(|poll| poll.registry().try_clone()).expect("registry");
+        TestServer(Server::new(tokens, ADMIN_TOKEN.to_string(), registry, None, None, None, config))
+    }
+
+    /// Connects from the IP without sending anything yet
+    fn connect(server: &mut Server, ip: &str) -> Peer {
+        // NOTE: the port tells the connections from the same IP apart
+        let port = 1024 + server.waiting.len() + server.clients.len();
+        let addr = SocketAddr::new(ip.parse().expect("ip"), port as u16);
+        let stream = MemoryStream::default();
+        server.client_connected(stream.clone(), addr, Origin::Chat);
+        server.flush();
+        let token = server.waiting
+            .iter()
+            .find(|(_, waiting)| waiting.conn.addr == addr)
+            .map(|(token, _)| *token)
+            .unwrap_or(Token(usize::MAX));
+        Peer { token, addr, stream }
+    }
+
+    /// Connects from the IP and authorizes with TOKEN dropping everything the Server says upon that
+    fn join(server: &mut Server, ip: &str) -> Peer {
+        let peer = connect(server, ip);
+        peer.send(server, TOKEN);
+        assert!(peer.received().iter().any(|line| line == WELCOME));
+        peer
+    }
+
+    #[test]
+    fn bans_survive_round_trip() {
+        let now = Instant::now();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let mut sinners = HashMap::new();
+        sinners.insert(ip("10.0.0.1"), Sinner::Banned(now + Duration::from_secs(3600), Some(StrikeReason::AuthFailure), 2));
+        sinners.insert(ip("10.0.0.2"), Sinner::Banned(now + Duration::from_secs(60), None, 0));
+        sinners.insert(ip("::1"), Sinner::shadowed());
+        sinners.insert(ip("10.0.0.3"), Sinner::Banned(now, Some(StrikeReason::RateLimit), 0));
+        sinners.insert(ip("10.0.0.4"), Sinner::new());
+        let loaded = parse_bans("BANNED", &format_bans(&sinners, now), now);
+        assert_eq!(loaded.len(), 3, "the expired ban and the strikes are not saved");
+        match loaded.get(&ip("10.0.0.1")) {
+            Some(Sinner::Banned(until, Some(StrikeReason::AuthFailure), 0)) => {
+                let secs = until.saturating_duration_since(now).as_secs_f64();
+                assert!((3599.0..=3601.0).contains(&secs), "{secs}");
+            }
+            _ => panic!("ban with a reason is lost"),
+        }
+        assert!(matches!(loaded.get(&ip("10.0.0.2")), Some(Sinner::Banned(_, None, 0))));
+        assert!(matches!(loaded.get(&ip("::1")), Some(Sinner::Shadowed(0, _))));
+    }
+
+    #[test]
+    fn corrupt_bans_are_skipped() {
+        let now = Instant::now();
+        let content = [
+            "10.0.0.1 18446744073709551615 admin",
+            "10.0.0.2 9223372036854775807",
+            "10.0.0.3 -5",
+            "10.0.0.4 99999999999 bogus-reason",
+            "not-an-ip 99999999999",
+            "10.0.0.5",
+            "10.0.0.6 99999999999 admin",
+        ].join("\n");
+        let loaded = parse_bans("BANNED", &content, now);
+        assert_eq!(loaded.len(), 2);
+        assert!(matches!(loaded.get(&"10.0.0.6".parse::<IpAddr>().unwrap()), Some(Sinner::Banned(_, Some(StrikeReason::Admin), 0))));
+        // NOTE: the latest time the wall clock can represent is still a valid ban, it just has
+        // to be saved back without overflowing
+        assert!(matches!(loaded.get(&"10.0.0.2".parse::<IpAddr>().unwrap()), Some(Sinner::Banned(_, None, 0))));
+        assert_eq!(parse_bans("BANNED", &format_bans(&loaded, now), now).len(), 2);
+    }
+
+    #[test]
+    fn config_durations_are_bounded() {
+        let mut config = Config::default();
+        for key in ["ban_limit", "message_rate", "slowloris_limit"] {
+            assert!(config.set(key, "1e18").is_err(), "{key}");
+            assert!(config.set(key, "inf").is_err(), "{key}");
+            assert!(config.set(key, "0").is_err(), "{key}");
+            assert_eq!(config.set(key, &DURATION_LIMIT.as_secs().to_string()), Ok(true), "{key}");
+        }
+        assert_eq!(config.set("ban_limit", "1.5"), Ok(true));
+        assert_eq!(config.ban_limit, Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn auth_success() {
+        let mut server = test_server(test_config("auth_success"));
+        let peer = connect(&mut server, "10.0.0.1");
+        peer.send(&mut server, &format!("  {TOKEN} \r"));
+        let lines = peer.received();
+        assert!(lines.iter().any(|line| line == WELCOME), "{lines:?}");
+        assert!(lines.iter().any(|line| line == "You are #1"), "{lines:?}");
+        assert!(server.clients.contains_key(&peer.token));
+        assert!(server.waiting.is_empty());
+    }
+
+    #[test]
+    fn auth_failure() {
+        let mut server = test_server(test_config("auth_failure"));
+        let peer = connect(&mut server, "10.0.0.1");
+        peer.send(&mut server, "not-a-token");
+        assert_eq!(peer.received(), vec![format!("{DISCONNECT_TAG}disconnected: invalid token! Bruh!")]);
+        assert!(peer.is_shut_down());
+        assert!(server.waiting.is_empty() && server.clients.is_empty());
+        assert!(matches!(server.sinners.get(&peer.addr.ip()), Some(Sinner::Striked(1, _))));
+        // NOTE: without the admin port the admin token works on the chat port too
+        let admin = connect(&mut server, "10.0.0.2");
+        admin.send(&mut server, ADMIN_TOKEN);
+        assert!(server.clients.get(&admin.token).is_some_and(|client| client.admin));
+    }
+
+    #[test]
+    fn rate_limit_strikes() {
+        let mut server = test_server(test_config("rate_limit_strikes"));
+        let peer = join(&mut server, "10.0.0.1");
+        let burst = MESSAGE_BURST as usize;
+        for i in 0..burst {
+            peer.send(&mut server, &format!("message {i}"));
+        }
+        assert_eq!(peer.received().len(), burst);
+        // NOTE: the first overflow of the bucket is only a warning
+        peer.send(&mut server, "one too many");
+        let lines = peer.received();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with(&format!("{ERROR_TAG}rate-limited: wait")), "{lines:?}");
+        assert!(matches!(server.sinners.get(&peer.addr.ip()), Some(Sinner::Striked(0, _))));
+        peer.send(&mut server, "and another one");
+        let lines = peer.received();
+        assert!(lines.iter().any(|line| line.starts_with(&format!("{ERROR_TAG}Warning: strike 1 of {STRIKE_LIMIT}"))), "{lines:?}");
+        assert!(matches!(server.sinners.get(&peer.addr.ip()), Some(Sinner::Striked(1, _))));
+    }
+
+    #[test]
+    fn ban_expiry() {
+        let mut config = test_config("ban_expiry");
+        config.strike_limit = 0;
+        let banned_file = config.banned_file.clone();
+        let mut server = test_server(config);
+        let peer = connect(&mut server, "10.0.0.1");
+        peer.send(&mut server, "not-a-token");
+        assert!(peer.received()[0].starts_with(&format!("{DISCONNECT_TAG}disconnected: banned")));
+        assert!(fs::read_to_string(&banned_file).is_ok_and(|content| content.starts_with("10.0.0.1 ")));
+
+        let refused = connect(&mut server, "10.0.0.1");
+        assert!(refused.received()[0].starts_with(&format!("{ERROR_TAG}You are banned MF")));
+        assert!(refused.is_shut_down());
+
+        if let Some(Sinner::Banned(until, ..)) = server.sinners.get_mut(&peer.addr.ip()) {
+            *until = Instant::now();
+        }
+        server.sweep();
+        assert!(server.sinners.is_empty());
+        assert_eq!(fs::read_to_string(&banned_file).ok().as_deref(), Some(""));
+        join(&mut server, "10.0.0.1");
+    }
+
+    #[test]
+    fn broadcast_excludes_author() {
+        let mut server = test_server(test_config("broadcast_excludes_author"));
+        let author = join(&mut server, "10.0.0.1");
+        let other = join(&mut server, "10.0.0.2");
+        assert_eq!(author.received(), vec!["* #2 joined"]);
+        author.send(&mut server, "hello");
+        assert_eq!(author.received(), vec![format!("{ECHO_TAG}<#1> hello")]);
+        assert_eq!(other.received(), vec!["<#1> hello"]);
+    }
+
+    #[test]
+    fn shadowed_client_only_sees_itself() {
+        let mut server = test_server(test_config("shadowed_client_only_sees_itself"));
+        let other = join(&mut server, "10.0.0.1");
+        let shadowed = join(&mut server, "10.0.0.2");
+        let admin = connect(&mut server, "10.0.0.3");
+        admin.send(&mut server, ADMIN_TOKEN);
+        admin.send(&mut server, "/shadow 10.0.0.2");
+        assert!(admin.received().iter().any(|line| line == "Shadow banned 10.0.0.2"));
+        other.received();
+        shadowed.received();
+
+        shadowed.send(&mut server, "/nick troll");
+        shadowed.send(&mut server, "/away brb");
+        shadowed.send(&mut server, "hello");
+        shadowed.send(&mut server, "/join other");
+        assert_eq!(shadowed.received(), vec![
+            "* #2 is now known as troll".to_string(),
+            "* troll is away (brb)".to_string(),
+            "* troll is back".to_string(),
+            format!("{ECHO_TAG}<troll> hello"),
+            "* troll joined #other".to_string(),
+            "There are 1 members in #other".to_string(),
+        ]);
+        let again = join(&mut server, "10.0.0.2");
+        again.send(&mut server, "hello again");
+        assert_eq!(again.received(), vec![format!("{ECHO_TAG}<#4> hello again")]);
+        assert_eq!(other.received(), Vec::<String>::new());
+        assert_eq!(admin.received(), Vec::<String>::new());
+
+        other.send(&mut server, "hi");
+        assert_eq!(again.received(), vec!["<#1> hi"]);
+    }
+
+    #[test]
+    fn shadowed_flooder_gets_banned() {
+        let mut config = test_config("shadowed_flooder_gets_banned");
+        config.strike_limit = 1;
+        let mut server = test_server(config);
+        let peer = join(&mut server, "10.0.0.1");
+        server.sinners.insert(peer.addr.ip(), Sinner::shadowed());
+        for i in 0..MESSAGE_BURST as usize + 3 {
+            peer.send(&mut server, &format!("message {i}"));
+        }
+        assert!(peer.is_shut_down());
+        assert!(matches!(server.sinners.get(&peer.addr.ip()), Some(Sinner::Banned(_, Some(StrikeReason::RateLimit), _))));
+    }
+
+    #[test]
+    fn away_is_a_message() {
+        let mut server = test_server(test_config("away_is_a_message"));
+        let other = join(&mut server, "10.0.0.1");
+        let shadowed = join(&mut server, "10.0.0.2");
+        server.sinners.insert(shadowed.addr.ip(), Sinner::shadowed());
+        other.received();
+        shadowed.send(&mut server, "/away lunch");
+        other.send(&mut server, "/list");
+        assert_eq!(other.received(), vec!["2 clients online", "#1", "#2"]);
+        shadowed.send(&mut server, "/list");
+        assert_eq!(shadowed.received(), vec!["* #2 is away (lunch)", "2 clients online", "#1", "#2 (away: lunch)"]);
+
+        // NOTE: the /list took one token from the bucket already
+        for _ in 1..MESSAGE_BURST as usize {
+            other.send(&mut server, "/away");
+        }
+        other.received();
+        other.send(&mut server, "/away spam");
+        let lines = other.received();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with(&format!("{ERROR_TAG}rate-limited: wait")), "{lines:?}");
+        other.send(&mut server, "/away more spam");
+        assert!(matches!(server.sinners.get(&other.addr.ip()), Some(Sinner::Striked(1, _))));
+        assert_eq!(shadowed.received().len(), MESSAGE_BURST as usize - 1);
+    }
+
+    #[test]
+    fn token_and_message_split_across_reads() {
+        let mut server = test_server(test_config("token_and_message_split_across_reads"));
+        let other = join(&mut server, "10.0.0.1");
+        let peer = connect(&mut server, "10.0.0.2");
+        for byte in format!("{TOKEN}\n").bytes() {
+            peer.write(&mut server, &[byte]);
+        }
+        assert!(peer.received().iter().any(|line| line == WELCOME));
+        other.received();
+        peer.write(&mut server, b"hello ");
+        assert_eq!(other.received(), Vec::<String>::new());
+        peer.write(&mut server, b"world\n");
+        assert_eq!(other.received(), vec!["<#2> hello world"]);
+    }
+
+    #[test]
+    fn waiting_client_cannot_buffer_more_than_token() {
+        let mut server = test_server(test_config("waiting_client_cannot_buffer_more_than_token"));
+        let peer = connect(&mut server, "10.0.0.1");
+        peer.write(&mut server, TOKEN.as_bytes());
+        assert!(server.waiting.contains_key(&peer.token));
+        peer.write(&mut server, &[b'x'; TOKEN_PADDING + 8]);
+        assert!(peer.is_shut_down());
+        assert!(server.waiting.is_empty());
+        assert!(matches!(server.sinners.get(&peer.addr.ip()), Some(Sinner::Striked(1, _))));
+    }
+
+    #[test]
+    fn tokens_are_recycled() {
+        let mut server = test_server(test_config("tokens_are_recycled"));
+        let first = connect(&mut server, "10.0.0.1");
+        first.close(&mut server);
+        let last_token = server.last_token;
+        for i in 0..100_000u32 {
+            // NOTE: a new IP each time, so the connection rate limit is not in the way
+            let ip = Ipv4Addr::from(0x0b00_0000 + i).to_string();
+            let peer = connect(&mut server, &ip);
+            assert_eq!(peer.token, first.token);
+            if i % 2 == 0 {
+                peer.send(&mut server, TOKEN);
+            }
+            peer.close(&mut server);
+        }
+        assert_eq!(server.last_token, last_token);
+        assert_eq!(server.free_tokens, vec![first.token]);
+        assert!(server.clients.is_empty() && server.waiting.is_empty() && server.rooms.is_empty());
+    }
+
+    #[test]
+    fn waiting_connections_are_limited() {
+        let mut server = test_server(test_config("waiting_connections_are_limited"));
+        for _ in 0..WAITING_PER_IP_LIMIT {
+            assert!(!connect(&mut server, "10.0.0.1").is_shut_down());
+        }
+        let refused = connect(&mut server, "10.0.0.1");
+        assert!(refused.is_shut_down());
+        assert_eq!(refused.received(), vec![format!("{ERROR_TAG}Too many connections. Authorize the ones you already have.")]);
+        assert!(matches!(server.sinners.get(&refused.addr.ip()), Some(Sinner::Striked(1, _))));
+
+        for i in server.waiting.len()..WAITING_LIMIT {
+            let ip = Ipv4Addr::from(0x0b00_0000 + i as u32).to_string();
+            assert!(!connect(&mut server, &ip).is_shut_down());
+        }
+        let refused = connect(&mut server, "10.0.0.2");
+        assert!(refused.is_shut_down());
+        assert_eq!(refused.received(), vec![format!("{ERROR_TAG}Server is busy. Try again later.")]);
+        assert_eq!(server.waiting.len(), WAITING_LIMIT);
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(!constant_time_eq(b"abc", b""));
+
+        let token = generate_token().expect("token");
+        assert_eq!(token.len(), TOKEN_LEN);
+        let mut server = test_server(test_config("tokens_are_compared_whole"));
+        server.tokens.insert(token.clone(), TokenInfo { nick: None });
+        for shared in 0..=TOKEN_LEN {
+            let mut guess: Vec<u8> = token.bytes().collect();
+            if shared < TOKEN_LEN {
+                guess[shared] = if guess[shared] == b'0' { b'1' } else { b'0' };
+            } else {
+                guess.push(b'0');
+            }
+            let ip = Ipv4Addr::from(0x0b00_0000 + shared as u32).to_string();
+            let peer = connect(&mut server, &ip);
+            peer.send(&mut server, &String::from_utf8_lossy(&guess));
+            assert!(peer.is_shut_down(), "{shared} bytes in common");
+        }
+        let peer = connect(&mut server, "10.0.0.1");
+        peer.send(&mut server, &token);
+        assert!(peer.received().iter().any(|line| line == WELCOME));
+    }
+
+    #[test]
+    fn event_log_rotates() {
+        let file_path = env::temp_dir().join(format!("4at-event_log_rotates-{pid}.log", pid = std::process::id()));
+        let file_path = file_path.to_string_lossy().to_string();
+        let rotated = format!("{file_path}.1");
+        let _ = fs::remove_file(&rotated);
+        // NOTE: a sparse file just below the limit, so the test does not have to write 10 MB
+        fs::File::create(&file_path).and_then(|file| file.set_len(LOG_ROTATE_SIZE - 16)).expect("log file");
+
+        let events = EventLog::open(file_path.clone()).expect("event log");
+        events.write("AUTH 10.0.0.1:1234");
+        events.close();
+        assert_eq!(fs::metadata(&rotated).map(|metadata| metadata.len()).ok(), Some(LOG_ROTATE_SIZE - 16));
+        let content = fs::read_to_string(&file_path).expect("log file");
+        let (time, event) = content.strip_suffix('\n').and_then(|line| line.split_once(' ')).expect("event line");
+        assert_eq!(event, "AUTH 10.0.0.1:1234");
+        assert!(time.len() == "1970-01-01T00:00:00Z".len() && time.ends_with('Z'), "{time}");
+
+        let _ = fs::remove_file(&file_path);
+        let _ = fs::remove_file(&rotated);
+    }
+
+    #[test]
+    fn mapped_addresses_are_the_same_ip() {
+        let mut server = test_server(test_config("mapped_addresses_are_the_same_ip"));
+        let admin = connect(&mut server, "10.0.0.1");
+        admin.send(&mut server, ADMIN_TOKEN);
+        admin.send(&mut server, "/ban ::ffff:10.0.0.5");
+        assert!(server.sinners.contains_key(&"10.0.0.5".parse::<IpAddr>().expect("ip")));
+        let banned = connect(&mut server, "10.0.0.5");
+        assert!(banned.is_shut_down());
+
+        admin.send(&mut server, "/ban 10.0.0.6");
+        let banned = connect(&mut server, "::ffff:10.0.0.6");
+        assert!(banned.is_shut_down());
+        assert!(banned.received()[0].starts_with(&format!("{ERROR_TAG}You are banned MF")));
+    }
+
+    /// The name of the most recent strike of the IP
+    fn last_strike(server: &Server, peer: &Peer) -> Option<&'static str> {
+        match server.sinners.get(&peer.addr.ip()) {
+            Some(Sinner::Striked(_, strikes)) => strikes.back().map(|strike| strike.reason.name()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn strikes_have_reasons() {
+        let mut config = test_config("strikes_have_reasons");
+        config.slowloris_limit = Duration::ZERO;
+        let mut server = test_server(config);
+
+        let peer = connect(&mut server, "10.0.0.1");
+        peer.send(&mut server, "not-a-token");
+        assert_eq!(last_strike(&server, &peer), Some("auth-failure"));
+
+        let peer = join(&mut server, "10.0.0.2");
+        peer.write(&mut server, b"\xff\n");
+        assert_eq!(last_strike(&server, &peer), Some("invalid-utf8"));
+
+        let peer = join(&mut server, "10.0.0.3");
+        peer.send(&mut server, &"x".repeat(2*FRAME_LIMIT));
+        assert_eq!(last_strike(&server, &peer), Some("oversize"));
+
+        let peer = join(&mut server, "10.0.0.4");
+        for _ in 0..MESSAGE_BURST as usize + 2 {
+            peer.send(&mut server, "flood");
+        }
+        assert_eq!(last_strike(&server, &peer), Some("rate-limit"));
+
+        let peer = connect(&mut server, "10.0.0.5");
+        server.sweep();
+        assert!(peer.is_shut_down());
+        assert_eq!(last_strike(&server, &peer), Some("slowloris"));
+
+        let peers: Vec<Peer> = (0..=WAITING_PER_IP_LIMIT).map(|_| connect(&mut server, "10.0.0.6")).collect();
+        assert_eq!(last_strike(&server, &peers[WAITING_PER_IP_LIMIT]), Some("waiting-flood"));
+
+        let peer = (0..=CONNECTION_RATE_LIMIT).map(|_| {
+            let peer = connect(&mut server, "10.0.0.7");
+            peer.close(&mut server);
+            peer
+        }).last().expect("peer");
+        assert_eq!(last_strike(&server, &peer), Some("connect-flood"));
+    }
+
+    #[test]
+    fn ban_tells_the_reason() {
+        let mut config = test_config("ban_tells_the_reason");
+        config.strike_limit = 0;
+        let mut server = test_server(config);
+        let peer = join(&mut server, "10.0.0.1");
+        peer.write(&mut server, b"\xff\n");
+        assert_eq!(peer.received(), vec![format!("{DISCONNECT_TAG}disconnected: banned: sending invalid UTF-8")]);
+        let admin = connect(&mut server, "10.0.0.2");
+        admin.send(&mut server, ADMIN_TOKEN);
+        admin.received();
+        admin.send(&mut server, "/baninfo 10.0.0.1");
+        let lines = admin.received();
+        assert!(lines[0].starts_with("10.0.0.1 is banned for ") && lines[0].ends_with("reason: invalid-utf8"), "{lines:?}");
+    }
+
+    #[test]
+    fn ban_drops_every_connection_of_ip() {
+        let mut config = test_config("ban_drops_every_connection_of_ip");
+        config.strike_limit = 0;
+        let mut server = test_server(config);
+        let admin = connect(&mut server, "10.0.0.100");
+        admin.send(&mut server, ADMIN_TOKEN);
+
+        type Ban = fn(&mut Server, &Peer, &Peer);
+        let bans: [(&str, Ban); 3] = [
+            ("admin", |server, admin, waiting| admin.send(server, &format!("/ban {ip}", ip = waiting.addr.ip()))),
+            ("auth failure", |server, _, waiting| waiting.send(server, "not-a-token")),
+            ("slowloris", |server, _, _| {
+                server.config.slowloris_limit = Duration::ZERO;
+                server.sweep();
+            }),
+        ];
+        for (index, (path, ban)) in bans.into_iter().enumerate() {
+            let ip = format!("10.0.0.{n}", n = index + 1);
+            let first = join(&mut server, &ip);
+            let second = join(&mut server, &ip);
+            let waiting = connect(&mut server, &ip);
+            ban(&mut server, &admin, &waiting);
+            for peer in [&first, &second, &waiting] {
+                assert!(peer.is_shut_down(), "{path}");
+                assert!(!server.clients.contains_key(&peer.token) && !server.waiting.contains_key(&peer.token), "{path}");
+            }
+            assert!(matches!(server.sinners.get(&first.addr.ip()), Some(Sinner::Banned(..))), "{path}");
+            assert!(server.clients.contains_key(&admin.token), "{path}");
+        }
+    }
+
+    #[test]
+    fn proxied_clients_are_known_by_their_own_address() {
+        let mut config = test_config("proxied_clients_are_known_by_their_own_address");
+        config.proxy_protocol = true;
+        let mut server = test_server(config);
+        let proxy: SocketAddr = "192.0.2.1:40000".parse().expect("address");
+        let stream = MemoryStream::default();
+        server.client_connected(stream.clone(), proxy, Origin::Chat);
+        let token = *server.proxied.keys().next().expect("proxied");
+        let peer = Peer { token, addr: "203.0.113.7:56324".parse().expect("address"), stream };
+        peer.write(&mut server, format!("PROXY TCP4 203.0.113.7 192.0.2.1 56324 6969\r\n{TOKEN}\n").as_bytes());
+        // NOTE: the Poll reports the token left after the header once the Connection is registered
+        peer.write(&mut server, b"");
+        assert!(peer.received().iter().any(|line| line == WELCOME));
+        assert!(server.clients.get(&token).is_some_and(|client| client.conn.addr == peer.addr));
+        peer.write(&mut server, b"\xff\n");
+        assert!(server.sinners.contains_key(&peer.addr.ip()));
+        assert!(!server.sinners.contains_key(&proxy.ip()));
+
+        let stream = MemoryStream::default();
+        server.client_connected(stream.clone(), proxy, Origin::Chat);
+        let token = *server.proxied.keys().next().expect("proxied");
+        let peer = Peer { token, addr: proxy, stream };
+        peer.write(&mut server, format!("{TOKEN}\n").as_bytes());
+        assert!(server.proxied.is_empty() && server.waiting.is_empty());
+        assert_eq!(server.clients.len(), 1);
+    }
+
+    #[test]
+    fn rotated_token_keeps_clients_connected() {
+        let mut config = test_config("rotated_token_keeps_clients_connected");
+        let token_file = env::temp_dir().join(format!("4at-rotated_token_keeps_clients_connected-{pid}-TOKENS", pid = std::process::id()));
+        config.token_file = token_file.to_string_lossy().to_string();
+        fs::write(&token_file, format!("{TOKEN}\n")).expect("token file");
+        let mut server = test_server(config);
+        let alice = join(&mut server, "10.0.0.1");
+        let admin = connect(&mut server, "10.0.0.2");
+        admin.send(&mut server, ADMIN_TOKEN);
+        admin.received();
+        alice.received();
+
+        admin.send(&mut server, &format!("/rotate-token {TOKEN}"));
+        let lines = admin.received();
+        assert!(lines[0].starts_with("Rotated the token"), "{lines:?}");
+        assert!(!lines[0].contains(TOKEN));
+        let new_token = fs::read_to_string(&token_file).expect("token file").trim().to_string();
+        assert!(new_token != TOKEN && server.tokens.contains_key(&new_token));
+
+        alice.send(&mut server, "still here");
+        assert_eq!(alice.received(), vec![format!("{ECHO_TAG}<#1> still here")]);
+        server.reload_tokens();
+        assert!(!alice.is_shut_down());
+
+        // NOTE: the old token works during the grace period only
+        join(&mut server, "10.0.0.3");
+        for rotated in server.rotated.values_mut() {
+            rotated.until = Instant::now();
+        }
+        server.sweep();
+        let late = connect(&mut server, "10.0.0.4");
+        late.send(&mut server, TOKEN);
+        assert!(late.is_shut_down());
+        let bob = connect(&mut server, "10.0.0.5");
+        bob.send(&mut server, &new_token);
+        assert!(bob.received().iter().any(|line| line == WELCOME));
+
+        let _ = fs::remove_file(&token_file);
+    }
+
+    #[test]
+    fn blank_messages_are_dropped() {
+        let mut server = test_server(test_config("blank_messages_are_dropped"));
+        let author = join(&mut server, "10.0.0.1");
+        let other = join(&mut server, "10.0.0.2");
+        author.received();
+        for blank in [" ", "\t\t", "", " \t \r"] {
+            author.send(&mut server, blank);
+        }
+        assert_eq!(author.received(), Vec::<String>::new());
+        assert_eq!(other.received(), Vec::<String>::new());
+        assert!(matches!(server.sinners.get(&author.addr.ip()), Some(Sinner::Striked(0, _))));
+        // NOTE: the blank lines took their tokens from the bucket all the same
+        author.send(&mut server, "hi \t  ");
+        assert_eq!(other.received(), vec!["<#1> hi"]);
+        author.received();
+        author.send(&mut server, " ");
+        let lines = author.received();
+        assert!(lines.len() == 1 && lines[0].starts_with(&format!("{ERROR_TAG}rate-limited")), "{lines:?}");
+    }
+
+    #[test]
+    fn padded_token_is_accepted() {
+        let mut server = test_server(test_config("padded_token_is_accepted"));
+        for (index, frame) in [format!("{TOKEN}\n"), format!(" {TOKEN} \n"), format!("\t{TOKEN}\r\n")].iter().enumerate() {
+            let peer = connect(&mut server, &format!("10.0.0.{n}", n = index + 1));
+            peer.write(&mut server, frame.as_bytes());
+            assert!(peer.received().iter().any(|line| line == WELCOME), "{frame:?}");
+        }
+
+        let peer = connect(&mut server, "10.0.1.1");
+        peer.send(&mut server, &format!("{TOKEN}GARBAGE"));
+        assert!(peer.is_shut_down());
+        assert!(matches!(server.sinners.get(&peer.addr.ip()), Some(Sinner::Striked(1, _))));
+
+        // NOTE: the garbage is not buffered past the length of the token
+        let peer = connect(&mut server, "10.0.1.2");
+        peer.write(&mut server, format!("{TOKEN}{garbage}", garbage = "GARBAGE".repeat(1000)).as_bytes());
+        assert!(peer.is_shut_down());
+        assert!(server.waiting.is_empty());
+    }
+
+    #[test]
+    fn tls_session_goes_over_the_transport() {
+        let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+        let mut server = test_server(test_config("tls"));
+        server.tls = Some(load_tls(&format!("{data}/localhost.pem"), &format!("{data}/localhost.key")).expect("tls"));
+        let peer = connect(&mut server, "10.0.0.1");
+
+        // NOTE: the self-signed certificate is its own CA
+        let mut roots = RootCertStore::empty();
+        let cert = CertificateDer::from_pem_file(format!("{data}/localhost.pem")).expect("certificate");
+        roots.add(cert).expect("root");
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let server_name = "localhost".try_into().expect("server name");
+        let mut client = ClientConnection::new(Arc::new(config), server_name).expect("client");
+        // NOTE: the records go back and forth through the MemoryStream, the plain text comes out
+        // of the TLS session of the client
+        let exchange = |client: &mut ClientConnection, server: &mut Server| -> String {
+            let mut records = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut records).expect("client records");
+            }
+            peer.write(server, &records);
+            let outgoing = mem::take(&mut peer.stream.0.borrow_mut().outgoing);
+            assert!(!String::from_utf8_lossy(&outgoing).contains(WELCOME), "nothing goes out in plain text");
+            client.read_tls(&mut outgoing.as_slice()).expect("server records");
+            client.process_new_packets().expect("server records");
+            let mut text = Vec::new();
+            let _ = client.reader().read_to_end(&mut text);
+            String::from_utf8_lossy(&text).to_string()
+        };
+        for _ in 0..10 {
+            if !client.is_handshaking() {
+                break;
+            }
+            exchange(&mut client, &mut server);
+        }
+        assert!(!client.is_handshaking());
+
+        client.writer().write_all(format!("{TOKEN}\n").as_bytes()).expect("token");
+        let text = exchange(&mut client, &mut server);
+        assert!(text.split(FRAME_SEPARATOR as char).any(|line| line == WELCOME), "{text:?}");
+        assert!(server.clients.contains_key(&peer.token));
+    }
+}
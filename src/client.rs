@@ -1,15 +1,241 @@
 use std::io::{self, stdout, Read, Write, ErrorKind};
-use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, SetTitle};
 use crossterm::cursor::{MoveTo};
-use crossterm::style::{Print, SetBackgroundColor, SetForegroundColor, Color};
+use crossterm::style::{Print, SetAttribute, SetBackgroundColor, SetForegroundColor, Attribute, Color};
 use crossterm::{execute, QueueableCommand};
-use crossterm::event::{read, poll, Event, KeyCode, KeyModifiers, KeyEventKind};
-use std::time::Duration;
+use crossterm::event::{read, poll, Event, KeyEvent, KeyCode, KeyModifiers, KeyEventKind, MouseEventKind, MouseButton, EnableMouseCapture, DisableMouseCapture, EnableFocusChange, DisableFocusChange, EnableBracketedPaste, DisableBracketedPaste};
+use std::time::{Duration, Instant, SystemTime};
 use std::thread;
-use std::net::TcpStream;
-use std::str;
-use std::cmp;
+use std::net::{TcpStream, SocketAddr, IpAddr, ToSocketAddrs};
 use std::mem;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::env;
+use std::ops::Range;
+use std::path::Path;
+use std::process::{self, Stdio};
+use fourat::protocol::{FrameReader, write_frame, sanitize_lossy, Sanitized, ECHO_TAG, PING_TAG, PONG_TAG, DM_TAG, HISTORY_TAG, ERROR_TAG, DISCONNECT_TAG, DEFAULT_ROOM, WELCOME, DEFAULT_PORT, MESSAGE_RATE, FRAME_LIMIT, split_stamp, split_sequence, rfc3339};
+use fourat::moderation::BAN_LIMIT;
+use fourat::sens::{Sens, safe_mode_from_env, set_safe_mode};
+use fourat::tls::fingerprint;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, DigitallySignedStruct, SignatureScheme};
+use rustls::client::danger::{ServerCertVerifier, ServerCertVerified, HandshakeSignatureValid};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use signal_hook::consts::{SIGSTOP, SIGTSTP, SIGWINCH};
+#[cfg(unix)]
+use signal_hook_mio::v0_8::Signals;
+#[cfg(unix)]
+use mio::{Poll, Events, Interest, Token};
+#[cfg(unix)]
+use mio::unix::SourceFd;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::io::IsTerminal;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use unicode_width::UnicodeWidthChar;
+
+const ECHO_TIMEOUT: Duration = Duration::from_secs(3);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long each of the addresses of the Server is tried
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The pause before the first attempt to reconnect, which doubles with every failed one
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_LIMIT: Duration = Duration::from_secs(30);
+/// The beginnings of the lines the Server sends when it does not want the client back (see
+/// DisconnectReason::notice() and admit() of the Server), each with how the status bar puts it.
+/// Reconnecting after them would only earn more strikes
+const FINAL_NOTICES: &[(&str, &str)] = &[
+    ("disconnected: banned", "banned"),
+    ("disconnected: kicked", "kicked"),
+    ("disconnected: invalid token", "invalid token"),
+    ("disconnected: token was revoked", "token revoked"),
+    ("disconnected: IP is not allowed", "IP not allowed"),
+    ("You are banned", "banned"),
+];
+/// Where /connect takes the token from if it is not given, the file the Server puts the tokens in
+const TOKENS_FILE_PATH: &str = "./TOKENS";
+/// Relative to $XDG_CONFIG_HOME or ~/.config, see config_path()
+const CONFIG_FILE_PATH: &str = "4at/config";
+/// How many lines sent from the prompt can be recalled with Up
+const HISTORY_LIMIT: usize = 200;
+/// Most terminals keep a stack of the titles, so the title the terminal had before the Client is
+/// pushed upon the start and popped back upon the exit. The rest of them ignore these
+const TITLE_SAVE: &str = "\x1b[22;0t";
+const TITLE_RESTORE: &str = "\x1b[23;0t";
+/// The most bytes of one paste that get into the prompt, see sanitize_paste()
+const PASTE_LIMIT: usize = 10*1024;
+/// How many of the nicks seen lately Tab completes, see Client::saw_nick()
+const RECENT_NICKS_LIMIT: usize = 100;
+/// How many lines of the chat one notch of the mouse wheel scrolls
+const WHEEL_SCROLL: usize = 3;
+/// The second cell of a wide character. Nothing is printed for it, since the terminal fills it
+/// with the first half. The text never contains it, because the control characters are stripped
+const WIDE_TAIL: char = '\0';
+const STATUS_SEPARATOR: &str = " | ";
+/// How many lines the chat keeps by default, the older ones are dropped
+const SCROLLBACK_LIMIT: usize = 10_000;
+/// Overrides SCROLLBACK_LIMIT
+const SCROLLBACK_ENV: &str = "FOURAT_SCROLLBACK";
+/// Longest line accepted from the Server. Its lines carry the nick, the stamp and the tags on top
+/// of a message of up to FRAME_LIMIT, and a line of the MOTD may be as long as the whole MOTD
+const SERVER_FRAME_LIMIT: usize = 8*FRAME_LIMIT;
+/// How often the result of the connecting thread is checked. Same for the socket where the
+/// Waiter can't watch it together with the keys
+const SOCKET_POLL_INTERVAL: Duration = Duration::from_millis(16);
+/// How often the status bar is redrawn while it shows a countdown. Also the longest the main
+/// loop sleeps where the Waiter can't be used
+const COUNTDOWN_TICK: Duration = Duration::from_secs(1);
+/// How long "Really quit?" waits for the answer
+const QUIT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many steps back the prompt can undo
+const UNDO_LIMIT: usize = 100;
+/// Put in front of the query instead of the `-` of the prompt
+const SEARCH_LABEL: &str = "search:";
+/// How many times an alias may expand into another one, so the aliases that refer to each other
+/// end up with an error instead of hanging the Client
+const ALIAS_DEPTH_LIMIT: usize = 10;
+/// How many mentions /mentions prints by default
+const MENTIONS_SHOWN: usize = 10;
+/// The colors of the nicks of the others, see nick_color(). None of them is the color of the
+/// background, the errors, the notices, the own messages, the mentions or the links of the dark
+/// Theme
+const NICK_COLORS: &[Color] = &[
+    Color::AnsiValue(209),
+    Color::AnsiValue(215),
+    Color::AnsiValue(180),
+    Color::AnsiValue(114),
+    Color::AnsiValue(79),
+    Color::AnsiValue(75),
+    Color::AnsiValue(147),
+    Color::AnsiValue(176),
+    Color::AnsiValue(211),
+    Color::AnsiValue(187),
+];
+/// The darker NICK_COLORS that are readable on the white background. Same as there, none of them
+/// is taken by anything else of the light Theme
+const NICK_COLORS_LIGHT: &[Color] = &[
+    Color::AnsiValue(124),
+    Color::AnsiValue(130),
+    Color::AnsiValue(94),
+    Color::AnsiValue(64),
+    Color::AnsiValue(30),
+    Color::AnsiValue(24),
+    Color::AnsiValue(61),
+    Color::AnsiValue(91),
+    Color::AnsiValue(125),
+    Color::AnsiValue(58),
+];
+/// The first one is the default
+const THEMES: &[Theme] = &[
+    Theme {
+        name: "dark",
+        background: Color::Black,
+        foreground: Color::White,
+        status_bg: Color::White,
+        status_fg: Color::Black,
+        info: Color::Blue,
+        error: Color::Red,
+        own_message: Color::Green,
+        mention: Color::Yellow,
+        dim: Color::DarkGrey,
+        dm: Color::Magenta,
+        link: Color::Cyan,
+        nicks: NICK_COLORS,
+    },
+    // NOTE: the colors of the terminal itself, for the schemes where the ones above look wrong
+    Theme {
+        name: "terminal",
+        background: Color::Reset,
+        foreground: Color::Reset,
+        status_bg: Color::DarkGrey,
+        status_fg: Color::White,
+        info: Color::Cyan,
+        error: Color::Red,
+        own_message: Color::Green,
+        mention: Color::DarkYellow,
+        dim: Color::DarkGrey,
+        dm: Color::Magenta,
+        link: Color::Blue,
+        nicks: NICK_COLORS,
+    },
+    Theme {
+        name: "light",
+        background: Color::AnsiValue(231),
+        foreground: Color::AnsiValue(16),
+        status_bg: Color::AnsiValue(238),
+        status_fg: Color::AnsiValue(231),
+        info: Color::AnsiValue(25),
+        error: Color::AnsiValue(160),
+        own_message: Color::AnsiValue(28),
+        mention: Color::AnsiValue(172),
+        dim: Color::AnsiValue(245),
+        dm: Color::AnsiValue(90),
+        link: Color::AnsiValue(31),
+        nicks: NICK_COLORS_LIGHT,
+    },
+];
+/// The colors of the Theme that can be changed one by one in the config, see Theme::color_mut()
+const THEME_KEYS: &[&str] = &[
+    "theme.background",
+    "theme.foreground",
+    "theme.status_bg",
+    "theme.status_fg",
+    "theme.info",
+    "theme.error",
+    "theme.own_message",
+    "theme.mention",
+    "theme.dim",
+    "theme.dm",
+    "theme.link",
+];
+/// Besides these the colors are given as `0`-`255` of the 256 colors or as `#rrggbb`
+const COLOR_NAMES: &[(&str, Color)] = &[
+    ("default", Color::Reset),
+    ("black", Color::Black),
+    ("dark_grey", Color::DarkGrey),
+    ("red", Color::Red),
+    ("dark_red", Color::DarkRed),
+    ("green", Color::Green),
+    ("dark_green", Color::DarkGreen),
+    ("yellow", Color::Yellow),
+    ("dark_yellow", Color::DarkYellow),
+    ("blue", Color::Blue),
+    ("dark_blue", Color::DarkBlue),
+    ("magenta", Color::Magenta),
+    ("dark_magenta", Color::DarkMagenta),
+    ("cyan", Color::Cyan),
+    ("dark_cyan", Color::DarkCyan),
+    ("white", Color::White),
+    ("grey", Color::Grey),
+];
+/// The colors of SGR 30-37 and 90-97 in order, see sgr_color()
+const SGR_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::DarkRed,
+    Color::DarkGreen,
+    Color::DarkYellow,
+    Color::DarkBlue,
+    Color::DarkMagenta,
+    Color::DarkCyan,
+    Color::Grey,
+    Color::DarkGrey,
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::White,
+];
+/// How many of the last links /open remembers
+const LINKS_LIMIT: usize = 100;
+/// The longer links are cut in the chat, /open opens them in full
+const LINK_DISPLAY_LIMIT: usize = 60;
 
 struct Rect {
     x: usize, y: usize, w: usize, h: usize,
@@ -19,42 +245,233 @@ struct ScreenState;
 
 impl ScreenState {
     fn enable() -> io::Result<Self> {
-        execute!(stdout(), EnterAlternateScreen)?;
-        terminal::enable_raw_mode()?;
+        Self::enter()?;
         Ok(Self)
     }
-}
 
-impl Drop for ScreenState {
-    fn drop(&mut self) {
+    fn enter() -> io::Result<()> {
+        Self::save_title()?;
+        execute!(stdout(), EnterAlternateScreen, EnableMouseCapture, EnableFocusChange)?;
+        // NOTE: the legacy console of Windows can't, the pastes come as the keys there
+        let _ = execute!(stdout(), EnableBracketedPaste);
+        terminal::enable_raw_mode()
+    }
+
+    fn leave() {
         let _ = terminal::disable_raw_mode().map_err(|err| {
             eprintln!("ERROR: disable raw mode: {err}")
         });
-        let _ = execute!(stdout(), LeaveAlternateScreen).map_err(|err| {
+        let _ = execute!(stdout(), DisableBracketedPaste);
+        let _ = execute!(stdout(), DisableFocusChange, DisableMouseCapture, LeaveAlternateScreen).map_err(|err| {
             eprintln!("ERROR: leave alternate screen: {err}")
         });
+        let _ = Self::restore_title().map_err(|err| {
+            eprintln!("ERROR: restore the title: {err}")
+        });
+    }
+
+    fn save_title() -> io::Result<()> {
+        // NOTE: the legacy console of Windows would print it as it is
+        #[cfg(unix)]
+        execute!(stdout(), Print(TITLE_SAVE))?;
+        Ok(())
+    }
+
+    /// Puts back the title the terminal had before the Client, see TITLE_SAVE. The terminals
+    /// that can't are at least left without the title of the Client
+    fn restore_title() -> io::Result<()> {
+        execute!(stdout(), SetTitle(""))?;
+        #[cfg(unix)]
+        execute!(stdout(), Print(TITLE_RESTORE))?;
+        Ok(())
+    }
+
+    /// Gives the terminal back to the shell until `fg`. The connection stays, whatever the Server
+    /// sends meanwhile waits in the socket. The mouse is left to the terminal after /mouse off
+    #[cfg(unix)]
+    fn suspend(&self, mouse: bool) -> io::Result<()> {
+        Self::leave();
+        // NOTE: SIGSTOP and not SIGTSTP, since the latter is caught by the Client itself
+        signal_hook::low_level::raise(SIGSTOP)?;
+        Self::enter()?;
+        if !mouse {
+            execute!(stdout(), DisableMouseCapture)?;
+        }
+        Ok(())
     }
 }
 
-fn sanitize_terminal_output(bytes: &[u8]) -> Option<String> {
-    let bytes: Vec<u8> = bytes.iter().cloned().filter(|x| *x >= 32).collect();
-    if let Ok(result) = str::from_utf8(&bytes) {
-        Some(result.to_string())
-    } else {
-        None
+impl Drop for ScreenState {
+    fn drop(&mut self) {
+        Self::leave();
+    }
+}
+
+/// Sleeps until there is a key (or a resize) or something from the Server, whichever comes
+/// first. crossterm only watches the terminal, and polling the socket on top of it would keep the
+/// idle Client waking up all the time
+#[cfg(unix)]
+struct Waiter {
+    poll: Poll,
+    events: Events,
+    /// The terminal crossterm reads the keys from, unless it is the stdin
+    tty: Option<File>,
+    /// SIGWINCH for the resizes and SIGTSTP for the suspends, see ScreenState::suspend()
+    signals: Signals,
+}
+
+#[cfg(unix)]
+impl Waiter {
+    const TERMINAL: Token = Token(0);
+    const SIGNALS: Token = Token(1);
+    const SOCKET: Token = Token(2);
+
+    fn new() -> io::Result<Self> {
+        let poll = Poll::new()?;
+        // NOTE: the same terminal crossterm picks
+        let tty = if io::stdin().is_terminal() {
+            None
+        } else {
+            Some(File::open("/dev/tty")?)
+        };
+        let mut signals = Signals::new([SIGWINCH, SIGTSTP])?;
+        poll.registry().register(&mut signals, Self::SIGNALS, Interest::READABLE)?;
+        Ok(Self {
+            poll,
+            events: Events::with_capacity(8),
+            tty,
+            signals,
+        })
+    }
+
+    /// Registers the source anew or updates its registration. The latter reports the readiness
+    /// the source already has, so nothing that was left unread in it goes unnoticed
+    fn watch(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        match self.poll.registry().register(&mut SourceFd(&fd), token, interest) {
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => self.poll.registry().reregister(&mut SourceFd(&fd), token, interest),
+            result => result,
+        }
+    }
+
+    /// The socket is watched for the writes as well while its Stream wants to write
+    fn wait(&mut self, socket: Option<&Stream>, timeout: Option<Duration>) -> io::Result<()> {
+        let terminal = self.tty.as_ref().map(|tty| tty.as_raw_fd()).unwrap_or_else(|| io::stdin().as_raw_fd());
+        self.watch(terminal, Self::TERMINAL, Interest::READABLE)?;
+        // NOTE: the closed sockets are forgotten by the Poll on their own
+        if let Some(socket) = socket {
+            let interest = if socket.wants_write() {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            self.watch(socket.as_raw_fd(), Self::SOCKET, interest)?;
+        }
+        if let Err(err) = self.poll.poll(&mut self.events, timeout) {
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        // NOTE: the signals are handled by crossterm and by the `suspend` flag, they only wake us up
+        self.signals.pending().for_each(drop);
+        Ok(())
+    }
+}
+
+/// The segments on the left start at the left edge and the ones on the right end at the right
+/// edge. When the terminal is too narrow for all of them, the segments on the right go away one
+/// by one starting from the last, then the left ones are cut off with `…`
+fn status_bar(buffer: &mut Buffer, theme: &Theme, left: &[String], right: &[String], x: usize, y: usize, w: usize) {
+    for dx in 0..w {
+        buffer.put_cell(x + dx, y, ' ', theme.status_fg, theme.status_bg);
+    }
+    let mut left_chars: Vec<_> = left.join(STATUS_SEPARATOR).chars().collect();
+    if columns(&left_chars) > w && w > 0 {
+        left_chars = fit_columns(&left_chars, w - 1).to_vec();
+        left_chars.push('…');
+    }
+    let n = buffer.put_cells(x, y, fit_columns(&left_chars, w), theme.status_fg, theme.status_bg);
+    for k in (1..=right.len()).rev() {
+        let right_chars: Vec<_> = right[..k].join(STATUS_SEPARATOR).chars().collect();
+        let m = columns(&right_chars);
+        // NOTE: at least one column between the sides, so they don't look like one segment
+        if n + 1 + m <= w {
+            buffer.put_cells(x + w - m, y, &right_chars, theme.status_fg, theme.status_bg);
+            break;
+        }
+    }
+}
+
+/// How many columns of the terminal the characters take
+fn columns(chs: &[char]) -> usize {
+    chs.iter().map(|x| x.width().unwrap_or(0)).sum()
+}
+
+/// The rows of the scrollbar of the `height` rows tall view taken by the thumb, when the view shows
+/// the `total` lines starting from the `top` one. The thumb is at least a row tall, and it touches
+/// the ends of the bar only at the very top and the very bottom. None if everything fits
+fn scrollbar_thumb(total: usize, height: usize, top: usize) -> Option<Range<usize>> {
+    if total <= height || height == 0 {
+        return None;
+    }
+    let size = ((height*height + total/2)/total).clamp(1, height);
+    let room = height - size;
+    let scrollable = total - height;
+    let top = top.min(scrollable);
+    let mut start = (top*room + scrollable/2)/scrollable;
+    // NOTE: even a line away from either end shows on the bar
+    if room >= 2 && 0 < top && top < scrollable {
+        start = start.clamp(1, room - 1);
+    }
+    Some(start..start + size)
+}
+
+/// The longest beginning of the characters that fits into `w` columns
+fn fit_columns(chs: &[char], w: usize) -> &[char] {
+    let mut total = 0;
+    for (i, x) in chs.iter().enumerate() {
+        total += x.width().unwrap_or(0);
+        if total > w {
+            return &chs[..i];
+        }
+    }
+    chs
+}
+
+/// The nick the line of the Server is about: the author of `<nick> text`, the sender of
+/// `[DM from nick] text` and the newcomer of `* nick joined`
+fn speaker(message: &str) -> Option<&str> {
+    if let Some(rest) = message.strip_prefix('<') {
+        return rest.split_once("> ").map(|(nick, _)| nick);
     }
+    if let Some(rest) = message.strip_prefix("[DM from ") {
+        return rest.split_once("] ").map(|(nick, _)| nick);
+    }
+    message.strip_prefix("* ")?.split_once(" joined").map(|(nick, _)| nick)
 }
 
-fn status_bar(buffer: &mut Buffer, label: &str, x: usize, y: usize, w: usize) {
-    let label_chars: Vec<_> = label.chars().collect();
-    let n = cmp::min(label_chars.len(), w);
-    buffer.put_cells(x, y, &label_chars[..n], Color::Black, Color::White);
-    for x in label.len()..w {
-        buffer.put_cell(x, y, ' ', Color::Black, Color::White);
+/// The prompt is a single line, so the line breaks of the pasted text become spaces and the rest
+/// of the control characters are dropped. At most PASTE_LIMIT bytes are taken, the second value
+/// tells whether the text was cut
+fn sanitize_paste(text: &str) -> (String, bool) {
+    let mut pasted = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let ch = match ch {
+            // NOTE: `\r\n` is one line break
+            '\r' if chars.peek() == Some(&'\n') => continue,
+            '\r' | '\n' | '\t' => ' ',
+            ch if ch.is_control() => continue,
+            ch => ch,
+        };
+        if pasted.len() + ch.len_utf8() > PASTE_LIMIT {
+            return (pasted, true);
+        }
+        pasted.push(ch);
     }
+    (pasted, false)
 }
 
-fn parse_command<'a>(prompt: &'a [char]) -> Option<(&'a [char], &'a [char])> {
+fn parse_command(prompt: &[char]) -> Option<(&[char], &[char])> {
     let prompt = prompt.strip_prefix(&['/'])?;
     let mut iter = prompt.splitn(2, |x| *x == ' ');
     let a = iter.next().unwrap_or(prompt);
@@ -62,33 +479,263 @@ fn parse_command<'a>(prompt: &'a [char]) -> Option<(&'a [char], &'a [char])> {
     Some((a, b))
 }
 
-#[derive(Default)]
-struct ChatLog {
-    items: Vec<(String, Color)>,
+/// Cycling through the variants of the word before the cursor with Tab and Shift+Tab. The
+/// names of the commands are completed, as well as the argument of /help, and the nicks in the
+/// messages and in /msg
+struct Completion {
+    /// The prompt before the word
+    head: String,
+    /// The word as it was typed
+    prefix: String,
+    /// The prompt after the cursor, which stays intact
+    rest: String,
+    matches: Vec<String>,
+    /// The variant in the prompt. The prefix itself comes after all the matches, so the cycle
+    /// gets back to it
+    index: usize,
+}
+
+impl Completion {
+    /// None if there is nothing to complete. The `prefix` of an alias of a command completes to
+    /// the full name of the command, the user `aliases` are completed as they are. The words of
+    /// the messages and the argument of /msg complete to the `nicks`, ignoring the case
+    fn start(before: &[char], after: &[char], aliases: &HashMap<String, String>, nicks: &[String]) -> Option<Self> {
+        let before: String = before.iter().collect();
+        let complete_command = |prefix: &str| -> Vec<String> {
+            let mut matches: Vec<String> = COMMANDS.iter()
+                .filter(|command| command.name.starts_with(prefix) || command.aliases.iter().any(|alias| alias.starts_with(prefix)))
+                .map(|command| command.name.to_string())
+                .collect();
+            let mut user: Vec<_> = aliases.keys().filter(|name| name.starts_with(prefix)).cloned().collect();
+            user.sort();
+            matches.extend(user);
+            matches
+        };
+        let complete_nick = |prefix: &str, suffix: &str| -> Vec<String> {
+            let prefix = prefix.to_lowercase();
+            nicks.iter()
+                .filter(|nick| nick.to_lowercase().starts_with(&prefix))
+                .map(|nick| format!("{nick}{suffix}"))
+                .collect()
+        };
+        let (prefix, matches) = if let Some(command) = before.strip_prefix('/') {
+            match command.split_once(' ') {
+                None => (command, complete_command(command)),
+                Some((name, argument)) if !argument.contains(' ') => match find_command(name).map(|command| command.name) {
+                    Some("help") => (argument, complete_command(argument)),
+                    Some("msg") => (argument, complete_nick(argument, "")),
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        } else {
+            let prefix = before.rsplit(' ').next().unwrap_or(&before);
+            if prefix.is_empty() {
+                return None;
+            }
+            // NOTE: the message starting with the nick is addressed to that user
+            let suffix = if prefix.len() == before.len() { ": " } else { "" };
+            (prefix, complete_nick(prefix, suffix))
+        };
+        if matches.is_empty() {
+            return None;
+        }
+        Some(Self {
+            head: before[..before.len() - prefix.len()].to_string(),
+            prefix: prefix.to_string(),
+            rest: after.iter().collect(),
+            index: matches.len(),
+            matches,
+        })
+    }
+
+    /// Returns the new prompt and where the cursor goes in it
+    fn cycle(&mut self, forward: bool) -> (String, usize) {
+        let n = self.matches.len() + 1;
+        self.index = if forward {
+            (self.index + 1)%n
+        } else {
+            (self.index + n - 1)%n
+        };
+        let word = self.matches.get(self.index).unwrap_or(&self.prefix);
+        let cursor = self.head.chars().count() + word.chars().count();
+        (format!("{head}{word}{rest}", head = self.head, rest = self.rest), cursor)
+    }
+}
+
+/// The placeholders of the arguments left to type after the command in the prompt, like
+/// `<nick> <text>` right after `/msg `. Only while the cursor is at the end after a space
+fn argument_hint(before: &[char], after: &[char]) -> Option<String> {
+    if !after.is_empty() || before.last() != Some(&' ') {
+        return None;
+    }
+    let before: String = before.iter().collect();
+    let (name, argument) = before.strip_prefix('/')?.split_once(' ')?;
+    // NOTE: the quoted arguments with spaces in them are counted wrong, which only shifts the hint
+    find_command(name)?.hint(argument.split_whitespace().count())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SpanKind {
+    /// Rendered in the color of the line
+    Text,
+    Link,
+    /// The number of the link for /open
+    Number,
+    /// The `<nick>` of the author, see nick_color()
+    Nick(u64),
+    /// The color the Server asked for with `/set ansi on`, see sgr_color()
+    Colored(Color),
+}
+
+struct Span {
+    text: String,
+    kind: SpanKind,
+}
+
+struct ChatItem {
+    /// When the Server sent the line, see split_stamp()
+    time: Option<String>,
+    text: String,
+    /// The text split into the parts rendered differently
+    spans: Vec<Span>,
+    style: Style,
+}
+
+/// What the line of the chat is, the Theme decides the color of it. So the lines already in the
+/// chat change the color along with the Theme
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Style {
+    Message,
+    Own,
+    /// What the Client tells the user
+    Info,
+    /// What the Server tells everyone, like who joins and leaves
+    System,
+    Error,
+    Dm,
+    Mention,
+    /// The own messages waiting to be sent
+    Pending,
+    /// The lines of the room from before the Client came
+    History,
+}
+
+/// Which lines the chat shows, see /filter. The rest are only hidden, not dropped
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum Filter {
+    #[default]
+    All,
+    Errors,
+    Info,
+}
+
+impl Filter {
+    const NAMES: &'static [(&'static str, Filter)] = &[("all", Filter::All), ("errors", Filter::Errors), ("info", Filter::Info)];
+
+    fn shows(self, style: Style) -> bool {
+        match self {
+            Self::All => true,
+            Self::Errors => style == Style::Error,
+            Self::Info => matches!(style, Style::Info | Style::System),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, filter)| *filter == self).map_or("all", |(name, _)| name)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-struct Cell {
-    ch: char,
-    fg: Color,
-    bg: Color,
+struct Theme {
+    /// The one from THEMES the colors come from, the config may change some of them
+    name: &'static str,
+    background: Color,
+    foreground: Color,
+    status_bg: Color,
+    status_fg: Color,
+    info: Color,
+    error: Color,
+    own_message: Color,
+    mention: Color,
+    dim: Color,
+    dm: Color,
+    link: Color,
+    nicks: &'static [Color],
+}
+
+/// The links seen in the chat, numbered in the order of appearance for /open
+#[derive(Default)]
+struct Links {
+    list: VecDeque<(usize, String)>,
+    seen: usize,
+}
+
+struct ChatLog {
+    items: VecDeque<ChatItem>,
+    /// Most lines kept, see SCROLLBACK_LIMIT
+    capacity: usize,
+    /// Whether the user was told that the oldest lines are dropped
+    overflowed: bool,
+    /// How many lines were dropped from the beginning. The lines are referred to by their
+    /// number since the start (see ChatLog::next_index()), which stays valid after that
+    dropped: usize,
+    /// Whether the times of the lines are rendered, toggled by /timestamps
+    timestamps: bool,
+    filter: Filter,
+    /// How many of the shown lines the view is scrolled up from the bottom
+    scroll: usize,
+    /// How many lines arrived while the view was scrolled up
+    unseen: usize,
+    /// Height of the chat window at the last render, which is how much a page is
+    height: usize,
+    /// Whether any line was added or changed since the last render
+    changed: bool,
+    /// The nick of the user in lower case, see ChatLog::mentions()
+    nick: Option<String>,
+    /// Numbers of the lines mentioning the nick, the same as in ChatLog::next_index()
+    mentions: Vec<usize>,
+    links: Links,
+    /// The file every new line is appended to and its path, see /log
+    log: Option<(String, File)>,
 }
 
-impl Default for Cell {
+impl Default for ChatLog {
     fn default() -> Self {
         Self {
-            ch: ' ',
-            fg: Color::White,
-            bg: Color::Black,
+            items: VecDeque::new(),
+            capacity: SCROLLBACK_LIMIT,
+            overflowed: false,
+            dropped: 0,
+            timestamps: true,
+            filter: Filter::All,
+            scroll: 0,
+            unseen: 0,
+            height: 0,
+            changed: false,
+            nick: None,
+            mentions: Vec::new(),
+            links: Links::default(),
+            log: None,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+struct Cell {
+    ch: char,
+    fg: Color,
+    bg: Color,
+    underline: bool,
+}
+
 #[derive(Debug, Clone)]
 struct Buffer {
     cells: Vec<Cell>,
     width: usize,
     height: usize,
+    /// The empty cell in the colors of the Theme, see Theme::blank()
+    blank: Cell,
 }
 
 struct Patch {
@@ -98,14 +745,14 @@ struct Patch {
 }
 
 impl Buffer {
-    fn new(width: usize, height: usize) -> Self {
-        let cells = vec![Cell::default(); width*height];
-        Self { cells, width, height }
+    fn new(width: usize, height: usize, blank: Cell) -> Self {
+        let cells = vec![blank.clone(); width*height];
+        Self { cells, width, height, blank }
     }
 
     fn resize(&mut self, width: usize, height: usize) {
-        self.cells.resize(width*height, Cell::default());
-        self.cells.fill(Cell::default());
+        self.cells.resize(width*height, self.blank.clone());
+        self.cells.fill(self.blank.clone());
         self.width = width;
         self.height = height;
     }
@@ -126,34 +773,74 @@ impl Buffer {
     }
 
     fn clear(&mut self) {
-        self.cells.fill(Cell::default());
+        self.cells.fill(self.blank.clone());
     }
 
     fn put_cell(&mut self, x: usize, y: usize, ch: char, fg: Color, bg: Color) {
-        if let Some(cell) = self.cells.get_mut(y*self.width + x) {
-            *cell = Cell { ch, fg, bg }
+        debug_assert!(x < self.width, "x = {x} is outside of the buffer of width {width}", width = self.width);
+        if x >= self.width {
+            return;
+        }
+        let index = y*self.width + x;
+        let old = if let Some(cell) = self.cells.get_mut(index) {
+            mem::replace(cell, Cell { ch, fg, bg, underline: false }).ch
+        } else {
+            return
+        };
+        // NOTE: overwriting a half of a wide character leaves the other half on its own, which
+        // the terminal would not agree with
+        if old == WIDE_TAIL && ch != WIDE_TAIL && x > 0 {
+            self.cells[index - 1].ch = ' ';
+        }
+        if old.width() == Some(2) && x + 1 < self.width && self.cells[index + 1].ch == WIDE_TAIL {
+            self.cells[index + 1].ch = ' ';
         }
     }
 
-    fn put_cells(&mut self, x: usize, y: usize, chs: &[char], fg: Color, bg: Color) {
-        let start = y*self.width + x;
-        for (offset, &ch) in chs.iter().enumerate() {
-            if let Some(cell) = self.cells.get_mut(start + offset) {
-                *cell = Cell { ch, fg, bg };
-            } else {
+    /// The wide characters take two cells and the zero-width ones are dropped. The characters
+    /// that do not fit into the row are cut off instead of spilling onto the next row. Returns
+    /// how many cells were taken
+    fn put_cells(&mut self, x: usize, y: usize, chs: &[char], fg: Color, bg: Color) -> usize {
+        let mut column = x;
+        for &ch in chs {
+            let width = ch.width().unwrap_or(0);
+            if width == 0 {
+                continue;
+            }
+            if column + width > self.width {
                 break;
             }
+            self.put_cell(column, y, ch, fg, bg);
+            if width == 2 {
+                self.put_cell(column + 1, y, WIDE_TAIL, fg, bg);
+            }
+            column += width;
+        }
+        column - x
+    }
+
+    /// Underlines the `n` cells already put into the row
+    fn underline(&mut self, x: usize, y: usize, n: usize) {
+        let start = (y*self.width + x).min(self.cells.len());
+        let end = (start + n).min((y + 1)*self.width).min(self.cells.len());
+        for cell in &mut self.cells[start..end] {
+            cell.underline = true;
         }
     }
 
     fn flush(&self, qc: &mut impl Write) -> io::Result<()> {
-        let mut fg_curr = Color::White;
-        let mut bg_curr = Color::Black;
+        let mut fg_curr = self.blank.fg;
+        let mut bg_curr = self.blank.bg;
+        let mut underline_curr = false;
         qc.queue(Clear(ClearType::All))?;
         qc.queue(SetForegroundColor(fg_curr))?;
         qc.queue(SetBackgroundColor(bg_curr))?;
+        qc.queue(SetAttribute(Attribute::NoUnderline))?;
         qc.queue(MoveTo(0, 0))?;
-        for Cell{ch, fg, bg} in self.cells.iter() {
+        for Cell{ch, fg, bg, underline} in self.cells.iter() {
+            if *ch == WIDE_TAIL {
+                continue;
+            }
             if fg_curr != *fg {
                 fg_curr = *fg;
                 qc.queue(SetForegroundColor(fg_curr))?;
@@ -162,6 +849,10 @@ impl Buffer {
                 bg_curr = *bg;
                 qc.queue(SetBackgroundColor(bg_curr))?;
             }
+            if underline_curr != *underline {
+                underline_curr = *underline;
+                qc.queue(SetAttribute(if underline_curr { Attribute::Underlined } else { Attribute::NoUnderline }))?;
+            }
             qc.queue(Print(ch))?;
         }
         qc.flush()?;
@@ -169,271 +860,2876 @@ impl Buffer {
     }
 }
 
-impl ChatLog {
-    fn push(&mut self, message: String, color: Color) {
-        self.items.push((message, color))
+impl ChatItem {
+    /// The lines from the Server come stamped with the time, which is kept aside, so it can be
+    /// rendered differently or not at all. The links are numbered along the way
+    fn new(message: String, style: Style, links: &mut Links) -> Self {
+        Self::with_colors(message, style, &[], links)
     }
 
-    fn render(&mut self, buffer: &mut Buffer, boundary: Rect) {
-        let n = self.items.len();
-        let m = n.checked_sub(boundary.h).unwrap_or(0);
-        for (dy, (line, color)) in self.items.iter().skip(m).enumerate() {
-            let line_chars: Vec<_> = line.chars().collect();
-            buffer.put_cells(
-                boundary.x, boundary.y + dy,
-                line_chars.get(0..boundary.w).unwrap_or(&line_chars),
-                *color, Color::Black);
+    /// The parts of the text between the `sgr` sequences go in their colors, except for the links
+    fn with_colors(message: String, style: Style, sgr: &[(usize, String)], links: &mut Links) -> Self {
+        let (time, text) = match split_stamp(&message) {
+            Some((time, text)) => (Some(time.to_string()), text.to_string()),
+            None => (None, message.clone()),
+        };
+        // NOTE: the sequences are found in the whole message, the stamp included
+        let shift = message.len() - text.len();
+        let mut colors = Vec::new();
+        let mut color = None;
+        for (at, params) in sgr {
+            color = sgr_color(params, color);
+            colors.push((at.saturating_sub(shift), color));
+        }
+        let push_text = |spans: &mut Vec<Span>, range: Range<usize>| {
+            let mut start = range.start;
+            let mut color = colors.iter().take_while(|(at, _)| *at <= start).last().and_then(|(_, color)| *color);
+            for &(at, next) in colors.iter().filter(|(at, _)| range.contains(at) && *at > range.start) {
+                spans.push(Span{text: text[start..at].to_string(), kind: color.map_or(SpanKind::Text, SpanKind::Colored)});
+                start = at;
+                color = next;
+            }
+            spans.push(Span{text: text[start..range.end].to_string(), kind: color.map_or(SpanKind::Text, SpanKind::Colored)});
+        };
+        let mut spans = Vec::new();
+        let mut end = 0;
+        for range in find_links(&text) {
+            let link = &text[range.clone()];
+            push_text(&mut spans, end..range.start);
+            let shown = if link.chars().count() > LINK_DISPLAY_LIMIT {
+                link.chars().take(LINK_DISPLAY_LIMIT - 1).chain(Some('…')).collect()
+            } else {
+                link.to_string()
+            };
+            spans.push(Span{text: shown, kind: SpanKind::Link});
+            spans.push(Span{text: format!(" [{number}]", number = links.add(link)), kind: SpanKind::Number});
+            end = range.end;
         }
+        push_text(&mut spans, end..text.len());
+        Self { time, text, spans, style }
     }
-}
 
-macro_rules! chat_msg {
-    ($chat:expr, $($arg:tt)*) => {
-        $chat.push(format!($($arg)*), Color::White)
+    /// The line as it is written to the files, without the colors
+    fn line(&self) -> String {
+        match &self.time {
+            Some(time) => format!("[{time}] {text}", text = self.text),
+            None => self.text.clone(),
+        }
     }
-}
 
-macro_rules! chat_error {
-    ($chat:expr, $($arg:tt)*) => {
-        $chat.push(format!($($arg)*), Color::Red)
+    /// Renders the `<nick>` the message starts with in the color of the nick
+    fn color_nick(&mut self) {
+        if let Some(first) = self.spans.first_mut().filter(|span| span.kind == SpanKind::Text) {
+            if let (Some(end), true) = (first.text.find("> "), first.text.starts_with('<')) {
+                let rest = first.text.split_off(end + 1);
+                first.kind = SpanKind::Nick(nick_color(&first.text[1..end]));
+                self.spans.insert(1, Span{text: rest, kind: SpanKind::Text});
+            }
+        }
     }
 }
 
-macro_rules! chat_info {
-    ($chat:expr, $($arg:tt)*) => {
-        $chat.push(format!($($arg)*), Color::Blue)
+/// Applies the SGR parameters like `1;31` to the `color` of the text. Only the color of the text
+/// is taken, the background and the rest of the attributes are ignored. None is the color of the
+/// line
+fn sgr_color(params: &str, mut color: Option<Color>) -> Option<Color> {
+    let mut params = params.split(';').map(|param| param.parse::<u8>().unwrap_or(0));
+    while let Some(param) = params.next() {
+        match param {
+            0 | 39 => color = None,
+            30..=37 => color = Some(SGR_COLORS[(param - 30) as usize]),
+            90..=97 => color = Some(SGR_COLORS[(param - 90 + 8) as usize]),
+            38 | 48 => {
+                let extended = match params.next() {
+                    Some(5) => params.next().map(Color::AnsiValue),
+                    Some(2) => match (params.next(), params.next(), params.next()) {
+                        (Some(r), Some(g), Some(b)) => Some(Color::Rgb { r, g, b }),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if param == 38 && extended.is_some() {
+                    color = extended;
+                }
+            }
+            _ => {}
+        }
     }
+    color
 }
 
-#[derive(Default)]
-struct Prompt {
-    buffer: Vec<char>,
-    cursor: usize,
-    scroll: usize,
+/// The same nick gets the same color every time, on any machine. Returns the hash of the nick,
+/// Theme::nick() picks the color by it
+fn nick_color(nick: &str) -> u64 {
+    // NOTE: FNV-1a, since the hashers of std are not promised to stay the same between the releases
+    nick.bytes().fold(0xcbf29ce484222325u64, |hash, x| (hash ^ x as u64).wrapping_mul(0x100000001b3))
 }
 
-impl Prompt {
-    fn sync_scroll_with_cursor(&mut self, w: usize) {
-        if self.cursor < self.scroll {
-            self.scroll = self.cursor;
-        }
-        if self.scroll + w <= self.cursor {
-            self.scroll = self.cursor - w;
-        }
+impl Default for Theme {
+    fn default() -> Self {
+        THEMES[0].clone()
     }
+}
 
-    fn sync_terminal_cursor(&mut self, qc: &mut impl Write, x: usize, y: usize, w: usize) -> io::Result<()> {
-        if let Some(w) = w.checked_sub(2) {
-            let x = x + 1;
-            self.sync_scroll_with_cursor(w);
-            let offset = self.cursor - self.scroll; // NOTE: self.scroll <= self.cursor must be guaranteed by self.sync_scroll_with_cursor()
-            let _ = qc.queue(MoveTo((x + offset) as u16, y as u16))?;
-        }
-        Ok(())
+impl Theme {
+    fn find(name: &str) -> Option<&'static Theme> {
+        THEMES.iter().find(|theme| theme.name == name)
     }
 
-    fn render(&mut self, buffer: &mut Buffer, x: usize, y: usize, w: usize) {
-        if let Some(w) = w.checked_sub(2) {
-            let x = x + 1;
-            self.sync_scroll_with_cursor(w);
-            let begin = self.scroll;
-            let end = cmp::min(self.scroll + w, self.buffer.len());
-            if let Some(window) = self.buffer.get(begin..end) {
-                buffer.put_cells(x, y, window, Color::White, Color::Black);
-                if self.scroll > 0 {
-                    buffer.put_cell(x - 1, y, '<', Color::White, Color::Black);
-                }
-                if self.scroll + w < self.buffer.len() {
-                    buffer.put_cell(x + w, y, '>', Color::White, Color::Black);
-                }
-            }
+    fn color(&self, style: Style) -> Color {
+        match style {
+            Style::Message => self.foreground,
+            Style::Own => self.own_message,
+            Style::Info | Style::System => self.info,
+            Style::Error => self.error,
+            Style::Dm => self.dm,
+            Style::Mention => self.mention,
+            Style::Pending | Style::History => self.dim,
         }
     }
 
-    fn insert(&mut self, x: char) {
-        if self.cursor > self.buffer.len() {
-            self.cursor = self.buffer.len()
-        }
-        self.buffer.insert(self.cursor, x);
-        self.cursor += 1;
+    fn nick(&self, hash: u64) -> Color {
+        self.nicks[(hash % self.nicks.len() as u64) as usize]
     }
 
-    fn insert_str(&mut self, text: &str) {
-        for x in text.chars() {
-            self.insert(x)
+    /// What the empty space of the terminal is filled with
+    fn blank(&self) -> Cell {
+        Cell { ch: ' ', fg: self.foreground, bg: self.background, underline: false }
+    }
+
+    /// See THEME_KEYS
+    fn color_mut(&mut self, key: &str) -> Option<&mut Color> {
+        match key {
+            "theme.background" => Some(&mut self.background),
+            "theme.foreground" => Some(&mut self.foreground),
+            "theme.status_bg" => Some(&mut self.status_bg),
+            "theme.status_fg" => Some(&mut self.status_fg),
+            "theme.info" => Some(&mut self.info),
+            "theme.error" => Some(&mut self.error),
+            "theme.own_message" => Some(&mut self.own_message),
+            "theme.mention" => Some(&mut self.mention),
+            "theme.dim" => Some(&mut self.dim),
+            "theme.dm" => Some(&mut self.dm),
+            "theme.link" => Some(&mut self.link),
+            _ => None,
         }
     }
+}
 
-    fn left_char(&mut self) {
-        if self.cursor > 0 {
-            self.cursor -= 1;
+/// See COLOR_NAMES
+fn parse_color(value: &str) -> Result<Color, String> {
+    if let Some((_, color)) = COLOR_NAMES.iter().find(|(name, _)| *name == value) {
+        return Ok(*color);
+    }
+    if let Ok(n) = value.parse::<u8>() {
+        return Ok(Color::AnsiValue(n));
+    }
+    if let Some(hex) = value.strip_prefix('#').filter(|hex| hex.len() == 6 && hex.is_ascii()) {
+        if let Ok(rgb) = u32::from_str_radix(hex, 16) {
+            return Ok(Color::Rgb { r: (rgb >> 16) as u8, g: (rgb >> 8) as u8, b: rgb as u8 });
         }
     }
+    Err(format!("invalid color {value:?}: must be a name like dark_grey, 0-255 or #rrggbb"))
+}
 
-    fn right_char(&mut self) {
-        if self.cursor < self.buffer.len() {
-            self.cursor += 1;
+/// The opposite of parse_color()
+fn color_name(color: Color) -> String {
+    match color {
+        Color::AnsiValue(n) => n.to_string(),
+        Color::Rgb { r, g, b } => format!("#{r:02x}{g:02x}{b:02x}"),
+        _ => COLOR_NAMES.iter().find(|(_, known)| *known == color).map(|(name, _)| name.to_string()).unwrap_or_default(),
+    }
+}
+
+impl Links {
+    /// Returns the number of the link. The same link keeps its number, but becomes the latest
+    fn add(&mut self, link: &str) -> usize {
+        let number = if let Some(i) = self.list.iter().position(|(_, known)| known == link) {
+            self.list.remove(i).map(|(number, _)| number).unwrap_or_default()
+        } else {
+            self.seen += 1;
+            self.seen
+        };
+        if self.list.len() >= LINKS_LIMIT {
+            self.list.pop_front();
         }
+        self.list.push_back((number, link.to_string()));
+        number
     }
 
-    fn at_cursor(&self) -> char {
-        self.buffer.get(self.cursor).cloned().unwrap_or('\n')
+    /// The link with the number, or the latest one
+    fn get(&self, number: Option<usize>) -> Option<&str> {
+        match number {
+            Some(number) => self.list.iter().find(|(known, _)| *known == number),
+            None => self.list.back(),
+        }.map(|(_, link)| link.as_str())
     }
+}
 
-    fn left_word(&mut self) {
-        while self.cursor > 0 && self.at_cursor().is_whitespace() {
-            self.cursor -= 1;
+/// Finds the `http://` and `https://` links in the text. A link goes until the whitespace, except
+/// for the punctuation at the end, which most likely belongs to the sentence around it
+fn find_links(text: &str) -> Vec<Range<usize>> {
+    let mut links = Vec::new();
+    let mut start = 0;
+    while let Some(i) = text[start..].find("http").map(|i| start + i) {
+        let rest = &text[i..];
+        let scheme = if let Some(scheme) = ["https://", "http://"].into_iter().find(|scheme| rest.starts_with(scheme)) {
+            scheme
+        } else {
+            start = i + "http".len();
+            continue;
+        };
+        // NOTE: like the `xhttp://` in the middle of a word
+        if text[..i].chars().next_back().is_some_and(char::is_alphanumeric) {
+            start = i + scheme.len();
+            continue;
         }
-        while self.cursor > 0 && !self.at_cursor().is_whitespace() {
-            self.cursor -= 1;
+        let mut end = i + rest.find(char::is_whitespace).unwrap_or(rest.len());
+        while let Some(ch) = text[..end].chars().next_back() {
+            let unbalanced = ch == ')' && text[i..end].matches('(').count() < text[i..end].matches(')').count();
+            if ".,;:!?'\"".contains(ch) || unbalanced {
+                end -= ch.len_utf8();
+            } else {
+                break;
+            }
+        }
+        if end > i + scheme.len() {
+            links.push(i..end);
         }
+        start = end.max(i + scheme.len());
     }
+    links
+}
 
-    fn right_word(&mut self) {
-        while self.cursor < self.buffer.len() && self.at_cursor().is_whitespace() {
-            self.cursor += 1;
-        }
-        while self.cursor < self.buffer.len() && !self.at_cursor().is_whitespace() {
-            self.cursor += 1;
+impl ChatLog {
+    fn push(&mut self, message: String, style: Style) {
+        let item = ChatItem::new(message, style, &mut self.links);
+        self.push_line(item);
+    }
+
+    fn push_line(&mut self, item: ChatItem) {
+        // NOTE: the first time the limit is reached the user is told about it with one more line,
+        // which has to fit as well
+        if self.len() + 1 >= self.capacity() && !self.overflowed {
+            self.overflowed = true;
+            let notice = format!("scrollback limit of {capacity} lines reached, oldest messages dropped", capacity = self.capacity());
+            let item = ChatItem::new(notice, Style::Info, &mut self.links);
+            self.push_item(item);
         }
+        self.push_item(item);
     }
 
-    fn backspace(&mut self) {
-        if self.cursor > 0 {
-            self.cursor -= 1;
-            self.buffer.remove(self.cursor);
+    /// Pushes the message of the others turning its `sgr` sequences into the colors (see `/set ansi`)
+    /// and highlighting it if it mentions the nick of the user. Returns whether it does
+    fn push_message(&mut self, message: String, sgr: &[(usize, String)]) -> bool {
+        let text = split_stamp(&message).map(|(_, text)| text).unwrap_or(&message);
+        // NOTE: the Server sends the messages as `<nick> text`, and the nick of the author does not count
+        let mention = self.mentions(text.split_once("> ").map(|(_, text)| text).unwrap_or(text));
+        let mut item = ChatItem::with_colors(message, if mention { Style::Mention } else { Style::Message }, sgr, &mut self.links);
+        item.color_nick();
+        self.push_line(item);
+        if mention {
+            self.mentions.push(self.next_index() - 1);
         }
+        mention
     }
 
-    fn before_cursor(&self) -> &[char] {
-        &self.buffer[..self.cursor]
+    fn set_nick(&mut self, nick: Option<&str>) {
+        self.nick = nick.map(str::to_lowercase);
     }
 
-    fn after_cursor(&self) -> &[char] {
-        &self.buffer[self.cursor..]
+    /// Whether the text has the nick as a whole word, in any case
+    fn mentions(&self, text: &str) -> bool {
+        let nick = if let Some(nick) = self.nick.as_deref().filter(|nick| !nick.is_empty()) {
+            nick
+        } else {
+            return false;
+        };
+        let is_word = |ch: char| ch.is_alphanumeric() || ch == '_';
+        let text = text.to_lowercase();
+        text.match_indices(nick).any(|(i, _)| {
+            let before = text[..i].chars().next_back();
+            let after = text[i + nick.len()..].chars().next();
+            !before.is_some_and(is_word) && !after.is_some_and(is_word)
+        })
     }
 
-    fn clear(&mut self) {
-        self.buffer.clear();
-        self.cursor = 0;
+    /// The last `n` lines mentioning the nick that are still in the chat
+    fn last_mentions(&self, n: usize) -> Vec<&ChatItem> {
+        let start = self.mentions.len().saturating_sub(n);
+        self.mentions[start..].iter().filter_map(|index| self.items.get(index.checked_sub(self.dropped)?)).collect()
     }
 
-    fn delete_until_end(&mut self) {
-        while self.cursor < self.buffer.len() {
-            self.buffer.pop();
+    /// Scrolls so the latest mention above the bottom of the view becomes the bottom line, so
+    /// pressing it again goes further back. Starts from the latest one again when there is
+    /// nothing further back. Returns whether there was anything to jump to
+    fn jump_to_mention(&mut self) -> bool {
+        // NOTE: none of the filters shows the mentions
+        if self.filter != Filter::All {
+            self.set_filter(Filter::All);
+        }
+        let bottom = self.next_index().saturating_sub(1 + self.scroll);
+        let target = self.mentions.iter().rev().find(|index| **index < bottom).or(self.mentions.last());
+        if let Some(&index) = target {
+            self.scroll = 0;
+            self.scroll_up(self.next_index() - 1 - index);
+            true
+        } else {
+            false
         }
     }
-}
 
-#[derive(Default)]
-struct Client {
-    stream: Option<TcpStream>,
-    chat: ChatLog,
-    quit: bool,
-}
+    fn push_item(&mut self, item: ChatItem) {
+        while self.items.len() >= self.capacity {
+            self.items.pop_front();
+            self.dropped += 1;
+        }
+        let dropped = self.dropped;
+        self.mentions.retain(|index| *index >= dropped);
+        let line = item.line();
+        let style = item.style;
+        self.items.push_back(item);
+        self.changed = true;
+        // NOTE: the scroll is counted from the bottom, so the view has to move along with it
+        // to stay where the user left it. It can't go past the oldest line though
+        if self.scroll > 0 && self.filter.shows(style) {
+            self.scroll = (self.scroll + 1).min(self.shown(0).saturating_sub(self.height));
+            self.unseen += 1;
+        }
+        self.write_log(&line);
+    }
+
+    /// Appends the line to the log if there is one. The logging stops on the first error, so the
+    /// user is not flooded with the same error for every line
+    fn write_log(&mut self, line: &str) {
+        if let Some((path, file)) = &mut self.log {
+            if let Err(err) = writeln!(file, "{line}") {
+                let path = path.clone();
+                self.log = None;
+                self.push(format!("Could not write to {path}: {err}. Logging stopped"), Style::Error);
+            }
+        }
+    }
+
+    /// Writes all the lines to the file. Returns how many
+    fn save(&self, path: &str, overwrite: bool) -> io::Result<usize> {
+        let mut file = if overwrite {
+            File::create(path)?
+        } else {
+            OpenOptions::new().write(true).create_new(true).open(path)?
+        };
+        let mut text = String::new();
+        for item in &self.items {
+            text.push_str(&item.line());
+            text.push('\n');
+        }
+        file.write_all(text.as_bytes())?;
+        Ok(self.items.len())
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many of the lines starting from the `i`th one in the `items` the filter shows
+    fn shown(&self, i: usize) -> usize {
+        match self.filter {
+            Filter::All => self.items.len().saturating_sub(i),
+            filter => self.items.range(i.min(self.items.len())..).filter(|item| filter.shows(item.style)).count(),
+        }
+    }
+
+    /// The view stays at the bottom, since the old scroll counted the lines of the other filter
+    fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+        self.scroll = 0;
+        self.unseen = 0;
+        self.changed = true;
+    }
+
+    /// The number of the line pushed next
+    fn next_index(&self) -> usize {
+        self.dropped + self.items.len()
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut ChatItem> {
+        self.changed = true;
+        self.items.get_mut(index.checked_sub(self.dropped)?)
+    }
+
+    /// Replaces the line in place, like the local copy of a message once the Server echoes it
+    fn set(&mut self, index: usize, message: String, style: Style) {
+        let new = ChatItem::new(message, style, &mut self.links);
+        // NOTE: the log can't be rewritten, so the new version of the line is appended to it
+        let line = new.line();
+        if let Some(item) = self.get_mut(index) {
+            *item = new;
+            self.write_log(&line);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.truncate_to(0);
+    }
+
+    /// Keeps only the last `n` lines
+    fn truncate_to(&mut self, n: usize) {
+        let excess = self.items.len().saturating_sub(n);
+        self.items.drain(..excess);
+        self.dropped += excess;
+        let dropped = self.dropped;
+        self.mentions.retain(|index| *index >= dropped);
+        self.scroll = 0;
+        self.unseen = 0;
+        self.changed = true;
+    }
+
+    fn scroll_up(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.shown(0).saturating_sub(self.height));
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+        if self.scroll == 0 {
+            self.unseen = 0;
+        }
+    }
+
+    fn scroll_to_bottom(&mut self) {
+        self.scroll_down(self.scroll);
+    }
+
+    /// Scrolls so the line is at the top of the view, as far as there are lines below it
+    fn show_line(&mut self, index: usize) {
+        let i = index.saturating_sub(self.dropped);
+        if !self.items.get(i).is_some_and(|item| self.filter.shows(item.style)) {
+            self.filter = Filter::All;
+        }
+        self.scroll = 0;
+        self.scroll_up(self.shown(i).saturating_sub(self.height));
+    }
+
+    /// Numbers of the lines that have the query, ignoring the case
+    fn find(&self, query: &[char]) -> Vec<usize> {
+        self.items.iter().enumerate().filter(|(_, item)| {
+            let chs: Vec<_> = item.text.chars().collect();
+            !find_ignore_case(&chs, query).is_empty()
+        }).map(|(i, _)| self.dropped + i).collect()
+    }
+
+    /// Takes only the visible lines, however many there are in total. The `highlight` is marked
+    /// wherever it is found in the lines, see Search
+    fn render(&mut self, buffer: &mut Buffer, boundary: Rect, highlight: &[char], theme: &Theme) {
+        let filter = self.filter;
+        let shown: Vec<&ChatItem> = self.items.iter().filter(|item| filter.shows(item.style)).collect();
+        let n = shown.len();
+        // NOTE: counting from the bottom keeps the last visible line in place when the terminal
+        // is resized, only the top of the view moves
+        self.height = boundary.h;
+        self.changed = false;
+        self.scroll = self.scroll.min(n.saturating_sub(boundary.h));
+        let m = n.saturating_sub(boundary.h + self.scroll);
+        // NOTE: the bar takes the last column, but only when there is anything to scroll
+        let mut boundary = boundary;
+        if let (Some(thumb), Some(w)) = (scrollbar_thumb(n, boundary.h, m), boundary.w.checked_sub(1)) {
+            boundary.w = w;
+            for dy in 0..boundary.h {
+                let ch = if thumb.contains(&dy) { '█' } else { '│' };
+                buffer.put_cell(boundary.x + w, boundary.y + dy, ch, theme.dim, theme.background);
+            }
+        }
+        for (dy, item) in shown[m..n.min(m + boundary.h)].iter().enumerate() {
+            let mut x = boundary.x;
+            let mut w = boundary.w;
+            if let (true, Some(time)) = (self.timestamps, &item.time) {
+                let time_chars: Vec<_> = format!("{time} ").chars().collect();
+                let n = buffer.put_cells(x, boundary.y + dy, fit_columns(&time_chars, w), theme.dim, theme.background);
+                x += n;
+                w -= n;
+            }
+            for span in &item.spans {
+                let fg = match span.kind {
+                    SpanKind::Text => theme.color(item.style),
+                    SpanKind::Link => theme.link,
+                    SpanKind::Number => theme.dim,
+                    SpanKind::Nick(hash) => theme.nick(hash),
+                    SpanKind::Colored(color) => color,
+                };
+                let span_chars: Vec<_> = span.text.chars().collect();
+                // NOTE: the span is put in pieces, either highlighted or not
+                let mut marks = vec![false; span_chars.len()];
+                for i in find_ignore_case(&span_chars, highlight) {
+                    marks[i..i + highlight.len()].fill(true);
+                }
+                let mut i = 0;
+                while i < span_chars.len() {
+                    let j = marks[i..].iter().position(|mark| *mark != marks[i]).map_or(span_chars.len(), |k| i + k);
+                    let (fg, bg) = if marks[i] { (theme.background, theme.mention) } else { (fg, theme.background) };
+                    let n = buffer.put_cells(x, boundary.y + dy, fit_columns(&span_chars[i..j], w), fg, bg);
+                    if span.kind == SpanKind::Link {
+                        buffer.underline(x, boundary.y + dy, n);
+                    }
+                    x += n;
+                    w -= n;
+                    i = j;
+                }
+            }
+        }
+    }
+}
+
+/// The messages of the user, see Client::send_message()
+macro_rules! chat_own {
+    ($chat:expr, $($arg:tt)*) => {
+        $chat.push(format!($($arg)*), Style::Own)
+    }
+}
+
+macro_rules! chat_error {
+    ($chat:expr, $($arg:tt)*) => {
+        $chat.push(format!($($arg)*), Style::Error)
+    }
+}
+
+macro_rules! chat_dm {
+    ($chat:expr, $($arg:tt)*) => {
+        $chat.push(format!($($arg)*), Style::Dm)
+    }
+}
+
+macro_rules! chat_info {
+    ($chat:expr, $($arg:tt)*) => {
+        $chat.push(format!($($arg)*), Style::Info)
+    }
+}
+
+/// The kinds of changes of the prompt that are undone together when they follow each other,
+/// see Prompt::checkpoint()
+#[derive(Clone, Copy, PartialEq)]
+enum Edit {
+    /// Typing a word
+    Insert,
+    /// Backspace and Delete
+    Delete,
+    /// Recalling the history and cycling through the completions
+    Replace,
+    /// Undone one by one
+    Other,
+}
+
+#[derive(Default)]
+struct Prompt {
+    buffer: Vec<char>,
+    cursor: usize,
+    scroll: usize,
+    /// The text removed by the last kills, see Prompt::kill()
+    kill_buffer: Vec<char>,
+    /// The text, the cursor and the direction right after the last kill. The next kill is
+    /// appended to the kill buffer only if nothing changed since then
+    last_kill: Option<(Vec<char>, usize, bool)>,
+    /// The text and the cursor before the changes, see Prompt::checkpoint()
+    undo: Vec<(Vec<char>, usize)>,
+    redo: Vec<(Vec<char>, usize)>,
+    /// The last change and where it left the cursor
+    last_edit: Option<(Edit, usize)>,
+}
+
+impl Prompt {
+    /// Where the token of `/connect <address> <token>` is, so it is not shown on the screen or
+    /// remembered in the history. Same for `/alias <name> connect <address> <token>`, which add_alias()
+    /// refuses for the same reason. The token read from `@file` is no secret
+    fn secret(&self) -> Option<Range<usize>> {
+        let line: String = self.buffer.iter().collect();
+        let secret = connect_token(line.strip_prefix('/')?)?;
+        // NOTE: the token is found among the bytes of the line, while the prompt counts characters
+        let chars = |at: usize| line[..at + 1].chars().count();
+        Some(chars(secret.start)..chars(secret.end))
+    }
+
+    /// The text as it is rendered, with an asterisk for each character of the token. The
+    /// characters stay where they are, so the cursor and the scroll mean the same in both
+    fn shown(&self) -> Vec<char> {
+        let mut shown = self.buffer.clone();
+        if let Some(secret) = self.secret() {
+            shown[secret].fill('*');
+        }
+        shown
+    }
+
+    /// The scroll is in characters, while `w` is in columns, which is not the same for the
+    /// wide characters
+    fn sync_scroll_with_cursor(&mut self, w: usize) {
+        let shown = self.shown();
+        // NOTE: the deletions may leave the scroll past the end of the text
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        }
+        // NOTE: the text may get shorter after the deletions, so there would be nothing to the
+        // right of `>` without it
+        while self.scroll > 0 && columns(&shown[self.scroll - 1..]) <= w {
+            self.scroll -= 1;
+        }
+        while columns(&shown[self.scroll..self.cursor]) > w {
+            self.scroll += 1;
+        }
+    }
+
+    fn sync_terminal_cursor(&mut self, qc: &mut impl Write, x: usize, y: usize, w: usize) -> io::Result<()> {
+        if let Some(w) = w.checked_sub(2) {
+            let x = x + 1;
+            self.sync_scroll_with_cursor(w);
+            let offset = columns(&self.shown()[self.scroll..self.cursor]); // NOTE: self.scroll <= self.cursor must be guaranteed by self.sync_scroll_with_cursor()
+            let _ = qc.queue(MoveTo((x + offset) as u16, y as u16))?;
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, buffer: &mut Buffer, x: usize, y: usize, w: usize, theme: &Theme) {
+        if let Some(w) = w.checked_sub(2) {
+            let x = x + 1;
+            self.sync_scroll_with_cursor(w);
+            if let Some(window) = self.shown().get(self.scroll..) {
+                let visible = fit_columns(window, w);
+                buffer.put_cells(x, y, visible, theme.foreground, theme.background);
+                if self.scroll > 0 {
+                    buffer.put_cell(x - 1, y, '<', theme.foreground, theme.background);
+                }
+                if visible.len() < window.len() {
+                    buffer.put_cell(x + w, y, '>', theme.foreground, theme.background);
+                }
+            }
+        }
+    }
+
+    /// Puts the cursor at the `column` of the terminal, `x` and `w` being the same as in render()
+    fn click(&mut self, x: usize, w: usize, column: usize) {
+        if w < 2 {
+            return;
+        }
+        let mut offset = if let Some(offset) = column.checked_sub(x + 1) {
+            offset
+        } else {
+            return
+        };
+        self.cursor = self.scroll;
+        let shown = self.shown();
+        while let Some(ch) = shown.get(self.cursor) {
+            let width = ch.width().unwrap_or(0);
+            // NOTE: the click on the right half of a wide character puts the cursor after it
+            if offset < width.div_ceil(2) {
+                break;
+            }
+            offset = offset.saturating_sub(width);
+            self.cursor += 1;
+        }
+    }
+
+    /// Remembers the state before the change, unless the change goes on with the previous one,
+    /// like typing the same word or pressing Backspace again. A new word starts a new step
+    fn checkpoint(&mut self, edit: Edit, new_word: bool) {
+        let goes_on = match self.last_edit {
+            Some((Edit::Replace, _)) => edit == Edit::Replace,
+            Some((last, cursor)) => last == edit && edit != Edit::Other && cursor == self.cursor && !new_word,
+            None => false,
+        };
+        if !goes_on {
+            if self.undo.len() >= UNDO_LIMIT {
+                self.undo.remove(0);
+            }
+            self.undo.push((self.buffer.clone(), self.cursor));
+        }
+        self.redo.clear();
+    }
+
+    fn undo(&mut self) {
+        if let Some((buffer, cursor)) = self.undo.pop() {
+            self.redo.push((mem::replace(&mut self.buffer, buffer), self.cursor));
+            self.cursor = cursor;
+            self.last_edit = None;
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some((buffer, cursor)) = self.redo.pop() {
+            self.undo.push((mem::replace(&mut self.buffer, buffer), self.cursor));
+            self.cursor = cursor;
+            self.last_edit = None;
+        }
+    }
+
+    fn insert(&mut self, x: char) {
+        if self.cursor > self.buffer.len() {
+            self.cursor = self.buffer.len()
+        }
+        let new_word = !x.is_whitespace() && self.cursor > 0 && self.buffer[self.cursor - 1].is_whitespace();
+        self.checkpoint(Edit::Insert, new_word);
+        self.buffer.insert(self.cursor, x);
+        self.cursor += 1;
+        self.last_edit = Some((Edit::Insert, self.cursor));
+    }
+
+    /// Inserts the pasted text as one step
+    fn insert_str(&mut self, text: &str) {
+        let cursor = self.cursor.min(self.buffer.len());
+        self.checkpoint(Edit::Other, false);
+        let before = self.buffer.len();
+        self.buffer.splice(cursor..cursor, text.chars());
+        self.cursor = cursor + self.buffer.len() - before;
+        self.last_edit = Some((Edit::Other, self.cursor));
+    }
+
+    fn left_char(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+        }
+    }
+
+    fn right_char(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.cursor += 1;
+        }
+    }
+
+    fn at_cursor(&self) -> char {
+        self.buffer.get(self.cursor).cloned().unwrap_or('\n')
+    }
+
+    fn left_word(&mut self) {
+        while self.cursor > 0 && self.at_cursor().is_whitespace() {
+            self.cursor -= 1;
+        }
+        while self.cursor > 0 && !self.at_cursor().is_whitespace() {
+            self.cursor -= 1;
+        }
+    }
+
+    fn right_word(&mut self) {
+        while self.cursor < self.buffer.len() && self.at_cursor().is_whitespace() {
+            self.cursor += 1;
+        }
+        while self.cursor < self.buffer.len() && !self.at_cursor().is_whitespace() {
+            self.cursor += 1;
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.checkpoint(Edit::Delete, false);
+            self.cursor -= 1;
+            self.buffer.remove(self.cursor);
+            self.last_edit = Some((Edit::Delete, self.cursor));
+        }
+    }
+
+    fn delete_char(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.checkpoint(Edit::Delete, false);
+            self.buffer.remove(self.cursor);
+            self.last_edit = Some((Edit::Delete, self.cursor));
+        }
+    }
+
+    /// Removes the text into the kill buffer like readline does. The kills in a row in the same
+    /// direction are collected together, so Ctrl+Y brings all of them back
+    fn kill(&mut self, range: Range<usize>, backward: bool) {
+        // NOTE: Ctrl+K at the end of the line must not lose what was killed before
+        if range.is_empty() {
+            return;
+        }
+        let in_row = self.last_kill.as_ref().is_some_and(|(buffer, cursor, last_backward)| {
+            *buffer == self.buffer && *cursor == self.cursor && *last_backward == backward
+        });
+        self.checkpoint(Edit::Other, false);
+        let killed: Vec<char> = self.buffer.drain(range.clone()).collect();
+        self.cursor = range.start;
+        self.last_edit = Some((Edit::Other, self.cursor));
+        if !in_row {
+            self.kill_buffer.clear();
+        }
+        if backward {
+            self.kill_buffer.splice(0..0, killed);
+        } else {
+            self.kill_buffer.extend(killed);
+        }
+        self.last_kill = Some((self.buffer.clone(), self.cursor, backward));
+    }
+
+    /// Inserts the text of the last kills at the cursor
+    fn yank(&mut self) {
+        let killed: String = self.kill_buffer.iter().collect();
+        self.insert_str(&killed);
+    }
+
+    fn delete_word_backward(&mut self) {
+        let mut start = self.cursor;
+        while start > 0 && self.buffer[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        while start > 0 && !self.buffer[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        self.kill(start..self.cursor, true);
+    }
+
+    fn delete_word_forward(&mut self) {
+        let mut end = self.cursor;
+        while end < self.buffer.len() && self.buffer[end].is_whitespace() {
+            end += 1;
+        }
+        while end < self.buffer.len() && !self.buffer[end].is_whitespace() {
+            end += 1;
+        }
+        self.kill(self.cursor..end, false);
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.buffer.len();
+    }
+
+    fn before_cursor(&self) -> &[char] {
+        &self.buffer[..self.cursor]
+    }
+
+    fn after_cursor(&self) -> &[char] {
+        &self.buffer[self.cursor..]
+    }
+
+    /// The cleared text can be brought back with undo, like the line that was just sent
+    fn clear(&mut self) {
+        if !self.buffer.is_empty() {
+            self.checkpoint(Edit::Other, false);
+            self.last_edit = Some((Edit::Other, 0));
+        }
+        self.buffer.clear();
+        self.cursor = 0;
+    }
+
+    fn delete_until_end(&mut self) {
+        self.kill(self.cursor.min(self.buffer.len())..self.buffer.len(), false);
+    }
+
+    fn delete_until_start(&mut self) {
+        self.kill(0..self.cursor, true);
+    }
+
+    /// Replaces the text putting the cursor at the end of it
+    fn set(&mut self, text: &str) {
+        self.checkpoint(Edit::Replace, false);
+        self.buffer = text.chars().collect();
+        self.cursor = self.buffer.len();
+        self.last_edit = Some((Edit::Replace, self.cursor));
+    }
+}
+
+/// Lines sent from the prompt (including the commands), so they can be recalled with Up and Down
+#[derive(Default)]
+struct History {
+    entries: Vec<String>,
+    /// The entry that is in the prompt, None if it is the text being typed
+    current: Option<usize>,
+    /// The text being typed when the recalling started
+    draft: String,
+}
+
+impl History {
+    fn push(&mut self, line: String) {
+        self.current = None;
+        self.draft.clear();
+        if line.trim().is_empty() || self.entries.last() == Some(&line) {
+            return;
+        }
+        if self.entries.len() >= HISTORY_LIMIT {
+            self.entries.remove(0);
+        }
+        self.entries.push(line);
+    }
+
+    /// Returns the previous entry. The prompt gets a copy of it, so the entry stays intact
+    /// whatever is done to the copy
+    fn up(&mut self, prompt: &[char]) -> Option<&str> {
+        let index = match self.current {
+            None => {
+                self.draft = prompt.iter().collect();
+                self.entries.len().checked_sub(1)?
+            }
+            Some(index) => index.checked_sub(1)?,
+        };
+        self.current = Some(index);
+        self.entries.get(index).map(|entry| entry.as_str())
+    }
+
+    /// Returns the next entry, or the text that was being typed after the newest one
+    fn down(&mut self) -> Option<&str> {
+        let index = self.current?;
+        if index + 1 < self.entries.len() {
+            self.current = Some(index + 1);
+            self.entries.get(index + 1).map(|entry| entry.as_str())
+        } else {
+            self.current = None;
+            Some(&self.draft)
+        }
+    }
+}
+
+/// Where the query starts in the characters, ignoring the case
+fn find_ignore_case(chs: &[char], query: &[char]) -> Vec<usize> {
+    let mut found = Vec::new();
+    if query.is_empty() {
+        return found;
+    }
+    let mut i = 0;
+    while i + query.len() <= chs.len() {
+        if chs[i..i + query.len()].iter().zip(query).all(|(a, b)| a.to_lowercase().eq(b.to_lowercase())) {
+            found.push(i);
+            i += query.len();
+        } else {
+            i += 1;
+        }
+    }
+    found
+}
+
+/// Searching the chat as the query is typed. The query is edited like the prompt
+struct Search {
+    query: Prompt,
+    /// Numbers of the matching lines, the same as in ChatLog::next_index()
+    matches: Vec<usize>,
+    /// Which of the matches the chat is scrolled to
+    current: Option<usize>,
+    /// The scroll of the chat before the search, it is restored upon Esc
+    scroll: usize,
+}
+
+impl Search {
+    fn new(chat: &ChatLog) -> Self {
+        Self {
+            query: Prompt::default(),
+            matches: Vec::new(),
+            current: None,
+            scroll: chat.scroll,
+        }
+    }
+
+    /// Finds the lines again after the query changed, starting from the latest one
+    fn update(&mut self, chat: &mut ChatLog) {
+        self.matches = chat.find(&self.query.buffer);
+        self.current = self.matches.len().checked_sub(1);
+        self.show(chat);
+    }
+
+    /// Goes to the next match down the chat or up if not `forward`, around the ends
+    fn step(&mut self, chat: &mut ChatLog, forward: bool) {
+        let n = self.matches.len();
+        if let Some(current) = self.current {
+            self.current = Some(if forward { (current + 1)%n } else { (current + n - 1)%n });
+            self.show(chat);
+        }
+    }
+
+    fn show(&self, chat: &mut ChatLog) {
+        match self.current {
+            Some(current) => chat.show_line(self.matches[current]),
+            None => chat.scroll = self.scroll,
+        }
+    }
+
+    /// Returns whether the search goes on
+    fn key(&mut self, event: KeyEvent, chat: &mut ChatLog) -> bool {
+        match event.code {
+            KeyCode::Esc => {
+                chat.scroll = self.scroll;
+                return false;
+            }
+            KeyCode::Char(x) => if event.modifiers.contains(KeyModifiers::CONTROL) {
+                match x {
+                    'c' => {
+                        chat.scroll = self.scroll;
+                        return false;
+                    }
+                    'r' => self.step(chat, false),
+                    _ => {}
+                }
+            } else {
+                self.query.insert(x);
+                self.update(chat);
+            }
+            KeyCode::Backspace => {
+                self.query.backspace();
+                self.update(chat);
+            }
+            KeyCode::Enter => self.step(chat, true),
+            KeyCode::Left => self.query.left_char(),
+            KeyCode::Right => self.query.right_char(),
+            KeyCode::Home => self.query.home(),
+            KeyCode::End => self.query.end(),
+            _ => {}
+        }
+        true
+    }
+
+    fn status(&self) -> String {
+        match self.current {
+            Some(current) => format!("match {k}/{n}", k = current + 1, n = self.matches.len()),
+            None if self.query.buffer.is_empty() => "Search: Enter for the next match, Ctrl+R for the previous one, Esc to go back".to_string(),
+            None => "no matches".to_string(),
+        }
+    }
+}
+
+/// What /connect was given, remembered for reconnecting
+#[derive(Clone)]
+struct Target {
+    address: String,
+    token: String,
+    pinned: Option<String>,
+}
+
+/// The connection being established on a separate thread, so the UI does not freeze while the
+/// Server does not answer
+struct Connecting {
+    target: Target,
+    started: Instant,
+    /// Whether it is an attempt to get back after the connection was lost
+    reconnecting: bool,
+    result: Receiver<io::Result<Stream>>,
+}
+
+/// Where the Client is with the Server, see Client::status()
+#[derive(Default)]
+enum Connection {
+    #[default]
+    Offline,
+    Connecting(Connecting),
+    /// The token is sent and nothing else is until the Server answers with WELCOME, so the
+    /// messages typed meanwhile wait in the outbox
+    Authenticating {
+        stream: Stream,
+        /// Whether it is an attempt to get back after the connection was lost
+        reconnecting: bool,
+    },
+    Online(Stream),
+    /// Waiting for the next attempt to reconnect, which is due at the Instant
+    Backoff(Instant),
+    /// The Server said it does not want the Client back, see FINAL_NOTICES
+    Refused(&'static str),
+}
+
+impl Connection {
+    /// The stream of the connection that is established, authenticated or not
+    fn stream(&mut self) -> Option<&mut Stream> {
+        match self {
+            Self::Authenticating{stream, ..} | Self::Online(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// The stream of the connection the Server accepted the token of
+    fn online(&mut self) -> Option<&mut Stream> {
+        match self {
+            Self::Online(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    fn is_established(&self) -> bool {
+        matches!(self, Self::Authenticating{..} | Self::Online(_))
+    }
+}
+
+/// Changed with /set and read from the config file, see Client::set()
+struct Settings {
+    /// Where /connect without the arguments connects to
+    server: Option<String>,
+    /// Where the token for the `server` is read from
+    token_file: String,
+    /// Taken right after connecting
+    nick: Option<String>,
+    /// Connect to the `server` upon the start
+    autoconnect: bool,
+    /// Ring the terminal bell upon the notifications
+    bell: bool,
+    /// Show the number of the unread notifications in the title of the terminal
+    title: bool,
+    /// Notify only about the messages mentioning the nick, not about everything that arrives
+    /// while the user is not looking
+    highlight_only: bool,
+    theme: Theme,
+    /// Show the messages in the colors the Server put into them, instead of stripping them along
+    /// with the rest of the escape sequences
+    ansi: bool,
+    /// The commands defined with /alias, the name without the slash to the line it expands to,
+    /// see Client::expand_alias()
+    aliases: HashMap<String, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            server: None,
+            token_file: TOKENS_FILE_PATH.to_string(),
+            nick: None,
+            autoconnect: false,
+            bell: true,
+            title: true,
+            highlight_only: false,
+            theme: Theme::default(),
+            ansi: false,
+            aliases: HashMap::new(),
+        }
+    }
+}
+
+/// Where the keys go
+#[derive(Default)]
+enum Mode {
+    #[default]
+    Prompt,
+    /// Waiting for `y` since the moment, see Client::request_quit()
+    ConfirmQuit(Instant),
+    /// Ctrl+F
+    Search(Search),
+}
+
+#[derive(Default)]
+struct Client {
+    connection: Connection,
+    /// The last Server the Client successfully connected to
+    target: Option<Target>,
+    /// When the ban of the Client on the `target` Server ends, see ban_left()
+    banned_until: Option<Instant>,
+    /// Failed attempts to reconnect in a row. Reset once the Server welcomes the Client, since
+    /// the Server may accept the connection just to close it right away
+    retries: u32,
+    frames: FrameReader,
+    /// The Server already sent something that is not valid UTF-8 over this connection, see
+    /// Client::decode()
+    invalid_utf8: bool,
+    /// Messages sent to the Server that were not echoed back yet together with the index of
+    /// their local copy in the chat
+    pending: VecDeque<(String, Instant, usize)>,
+    /// Messages typed while offline together with the index of their line in the chat. They
+    /// are sent once the Client is connected
+    outbox: VecDeque<(String, usize)>,
+    /// When the last message from the outbox was sent
+    outbox_sent_at: Option<Instant>,
+    nick: Option<String>,
+    /// The nicks of the others the Client saw lately, the latest first. Tab completes them
+    recent_nicks: Vec<String>,
+    /// How many lines of the answer to /list are still to come, see Client::collect_nicks()
+    listing: usize,
+    room: Option<String>,
+    /// Number of the last line received from the room, see SEQUENCE_TAG
+    sequence: Option<u64>,
+    /// Set by /away until the Server echoes the next message back
+    away: bool,
+    /// The nonce of the last /ping and when it was sent. Any other pong is ignored
+    ping: Option<(u64, Instant)>,
+    pings_sent: u64,
+    chat: ChatLog,
+    mode: Mode,
+    settings: Settings,
+    /// Messages that came while the user was not looking or that mention the user, see
+    /// Client::notify()
+    unread: usize,
+    /// The terminal reported that it lost the focus
+    unfocused: bool,
+    /// /mouse off left the mouse to the terminal
+    mouse_off: bool,
+    quit: bool,
+}
+
+impl Client {
+    fn echo_received(&mut self, line: String) {
+        // NOTE: the Server sends the message back as `[HH:MM:SS] <nick> text`
+        let message = split_stamp(&line).map(|(_, message)| message).unwrap_or(&line);
+        let echo = message.split_once("> ").map(|(_, text)| text).unwrap_or(message);
+        // NOTE: the Server brings the client back upon the first message after /away
+        self.away = false;
+        // NOTE: the Server may silently drop some of the messages (rate limiting, etc),
+        // so everything that was sent before the echoed message is considered undelivered
+        while let Some((text, _, index)) = self.pending.pop_front() {
+            if text == echo {
+                // NOTE: the echo takes the place of the local copy instead of repeating it
+                self.chat.set(index, line, Style::Own);
+                return;
+            }
+            self.chat.set(index, format!("Undelivered: {text}"), Style::Error);
+        }
+        chat_own!(&mut self.chat, "{line}");
+    }
+
+    /// The broken characters become `�` instead of hiding the whole line, and the user is told
+    /// about them once per connection
+    fn decode(&mut self, frame: &[u8]) -> Sanitized {
+        let sanitized = sanitize_lossy(frame);
+        if !sanitized.valid && !self.invalid_utf8 {
+            self.invalid_utf8 = true;
+            chat_error!(&mut self.chat, "The server sent text that is not valid UTF-8, the broken characters are shown as �");
+        }
+        sanitized
+    }
+
+    fn pong_received(&mut self, nonce: &str) {
+        if let Some((expected, sent_at)) = self.ping {
+            if nonce == expected.to_string() {
+                self.ping = None;
+                chat_info!(&mut self.chat, "Pong: {ms}ms", ms = sent_at.elapsed().as_millis());
+            }
+        }
+    }
+
+    /// Handles one line from the Server
+    fn frame_received(&mut self, frame: &[u8]) {
+        let (sequence, frame) = split_sequence(frame);
+        if let Some(sequence) = sequence {
+            // NOTE: the bare number comes upon entering a room
+            self.sequence_received(sequence, frame.is_empty());
+            if frame.is_empty() {
+                return;
+            }
+        }
+        if frame == PING_TAG.to_string().as_bytes() {
+            if let Some(Err(err)) = self.connection.stream().map(|s| write_frame(s, &PING_TAG.to_string())) {
+                chat_error!(&mut self.chat, "Could not answer the ping: {err}");
+            }
+        } else if let Some(old) = frame.strip_prefix(&[HISTORY_TAG as u8]) {
+            let line = self.decode(old).text;
+            self.chat.push(line, Style::History);
+        } else if let Some(nonce) = frame.strip_prefix(&[PONG_TAG as u8]) {
+            let nonce = self.decode(nonce).text;
+            self.pong_received(&nonce);
+        } else if let Some(error) = frame.strip_prefix(&[ERROR_TAG as u8]) {
+            let line = self.decode(error).text;
+            self.notice_received(&line);
+            chat_error!(&mut self.chat, "{line}");
+        } else if let Some(notice) = frame.strip_prefix(&[DISCONNECT_TAG as u8]) {
+            // NOTE: the Server closes the connection right after this line, so it ends up right
+            // before "Server closed the connection"
+            let line = self.decode(notice).text;
+            self.notice_received(&line);
+            self.chat.push(line, Style::System);
+        } else if let Some(dm) = frame.strip_prefix(&[DM_TAG as u8]) {
+            let line = self.decode(dm).text;
+            self.collect_nicks(&line);
+            chat_dm!(&mut self.chat, "{line}");
+            self.notify(true);
+        } else if let Some(echo) = frame.strip_prefix(&[ECHO_TAG as u8]) {
+            let line = self.decode(echo).text;
+            self.echo_received(line);
+        } else {
+            if frame == WELCOME.as_bytes() {
+                self.welcome_received();
+            }
+            let Sanitized{text: line, sgr, ..} = self.decode(frame);
+            self.collect_nicks(&line);
+            // NOTE: the Server announces who joins and leaves with `* `
+            let message = split_stamp(&line).map(|(_, message)| message).unwrap_or(&line);
+            if message.starts_with("* ") {
+                self.chat.push(line, Style::System);
+            } else if self.nick.as_ref().is_some_and(|nick| message.starts_with(&format!("<{nick}> "))) {
+                // NOTE: the messages sent from the other sessions with the same nick
+                chat_own!(&mut self.chat, "{line}");
+            } else {
+                let mention = self.chat.push_message(line, if self.settings.ansi { &sgr } else { &[] });
+                self.notify(mention);
+            }
+        }
+    }
+
+    fn sequence_received(&mut self, sequence: u64, announce: bool) {
+        if let (Some(last), false) = (self.sequence, announce) {
+            // NOTE: the same number twice is fine, see how the Server echoes to the shadowed clients
+            if sequence > last + 1 {
+                chat_error!(&mut self.chat, "⚠ {missed} messages missed", missed = sequence - last - 1);
+            }
+        }
+        self.sequence = Some(sequence);
+    }
+
+    fn start_connecting(&mut self, target: Target, reconnecting: bool) {
+        self.frames = FrameReader::default();
+        self.invalid_utf8 = false;
+        self.set_nick(None);
+        self.room = Some(DEFAULT_ROOM.to_string());
+        self.sequence = None;
+        self.away = false;
+        self.listing = 0;
+        // NOTE: connecting anyway gets a fresh ban notice from the Server if the ban is still on
+        self.banned_until = None;
+        let (sender, result) = mpsc::channel();
+        let (address, pinned) = (target.address.clone(), target.pinned.clone());
+        // NOTE: nobody is waiting for the result if the connecting was cancelled, so the error of
+        // send() is fine to ignore
+        thread::spawn(move || {
+            let _ = sender.send(connect(&address, pinned.as_deref()));
+        });
+        self.connection = Connection::Connecting(Connecting {
+            target,
+            started: Instant::now(),
+            reconnecting,
+            result,
+        });
+    }
+
+    /// Picks up the connection once the thread is done with it. The token is sent from here, so
+    /// the cancelled connections are never authorized
+    fn poll_connecting(&mut self) {
+        let result = match &self.connection {
+            Connection::Connecting(connecting) => match connecting.result.try_recv() {
+                Err(TryRecvError::Empty) => return,
+                Ok(result) => result,
+                Err(TryRecvError::Disconnected) => Err(io::Error::other("the connecting thread is gone")),
+            },
+            _ => return,
+        };
+        if let Connection::Connecting(Connecting{target, started, reconnecting, ..}) = mem::take(&mut self.connection) {
+            match result.and_then(|mut stream| write_frame(&mut stream, &target.token).map(|()| stream)) {
+                Ok(stream) => {
+                    self.connection = Connection::Authenticating{stream, reconnecting};
+                    self.target = Some(target);
+                }
+                Err(err) => {
+                    chat_error!(&mut self.chat, "Could not connect to {address} after {elapsed:.1?}: {err}", address = Sens(&target.address), elapsed = started.elapsed(), err = Sens(err));
+                    if reconnecting {
+                        self.schedule_reconnect();
+                    }
+                }
+            }
+        }
+    }
+
+    /// The Server accepted the token, so whatever was held back until now goes out
+    fn welcome_received(&mut self) {
+        let stream = match mem::take(&mut self.connection) {
+            Connection::Authenticating{stream, reconnecting} => {
+                if reconnecting {
+                    chat_info!(&mut self.chat, "Reconnected");
+                }
+                stream
+            }
+            // NOTE: the Server does not welcome the same client twice, but if it does nothing changes
+            connection => {
+                self.connection = connection;
+                return;
+            }
+        };
+        self.retries = 0;
+        self.connection = Connection::Online(stream);
+        if let Some(nick) = self.settings.nick.clone() {
+            if let Some(stream) = self.connection.online() {
+                match write_frame(stream, &format!("/nick {nick}")) {
+                    Ok(()) => self.set_nick(Some(nick)),
+                    Err(err) => chat_error!(&mut self.chat, "Could not change nick: {err}"),
+                }
+            }
+        }
+    }
+
+    /// Called when the connection is gone without /disconnect
+    fn connection_lost(&mut self) {
+        self.connection = Connection::Offline;
+        if self.target.is_some() {
+            self.schedule_reconnect();
+        }
+    }
+
+    fn schedule_reconnect(&mut self) {
+        let delay = RECONNECT_DELAY.saturating_mul(1 << self.retries.min(16)).min(RECONNECT_DELAY_LIMIT);
+        self.retries += 1;
+        self.connection = Connection::Backoff(Instant::now() + delay);
+    }
+
+    fn poll_reconnect(&mut self) {
+        if let (Connection::Backoff(at), Some(target)) = (&self.connection, &self.target) {
+            if *at <= Instant::now() {
+                self.start_connecting(target.clone(), true);
+            }
+        }
+    }
+
+    /// Tells the user once the ban is over, so the user does not have to guess when to come back
+    fn poll_ban(&mut self) {
+        if let Some(until) = self.banned_until {
+            if until <= Instant::now() {
+                self.banned_until = None;
+                if let Connection::Refused(_) = self.connection {
+                    self.connection = Connection::Offline;
+                }
+                if let Some(target) = &self.target {
+                    chat_info!(&mut self.chat, "The ban on {address} is over. Use /reconnect to get back", address = Sens(&target.address));
+                }
+            }
+        }
+    }
+
+    /// Returns whether there was anything to cancel, either the connecting or the waiting for the
+    /// next attempt to reconnect. The thread finishes on its own and its connection is just dropped
+    fn cancel_connecting(&mut self) -> bool {
+        self.retries = 0;
+        match mem::take(&mut self.connection) {
+            Connection::Connecting(Connecting{target, ..}) => {
+                chat_info!(&mut self.chat, "Cancelled connecting to {address}", address = Sens(target.address));
+                true
+            }
+            Connection::Backoff(_) => {
+                chat_info!(&mut self.chat, "Stopped reconnecting");
+                true
+            }
+            connection => {
+                self.connection = connection;
+                false
+            }
+        }
+    }
+
+    /// Called for the messages of the others. The user is notified if the message mentions the
+    /// user or the user does not see it, being scrolled up or in another window
+    fn notify(&mut self, mention: bool) {
+        let unseen = self.chat.scroll > 0 || self.unfocused;
+        if !mention && (!unseen || self.settings.highlight_only) {
+            return;
+        }
+        self.unread += 1;
+        if self.settings.bell {
+            let _ = execute!(stdout(), Print('\x07')).map_err(|err| {
+                chat_error!(&mut self.chat, "Could not ring the bell: {err}");
+            });
+        }
+    }
+
+    /// The keys are the same in the config file and in /set. Returns false if there is no such key
+    fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        fn switch(value: &str) -> Result<bool, String> {
+            match value {
+                "on" | "true" => Ok(true),
+                "off" | "false" => Ok(false),
+                _ => Err(format!("invalid value {value:?}: must be on or off")),
+            }
+        }
+        // NOTE: before the `-` is replaced, since it can be a part of the name of the alias
+        if let Some(name) = key.strip_prefix("alias.") {
+            self.add_alias(name, value)?;
+            return Ok(true);
+        }
+        match key.replace('-', "_").as_str() {
+            "server" => self.settings.server = Some(value.to_string()),
+            "token_file" => self.settings.token_file = value.to_string(),
+            "nick" => self.settings.nick = Some(value.to_string()),
+            "autoconnect" => self.settings.autoconnect = switch(value)?,
+            "bell" => self.settings.bell = switch(value)?,
+            "title" => self.settings.title = switch(value)?,
+            "highlight_only" => self.settings.highlight_only = switch(value)?,
+            "timestamps" => self.chat.timestamps = switch(value)?,
+            "ansi" => self.settings.ansi = switch(value)?,
+            "scrollback" => self.chat.capacity = match value.parse() {
+                Ok(lines) if lines > 0 => lines,
+                _ => return Err(format!("invalid value {value:?}: must be a positive number of lines")),
+            },
+            // NOTE: drops the colors changed one by one, they were meant for the previous theme
+            "theme" => self.settings.theme = match Theme::find(value) {
+                Some(theme) => theme.clone(),
+                None => return Err(format!("unknown theme {value:?}")),
+            },
+            key => match self.settings.theme.color_mut(key) {
+                Some(color) => *color = parse_color(value)?,
+                None => return Ok(false),
+            },
+        }
+        Ok(true)
+    }
+
+    /// The settings as they are written to the config file. The ones that are not set are left out
+    fn config(&self) -> Vec<(String, String)> {
+        let on_off = |value: bool| if value { "on" } else { "off" }.to_string();
+        let mut config = Vec::new();
+        if let Some(server) = &self.settings.server {
+            config.push(("server", server.clone()));
+        }
+        config.push(("token_file", self.settings.token_file.clone()));
+        if let Some(nick) = &self.settings.nick {
+            config.push(("nick", nick.clone()));
+        }
+        config.push(("autoconnect", on_off(self.settings.autoconnect)));
+        config.push(("bell", on_off(self.settings.bell)));
+        config.push(("title", on_off(self.settings.title)));
+        config.push(("highlight_only", on_off(self.settings.highlight_only)));
+        config.push(("timestamps", on_off(self.chat.timestamps)));
+        config.push(("ansi", on_off(self.settings.ansi)));
+        config.push(("scrollback", self.chat.capacity().to_string()));
+        // NOTE: the colors changed one by one go after the theme, so they are not dropped upon
+        // loading the config
+        let mut theme = self.settings.theme.clone();
+        let mut preset = Theme::find(theme.name).cloned().unwrap_or_default();
+        config.push(("theme", theme.name.to_string()));
+        for &key in THEME_KEYS {
+            let color = theme.color_mut(key).copied();
+            if color != preset.color_mut(key).copied() {
+                config.push((key, color.map(color_name).unwrap_or_default()));
+            }
+        }
+        let mut config: Vec<_> = config.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+        // NOTE: sorted, so saving the same aliases does not shuffle the file
+        let mut aliases: Vec<_> = self.settings.aliases.iter().collect();
+        aliases.sort();
+        for (name, expansion) in aliases {
+            config.push((format!("alias.{name}"), expansion.clone()));
+        }
+        config
+    }
+
+    /// The aliases can't be named after the commands, so they never hide them
+    fn add_alias(&mut self, name: &str, expansion: &str) -> Result<(), String> {
+        let name = name.strip_prefix('/').unwrap_or(name);
+        let expansion = expansion.trim();
+        let expansion = expansion.strip_prefix('/').unwrap_or(expansion);
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("invalid alias name {name:?}"));
+        }
+        if find_command(name).is_some() {
+            return Err(format!("/{name} is a command already"));
+        }
+        if expansion.is_empty() {
+            return Err(format!("/{name} must expand to a command"));
+        }
+        // NOTE: the aliases are listed by /alias and saved in the config file as they are
+        if connect_token(&format!("alias {name} {expansion}")).is_some() {
+            return Err(format!("/{name} would keep the token in plain text, read it from @file instead"));
+        }
+        self.settings.aliases.insert(name.to_string(), expansion.to_string());
+        Ok(())
+    }
+
+    /// Replaces the alias with the command it stands for, the `argument` going after the
+    /// arguments of the alias. Does nothing to the commands
+    fn expand_alias(&self, mut name: String, mut argument: String) -> Result<(String, String), String> {
+        let alias = name.clone();
+        // NOTE: one more round than the limit to see whether the last expansion is a command
+        for _ in 0..=ALIAS_DEPTH_LIMIT {
+            let expansion = if let Some(expansion) = self.settings.aliases.get(&name) {
+                expansion
+            } else {
+                return Ok((name, argument));
+            };
+            let (head, tail) = expansion.split_once(' ').unwrap_or((expansion, ""));
+            argument = match (tail.trim(), argument.trim()) {
+                (tail, "") => tail.to_string(),
+                ("", argument) => argument.to_string(),
+                (tail, argument) => format!("{tail} {argument}"),
+            };
+            name = head.to_string();
+        }
+        Err(format!("/{alias} expands into aliases more than {ALIAS_DEPTH_LIMIT} times"))
+    }
+
+    /// Remembers the nicks from the lines of the Server, see speaker(). The answer to /list is
+    /// `N clients online` followed by one nick per line, which are not stamped unlike the
+    /// messages of the room
+    fn collect_nicks(&mut self, line: &str) {
+        let stamped = split_stamp(line);
+        if stamped.is_none() && self.listing > 0 {
+            self.listing -= 1;
+            // NOTE: the Server lists only so many of the clients
+            if line.starts_with("...and ") {
+                self.listing = 0;
+            } else {
+                let nick = line.split_once(" (away").map_or(line, |(nick, _)| nick);
+                self.saw_nick(nick);
+            }
+            return;
+        }
+        if let (None, Some(Ok(n))) = (stamped, line.strip_suffix(" clients online").map(str::parse)) {
+            self.listing = n;
+            return;
+        }
+        let message = stamped.map_or(line, |(_, message)| message);
+        if let Some((old, new)) = message.strip_prefix("* ").and_then(|rest| rest.split_once(" is now known as ")) {
+            self.recent_nicks.retain(|known| known != old);
+            self.saw_nick(new);
+        } else if let Some(nick) = speaker(message) {
+            self.saw_nick(nick);
+        }
+    }
+
+    fn saw_nick(&mut self, nick: &str) {
+        if nick.is_empty() || nick.contains(' ') || self.nick.as_deref() == Some(nick) {
+            return;
+        }
+        self.recent_nicks.retain(|known| known != nick);
+        self.recent_nicks.insert(0, nick.to_string());
+        self.recent_nicks.truncate(RECENT_NICKS_LIMIT);
+    }
+
+    /// The chat highlights the mentions of the nick, so it has to know about every change of it
+    fn set_nick(&mut self, nick: Option<String>) {
+        self.chat.set_nick(nick.as_deref());
+        self.nick = nick;
+    }
+
+    /// The title of the terminal, None if it is left alone. The same number of the unread
+    /// messages as in the status bar and the Server the Client is connected to
+    fn title(&self) -> Option<String> {
+        if !self.settings.title {
+            return None;
+        }
+        let mut title = "4at".to_string();
+        if self.unread > 0 {
+            title.push_str(&format!(" ({unread})", unread = self.unread));
+        }
+        match (self.connection.is_established(), &self.target) {
+            (true, Some(target)) => title.push_str(&format!(" — {address}", address = Sens(&target.address))),
+            _ => title.push_str(" — offline"),
+        }
+        Some(title)
+    }
+
+    /// Asks first if there is something to lose, like the connection or the `unsent` text in the
+    /// prompt
+    fn request_quit(&mut self, unsent: bool) {
+        if unsent || self.connection.is_established() || !self.outbox.is_empty() {
+            self.mode = Mode::ConfirmQuit(Instant::now());
+        } else {
+            self.quit = true;
+        }
+    }
+
+    /// Ctrl+C cancels the connecting if there is one and quits otherwise
+    fn interrupt(&mut self, unsent: bool) {
+        if !self.cancel_connecting() {
+            self.request_quit(unsent);
+        }
+    }
+
+    /// How long the main loop may wait for the keys and the Server before something else needs its
+    /// attention. None if nothing else is going to
+    fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let mut deadlines = Vec::new();
+        match self.connection {
+            // NOTE: the connecting thread can't wake up the main loop
+            Connection::Connecting(_) => return Some(SOCKET_POLL_INTERVAL),
+            Connection::Backoff(at) => deadlines.extend([at, now + COUNTDOWN_TICK]),
+            Connection::Refused(_) if self.banned_until.is_some() => deadlines.push(now + COUNTDOWN_TICK),
+            Connection::Online(_) if !self.outbox.is_empty() => {
+                deadlines.extend(self.outbox_sent_at.map(|sent_at| sent_at + MESSAGE_RATE));
+            }
+            Connection::Offline | Connection::Refused(_) | Connection::Authenticating{..} | Connection::Online(_) => {}
+        }
+        deadlines.extend(self.banned_until);
+        deadlines.extend(self.pending.front().map(|(_, sent_at, _)| *sent_at + ECHO_TIMEOUT));
+        if let Mode::ConfirmQuit(since) = self.mode {
+            deadlines.push(since + QUIT_CONFIRM_TIMEOUT);
+        }
+        deadlines.into_iter().min().map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    /// The segments of the status bar at the bottom, see status_bar()
+    fn status(&self) -> (Vec<String>, Vec<String>) {
+        if let Mode::ConfirmQuit(_) = self.mode {
+            return (vec!["Really quit? y/N".to_string()], vec![]);
+        }
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        match &self.connection {
+            Connection::Online(_) => {
+                let mut label = "Online".to_string();
+                if let Some(nick) = &self.nick {
+                    label.push_str(&format!(" as {nick}"));
+                }
+                if self.away {
+                    label.push_str(" (away)");
+                }
+                left.push(label);
+            }
+            Connection::Authenticating{..} => left.push("Authenticating…".to_string()),
+            Connection::Connecting(Connecting{target, started, reconnecting, ..}) => {
+                let attempt = if *reconnecting {
+                    format!(", attempt {retries}", retries = self.retries.max(1))
+                } else {
+                    String::new()
+                };
+                left.push(format!("Connecting to {address}… ({elapsed}s{attempt})", address = Sens(&target.address), elapsed = started.elapsed().as_secs()));
+            }
+            Connection::Backoff(at) => {
+                let secs = at.saturating_duration_since(Instant::now()).as_secs_f32().ceil();
+                left.push(format!("Offline, reconnecting in {secs}s (attempt {retries})", retries = self.retries));
+            }
+            Connection::Refused(reason) => match self.banned_until {
+                Some(until) => {
+                    let secs = until.saturating_duration_since(Instant::now()).as_secs_f32().ceil();
+                    left.push(format!("Offline, {reason} for {secs}s"));
+                }
+                None => left.push(format!("Offline, {reason}")),
+            },
+            Connection::Offline => left.push("Offline".to_string()),
+        }
+        if let Mode::Search(search) = &self.mode {
+            left.push(search.status());
+        }
+        if self.chat.filter != Filter::All {
+            left.push(format!("showing {filter} only", filter = self.chat.filter.name()));
+        }
+        if self.chat.unseen > 0 {
+            left.push(format!("▼ {n} new messages", n = self.chat.unseen));
+        }
+        if self.unread > 0 {
+            left.push(format!("{n} unread", n = self.unread));
+        }
+        if !self.outbox.is_empty() {
+            right.push(format!("{n} queued", n = self.outbox.len()));
+        }
+        if let Some((path, _)) = &self.chat.log {
+            right.push(format!("logging to {path}"));
+        }
+        if let (true, Some(target)) = (self.connection.is_established(), &self.target) {
+            right.push(Sens(&target.address).to_string());
+        }
+        (left, right)
+    }
+
+    /// Checks whether the line from the Server is one of FINAL_NOTICES. The Server closes the
+    /// connection right after them, so the Client does not wait for that
+    fn notice_received(&mut self, line: &str) {
+        if let Some((_, reason)) = FINAL_NOTICES.iter().find(|(notice, _)| line.starts_with(notice)) {
+            if *reason == "banned" {
+                self.banned_until = Some(Instant::now() + ban_left(line));
+            }
+            self.connection = Connection::Refused(reason);
+        }
+    }
+
+    /// The message shows up in the chat right away as `you: text` and gets replaced by the echo
+    /// of the Server, see echo_received()
+    fn send_message(&mut self, text: String) {
+        // NOTE: the queued messages go first, so the order is kept
+        if let (Some(stream), true) = (self.connection.online(), self.outbox.is_empty()) {
+            if let Err(err) = write_frame(stream, &text) {
+                chat_error!(&mut self.chat, "Undelivered: {text} ({err})");
+            } else {
+                self.pending.push_back((text.clone(), Instant::now(), self.chat.next_index()));
+                chat_own!(&mut self.chat, "you: {text}");
+            }
+        } else {
+            if matches!(self.connection, Connection::Offline | Connection::Refused(_)) && self.outbox.is_empty() {
+                chat_info!(&mut self.chat, "You are offline. The message is sent once you connect with {signature}.", signature = find_command("connect").expect("connect command").signature());
+            }
+            self.outbox.push_back((text.clone(), self.chat.next_index()));
+            self.chat.push(format!("you: {text}"), Style::Pending);
+        }
+    }
+
+    /// Sends the queued messages one by one MESSAGE_RATE apart, so the Server does not drop them
+    /// and strike the Client for flooding
+    fn flush_outbox(&mut self) {
+        if let Some(sent_at) = self.outbox_sent_at {
+            if sent_at.elapsed() < MESSAGE_RATE {
+                return;
+            }
+        }
+        if let (Some(stream), Some((text, index))) = (self.connection.online(), self.outbox.front()) {
+            self.outbox_sent_at = Some(Instant::now());
+            match write_frame(stream, text) {
+                Ok(()) => {
+                    if let Some(item) = self.chat.get_mut(*index) {
+                        item.style = Style::Own;
+                    }
+                    if let Some((text, index)) = self.outbox.pop_front() {
+                        self.pending.push_back((text, Instant::now(), index));
+                    }
+                }
+                Err(err) => chat_error!(&mut self.chat, "Could not send the queued message: {err}"),
+            }
+        }
+    }
+
+    /// Takes back the last message of the outbox. Its line stays in the chat marked as such, since
+    /// the mentions, the search and the scroll refer to the lines after it by their numbers
+    fn unqueue_last(&mut self) {
+        if let Some((text, index)) = self.outbox.pop_back() {
+            self.chat.set(index, format!("Unqueued: {text}"), Style::Info);
+        }
+    }
+
+    fn expire_pending(&mut self) {
+        while let Some((_, sent_at, _)) = self.pending.front() {
+            if sent_at.elapsed() < ECHO_TIMEOUT {
+                break;
+            }
+            if let Some((text, _, index)) = self.pending.pop_front() {
+                self.chat.set(index, format!("Undelivered: {text}"), Style::Error);
+            }
+        }
+    }
+}
+
+/// Connection to the Server, encrypted or not. The socket is non-blocking either way
+enum Stream {
+    Plain(TcpStream),
+    Tls(TcpStream, Box<ClientConnection>),
+}
+
+impl Stream {
+    /// Whether some of the encrypted data is still waiting for the socket to take it
+    #[cfg(unix)]
+    fn wants_write(&self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            Self::Tls(_, tls) => tls.wants_write(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Plain(stream) | Self::Tls(stream, _) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (stream, tls) = match self {
+            Self::Plain(stream) => return stream.read(buf),
+            Self::Tls(stream, tls) => (stream, tls),
+        };
+        loop {
+            match tls.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+            if tls.read_tls(stream)? == 0 {
+                return Ok(0);
+            }
+            tls.process_new_packets().map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(_, tls) => {
+                let n = tls.writer().write(buf)?;
+                self.flush()?;
+                Ok(n)
+            }
+        }
+    }
+
+    /// Sends as much of the encrypted data as the socket takes without blocking. The rest is sent
+    /// on the next flush, which happens on every iteration of the main loop
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream, tls) => {
+                while tls.wants_write() {
+                    match tls.write_tls(stream) {
+                        Ok(_) => {}
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Trusts the single certificate with the given fingerprint, which is the way to go with the
+/// self-signed ones
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("the certificate does not match the fingerprint".to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Validates the certificate of the Server against the well known CAs, unless the fingerprint
+/// of the certificate is pinned
+fn tls_config(pinned: Option<&str>) -> ClientConfig {
+    if let Some(pinned) = pinned {
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                fingerprint: pinned.replace(':', "").to_lowercase(),
+                provider: Arc::new(crypto::ring::default_provider()),
+            }))
+            .with_no_client_auth()
+    } else {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+}
+
+/// Strips the port and the brackets from the address
+fn address_host(address: &str) -> &str {
+    if let Some(rest) = address.strip_prefix('[') {
+        return rest.split_once(']').map(|(host, _)| host).unwrap_or(rest);
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+        _ => address,
+    }
+}
+
+/// The address is prefixed with `tls://` for the encrypted connections. Blocks, so it is run on
+/// a separate thread, see Connecting
+fn connect(address: &str, pinned: Option<&str>) -> io::Result<Stream> {
+    let (tls, address) = match address.strip_prefix("tls://") {
+        Some(address) => (true, address),
+        None => (false, address),
+    };
+    let mut stream = connect_any(&resolve_address(address)?)?;
+    let stream = if tls {
+        let server_name = ServerName::try_from(address_host(address).to_string()).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        let mut session = ClientConnection::new(Arc::new(tls_config(pinned)), server_name).map_err(io::Error::other)?;
+        // NOTE: the handshake is done while the socket is still blocking, so the token is never
+        // sent before the Server is verified
+        stream.set_read_timeout(Some(TLS_HANDSHAKE_TIMEOUT))?;
+        while session.is_handshaking() {
+            session.complete_io(&mut stream)?;
+        }
+        stream.set_read_timeout(None)?;
+        stream.set_nonblocking(true)?;
+        Stream::Tls(stream, Box::new(session))
+    } else {
+        stream.set_nonblocking(true)?;
+        Stream::Plain(stream)
+    };
+    Ok(stream)
+}
+
+/// Tries the addresses one by one like TcpStream::connect() does, but does not wait for any of
+/// them longer than CONNECT_TIMEOUT
+fn connect_any(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "no addresses to connect to")))
+}
+
+/// Accepts `host`, `host:port`, `ip`, `ip:port`, `[ipv6]` and `[ipv6]:port`
+fn resolve_address(address: &str) -> io::Result<Vec<SocketAddr>> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidInput, message);
+    // NOTE: IPv6 without the brackets can't have the port
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, DEFAULT_PORT)]);
+    }
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) if port.starts_with(':') => (host, Some(&port[1..])),
+            _ => return Err(invalid(format!("malformed address {address:?}"))),
+        }
+    } else {
+        match address.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid(format!("invalid port {port:?}")))?,
+        None => DEFAULT_PORT,
+    };
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()
+        .map_err(|err| io::Error::new(err.kind(), format!("could not resolve {host}: {err}")))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(ErrorKind::NotFound, format!("{host} has no addresses")));
+    }
+    Ok(addrs)
+}
+
+/// The first word of the file, so the TOKENS file of the Server works as well as the file with
+/// just the token
+fn token_from_file(file_path: &str) -> io::Result<String> {
+    let content = fs::read_to_string(file_path)?;
+    content.split_whitespace().next()
+        .map(|token| token.to_string())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "there is no token in the file"))
+}
+
+/// How long the ban from the notice of the Server lasts, like `You are banned MF: 57.3 secs left`.
+/// The notices that don't tell, like the one upon getting banned, take BAN_LIMIT, the default of
+/// the Server
+fn ban_left(notice: &str) -> Duration {
+    let words: Vec<&str> = notice.split(|c: char| c.is_whitespace() || c == ':' || c == ',').filter(|word| !word.is_empty()).collect();
+    for (i, word) in words.iter().enumerate() {
+        // NOTE: both `57.3 secs` and `57.3s`
+        let (number, unit) = match word.find(|c: char| !c.is_ascii_digit() && c != '.') {
+            Some(at) => word.split_at(at),
+            None => (*word, words.get(i + 1).copied().unwrap_or("")),
+        };
+        if let (Ok(secs), true) = (number.parse(), unit == "s" || unit.starts_with("sec")) {
+            if let Ok(left) = Duration::try_from_secs_f64(secs) {
+                return left;
+            }
+        }
+    }
+    BAN_LIMIT
+}
+
+fn connect_command(client: &mut Client, args: &[String]) {
+    connect_with_args(client, args, false);
+}
 
-fn connect_command(client: &mut Client, argument: &str) {
-    if client.stream.is_none() {
-        let chunks: Vec<&str> = argument.split(' ').filter(|s| !s.is_empty()).collect();
-        match &chunks[..] {
-            &[ip, token] => {
-                client.stream = TcpStream::connect(&format!("{ip}:6969"))
-                    .and_then(|mut stream| {
-                        stream.set_nonblocking(true)?;
-                        stream.write(token.as_bytes())?;
-                        Ok(stream)
-                    })
-                    .map_err(|err| {
-                        chat_error!(&mut client.chat, "Could not connect to {ip}: {err}")
-                    })
-                    .ok();
+/// Connects even if the Server has banned the Client
+fn force_connect_command(client: &mut Client, args: &[String]) {
+    connect_with_args(client, args, true);
+}
+
+fn connect_with_args(client: &mut Client, args: &[String], force: bool) {
+    if let Connection::Connecting(Connecting{target, ..}) = &client.connection {
+        chat_error!(&mut client.chat, "Already connecting to {address}. Cancel with /disconnect or Ctrl+C first.", address = Sens(&target.address));
+    } else if !client.connection.is_established() {
+        // NOTE: the server from the config file, with the token from its token_file
+        let config_args;
+        let args = match (args, &client.settings.server) {
+            ([], Some(server)) => {
+                config_args = [server.clone(), format!("@{token_file}", token_file = client.settings.token_file)];
+                &config_args[..]
             }
-            _ => {
-                // TODO: get the signature of the command from COMMANDS
-                chat_error!(&mut client.chat, "Incorrect usage of connect command. Try /connect <ip> <token>");
+            _ => args,
+        };
+        let address = if let Some(address) = args.first() {
+            address
+        } else {
+            chat_error!(&mut client.chat, "There is no server to connect to. Try {signature} or /set server <address>", signature = find_command("connect").expect("connect command").signature());
+            return;
+        };
+        // NOTE: the Server only answers with the ban notice again, and drops the Client
+        // silently after a few of them
+        if let (false, Some(until), Some(target)) = (force, client.banned_until, &client.target) {
+            if target.address == *address {
+                let secs = until.saturating_duration_since(Instant::now()).as_secs_f32().ceil();
+                chat_error!(&mut client.chat, "You are banned from {address} for {secs}s more. Use /connect! to try anyway", address = Sens(address));
+                return;
             }
         }
+        let token = match args.get(1) {
+            Some(token) if !token.starts_with('@') => token.to_string(),
+            token => {
+                let file_path = token.map(|token| &token[1..]).unwrap_or(TOKENS_FILE_PATH);
+                match token_from_file(file_path) {
+                    Ok(token) => token,
+                    Err(err) => {
+                        chat_error!(&mut client.chat, "Could not read the token from {file_path}: {err}");
+                        return;
+                    }
+                }
+            }
+        };
+        client.retries = 0;
+        client.start_connecting(Target {
+            address: address.to_string(),
+            token,
+            pinned: args.get(2).cloned(),
+        }, false);
     } else {
-        // TODO: get the signature of the command from COMMANDS
-        chat_error!(&mut client.chat, "You are already connected to a server. Disconnect with /disconnect first.");
+        chat_error!(&mut client.chat, "You are already connected to a server. Disconnect with {signature} first.", signature = find_command("disconnect").expect("disconnect command").signature());
     }
 }
 
-fn disconnect_command(client: &mut Client, _argument: &str) {
-    if client.stream.is_some() {
-        client.stream = None;
+fn disconnect_command(client: &mut Client, _args: &[String]) {
+    if client.connection.is_established() {
+        client.connection = Connection::Offline;
         chat_info!(&mut client.chat, "Disconnected.");
-    } else {
+    } else if !client.cancel_connecting() {
         chat_info!(&mut client.chat, "You are already offline ._.");
     }
 }
 
-fn quit_command(client: &mut Client, _argument: &str) {
-    client.quit = true;
+fn reconnect_command(client: &mut Client, _args: &[String]) {
+    if client.connection.is_established() {
+        chat_error!(&mut client.chat, "You are already connected to a server. Disconnect with /disconnect first.");
+    } else if let Connection::Connecting(Connecting{target, ..}) = &client.connection {
+        chat_error!(&mut client.chat, "Already connecting to {address}. Cancel with /disconnect or Ctrl+C first.", address = Sens(&target.address));
+    } else if let Some(target) = &client.target {
+        client.start_connecting(target.clone(), true);
+    } else {
+        chat_error!(&mut client.chat, "There is nothing to reconnect to. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn nick_command(client: &mut Client, args: &[String]) {
+    let nick = &args[0];
+    if let Some(stream) = client.connection.online() {
+        match write_frame(stream, &format!("/nick {nick}")) {
+            Ok(()) => client.set_nick(Some(nick.to_string())),
+            Err(err) => chat_error!(&mut client.chat, "Could not change nick: {err}"),
+        }
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn msg_command(client: &mut Client, args: &[String]) {
+    let (nick, text) = (&args[0], &args[1]);
+    if let Some(stream) = client.connection.online() {
+        if let Err(err) = write_frame(stream, &format!("/msg {nick} {text}")) {
+            chat_error!(&mut client.chat, "Could not send the message: {err}");
+        }
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn list_command(client: &mut Client, _args: &[String]) {
+    if let Some(stream) = client.connection.online() {
+        if let Err(err) = write_frame(stream, "/list") {
+            chat_error!(&mut client.chat, "Could not request the list of users: {err}");
+        }
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn join_command(client: &mut Client, args: &[String]) {
+    let room = &args[0];
+    if let Some(stream) = client.connection.online() {
+        match write_frame(stream, &format!("/join {room}")) {
+            Ok(()) => client.room = Some(room.clone()),
+            Err(err) => chat_error!(&mut client.chat, "Could not join the room: {err}"),
+        }
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn leave_command(client: &mut Client, _args: &[String]) {
+    if let Some(stream) = client.connection.online() {
+        match write_frame(stream, "/leave") {
+            Ok(()) => client.room = Some(DEFAULT_ROOM.to_string()),
+            Err(err) => chat_error!(&mut client.chat, "Could not leave the room: {err}"),
+        }
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn away_command(client: &mut Client, args: &[String]) {
+    if let Some(stream) = client.connection.online() {
+        let line = match args.first() {
+            Some(reason) => format!("/away {reason}"),
+            None => "/away".to_string(),
+        };
+        match write_frame(stream, &line) {
+            Ok(()) => client.away = true,
+            Err(err) => chat_error!(&mut client.chat, "Could not go away: {err}"),
+        }
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn outbox_command(client: &mut Client, args: &[String]) {
+    match args.first().map(String::as_str) {
+        None => chat_info!(&mut client.chat, "{n} messages are waiting to be sent", n = client.outbox.len()),
+        Some("clear") => {
+            let n = client.outbox.len();
+            while !client.outbox.is_empty() {
+                client.unqueue_last();
+            }
+            chat_info!(&mut client.chat, "Dropped {n} messages that were waiting to be sent");
+        }
+        _ => chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("outbox").expect("outbox command").signature()),
+    }
+}
+
+fn ping_command(client: &mut Client, _args: &[String]) {
+    // NOTE: goes around the outbox, since the queued messages would only add to the time
+    if let Some(stream) = client.connection.online() {
+        client.pings_sent += 1;
+        let nonce = client.pings_sent;
+        match write_frame(stream, &format!("/ping {nonce}")) {
+            Ok(()) => client.ping = Some((nonce, Instant::now())),
+            Err(err) => chat_error!(&mut client.chat, "Could not ping the server: {err}"),
+        }
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature());
+    }
+}
+
+fn filter_command(client: &mut Client, args: &[String]) {
+    match Filter::NAMES.iter().find(|(name, _)| *name == args[0]) {
+        Some(&(_, filter)) => client.chat.set_filter(filter),
+        None => chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("filter").expect("filter command").signature()),
+    }
+}
+
+fn clear_command(client: &mut Client, args: &[String]) {
+    if let Some(lines) = args.first() {
+        match lines.parse() {
+            Ok(n) => client.chat.truncate_to(n),
+            Err(_) => chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("clear").expect("clear command").signature()),
+        }
+    } else {
+        client.chat.clear();
+    }
+}
+
+fn mentions_command(client: &mut Client, args: &[String]) {
+    let n = if let Some(n) = args.first() {
+        if let Ok(n) = n.parse() {
+            n
+        } else {
+            chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("mentions").expect("mentions command").signature());
+            return;
+        }
+    } else {
+        MENTIONS_SHOWN
+    };
+    let lines: Vec<String> = client.chat.last_mentions(n).iter().map(|item| item.line()).collect();
+    if lines.is_empty() {
+        chat_info!(&mut client.chat, "Nobody mentioned you yet");
+        return;
+    }
+    chat_info!(&mut client.chat, "Last {n} mentions:", n = lines.len());
+    for line in lines {
+        client.chat.push(line, Style::Mention);
+    }
+}
+
+fn open_command(client: &mut Client, args: &[String]) {
+    let number = if let Some(number) = args.first() {
+        if let Ok(number) = number.parse() {
+            Some(number)
+        } else {
+            chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("open").expect("open command").signature());
+            return;
+        }
+    } else {
+        None
+    };
+    let link = if let Some(link) = client.chat.links.get(number) {
+        link.to_string()
+    } else {
+        match number {
+            Some(number) => chat_error!(&mut client.chat, "There is no link [{number}]"),
+            None => chat_error!(&mut client.chat, "There are no links in the chat yet"),
+        }
+        return;
+    };
+    // NOTE: `start` is built into cmd, and its first quoted argument is the title of the window
+    let (program, args): (&str, &[&str]) = if cfg!(windows) {
+        ("cmd", &["/C", "start", ""])
+    } else if cfg!(target_os = "macos") {
+        ("open", &[])
+    } else {
+        ("xdg-open", &[])
+    };
+    // NOTE: the opener must not print anything over the TUI, and it is waited for on another
+    // thread, so it does not stay around as a zombie
+    let child = process::Command::new(program)
+        .args(args)
+        .arg(&link)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(err) => chat_error!(&mut client.chat, "Could not run {program}: {err}"),
+    }
+}
+
+/// The files are named after the current time in UTC, like 4at-20240131-235959.log
+fn default_log_path() -> String {
+    let time = rfc3339(SystemTime::now()).replace(['-', ':'], "").replace('T', "-");
+    format!("4at-{time}.log", time = time.trim_end_matches('Z'))
+}
+
+fn save(client: &mut Client, args: &[String], overwrite: bool) {
+    let path = args.first().cloned().unwrap_or_else(default_log_path);
+    match client.chat.save(&path, overwrite) {
+        Ok(n) => chat_info!(&mut client.chat, "Saved {n} lines to {path}"),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            chat_error!(&mut client.chat, "{path} already exists. Use {signature} to overwrite it", signature = find_command("save!").expect("save! command").signature());
+        }
+        Err(err) => chat_error!(&mut client.chat, "Could not save the chat to {path}: {err}"),
+    }
+}
+
+fn save_command(client: &mut Client, args: &[String]) {
+    save(client, args, false);
+}
+
+fn force_save_command(client: &mut Client, args: &[String]) {
+    save(client, args, true);
+}
+
+fn log_command(client: &mut Client, args: &[String]) {
+    match (args[0].as_str(), args.get(1)) {
+        ("on", path) => {
+            let path = path.cloned().unwrap_or_else(default_log_path);
+            match OpenOptions::new().append(true).create(true).open(&path) {
+                Ok(file) => {
+                    client.chat.log = Some((path.clone(), file));
+                    chat_info!(&mut client.chat, "Logging to {path}");
+                }
+                Err(err) => chat_error!(&mut client.chat, "Could not open {path}: {err}"),
+            }
+        }
+        ("off", None) => if let Some((path, _)) = client.chat.log.take() {
+            chat_info!(&mut client.chat, "Stopped logging to {path}");
+        } else {
+            chat_info!(&mut client.chat, "Not logging");
+        }
+        _ => chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("log").expect("log command").signature()),
+    }
+}
+
+fn mouse_command(client: &mut Client, args: &[String]) {
+    let result = match args[0].as_str() {
+        "on" => execute!(stdout(), EnableMouseCapture).map(|()| client.mouse_off = false),
+        "off" => execute!(stdout(), DisableMouseCapture).map(|()| client.mouse_off = true),
+        _ => {
+            chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("mouse").expect("mouse command").signature());
+            return;
+        }
+    };
+    if let Err(err) = result {
+        chat_error!(&mut client.chat, "Could not switch the mouse: {err}");
+    }
+}
+
+fn set_command(client: &mut Client, args: &[String]) {
+    let (key, value) = match (args.first().map(String::as_str), args.get(1)) {
+        (None, _) => {
+            for (key, value) in client.config() {
+                chat_info!(&mut client.chat, "{key} = {value}");
+            }
+            return;
+        }
+        (Some("save"), None) => {
+            match save_config(client) {
+                Ok(path) => chat_info!(&mut client.chat, "Saved the settings to {path}"),
+                Err(err) => chat_error!(&mut client.chat, "Could not save the settings: {err}"),
+            }
+            return;
+        }
+        (Some(key), Some(value)) => (key, value),
+        (Some(_), None) => {
+            chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("set").expect("set command").signature());
+            return;
+        }
+    };
+    match client.set(key, value) {
+        Ok(true) => {
+            // NOTE: saved again right away, so there is still something to restore upon the exit
+            if key == "title" && !client.settings.title {
+                if let Err(err) = ScreenState::restore_title().and_then(|()| ScreenState::save_title()) {
+                    chat_error!(&mut client.chat, "Could not reset the title: {err}");
+                }
+            }
+        }
+        Ok(false) => {
+            let keys: Vec<_> = client.config().into_iter().map(|(key, _)| key).collect();
+            chat_error!(&mut client.chat, "Unknown setting `{key}`. Try {keys}", keys = keys.join(", "));
+        }
+        Err(err) => chat_error!(&mut client.chat, "{key}: {err}"),
+    }
+}
+
+fn theme_command(client: &mut Client, args: &[String]) {
+    let names: Vec<_> = THEMES.iter().map(|theme| theme.name).collect();
+    if let Some(name) = args.first() {
+        if let Some(theme) = Theme::find(name) {
+            client.settings.theme = theme.clone();
+        } else {
+            chat_error!(&mut client.chat, "Unknown theme `{name}`. Try {names}", names = names.join(", "));
+        }
+    } else {
+        chat_info!(&mut client.chat, "Themes: {names}. Using {name}", names = names.join(", "), name = client.settings.theme.name);
+    }
+}
+
+/// Writes the aliases to the config file, but only if there is one. The rest of the settings go
+/// there as well
+fn save_aliases(client: &mut Client) {
+    if !config_path().is_some_and(|file_path| Path::new(&file_path).exists()) {
+        return;
+    }
+    if let Err(err) = save_config(client) {
+        chat_error!(&mut client.chat, "Could not save the aliases: {err}");
+    }
 }
 
-fn help_command(client: &mut Client, argument: &str) {
-    let name = argument.trim();
-    if name.is_empty() {
-        for Command{signature, description, ..} in COMMANDS.iter() {
-            chat_info!(client.chat, "{signature} - {description}");
+fn alias_command(client: &mut Client, args: &[String]) {
+    match (args.first(), args.get(1)) {
+        (None, _) => {
+            let mut aliases: Vec<_> = client.settings.aliases.iter().map(|(name, expansion)| format!("/{name} = /{expansion}")).collect();
+            aliases.sort();
+            if aliases.is_empty() {
+                chat_info!(&mut client.chat, "No aliases yet. Try {signature}", signature = find_command("alias").expect("alias command").signature());
+            }
+            for alias in aliases {
+                chat_info!(&mut client.chat, "{alias}");
+            }
         }
+        (Some(name), None) => {
+            let name = name.strip_prefix('/').unwrap_or(name);
+            match client.settings.aliases.get(name) {
+                Some(expansion) => chat_info!(&mut client.chat, "/{name} = /{expansion}"),
+                None => chat_error!(&mut client.chat, "There is no alias /{name}"),
+            }
+        }
+        (Some(name), Some(expansion)) => match client.add_alias(name, expansion) {
+            Ok(()) => save_aliases(client),
+            Err(err) => chat_error!(&mut client.chat, "{err}"),
+        }
+    }
+}
+
+fn unalias_command(client: &mut Client, args: &[String]) {
+    let name = args[0].strip_prefix('/').unwrap_or(&args[0]);
+    if client.settings.aliases.remove(name).is_some() {
+        save_aliases(client);
     } else {
-        if let Some(Command{signature, description, ..}) = find_command(name) {
-            chat_info!(client.chat, "{signature} - {description}");
+        chat_error!(&mut client.chat, "There is no alias /{name}");
+    }
+}
+
+fn timestamps_command(client: &mut Client, args: &[String]) {
+    match args[0].as_str() {
+        "on" => client.chat.timestamps = true,
+        "off" => client.chat.timestamps = false,
+        _ => chat_error!(&mut client.chat, "Usage: {signature}", signature = find_command("timestamps").expect("timestamps command").signature()),
+    }
+}
+
+fn quit_command(client: &mut Client, _args: &[String]) {
+    client.request_quit(false);
+}
+
+fn force_quit_command(client: &mut Client, _args: &[String]) {
+    client.quit = true;
+}
+
+fn help_command(client: &mut Client, args: &[String]) {
+    if let Some(name) = args.first() {
+        let name = name.strip_prefix('/').unwrap_or(name);
+        if let Some(command) = find_command(name) {
+            chat_info!(client.chat, "{help}", help = command.help());
+        } else if let Some(expansion) = client.settings.aliases.get(name) {
+            chat_info!(client.chat, "/{name} is an alias of /{expansion}");
         } else {
             chat_error!(&mut client.chat, "Unknown command `/{name}`");
         }
+    } else {
+        for command in COMMANDS.iter() {
+            chat_info!(client.chat, "{help}", help = command.help());
+        }
+    }
+}
+
+struct Arg {
+    /// Shown in the signature as `<name>` or `[name]`
+    name: &'static str,
+    required: bool,
+    /// Takes the rest of the line as it is, spaces included, like the text of /msg. Only the last
+    /// argument can be like that
+    rest: bool,
+}
+
+impl Arg {
+    const fn required(name: &'static str) -> Self {
+        Self { name, required: true, rest: false }
+    }
+
+    const fn optional(name: &'static str) -> Self {
+        Self { name, required: false, rest: false }
+    }
+
+    const fn rest(self) -> Self {
+        Self { rest: true, ..self }
+    }
+
+    fn placeholder(&self) -> String {
+        if self.required {
+            format!("<{name}>", name = self.name)
+        } else {
+            format!("[{name}]", name = self.name)
+        }
     }
 }
 
 struct Command {
     name: &'static str,
+    /// The short names that work the same as the `name`, like `/c` for `/connect`
+    aliases: &'static [&'static str],
     description: &'static str,
-    signature: &'static str,
-    run: fn(&mut Client, &str),
+    args: &'static [Arg],
+    /// Gets the arguments already checked against `args`, see parse_arguments(). The optional
+    /// ones that are not given are missing from the end
+    run: fn(&mut Client, &[String]),
+}
+
+impl Command {
+    /// The line of /help
+    fn help(&self) -> String {
+        let aliases: Vec<_> = self.aliases.iter().map(|alias| format!("/{alias}")).collect();
+        if aliases.is_empty() {
+            format!("{signature} - {description}", signature = self.signature(), description = self.description)
+        } else {
+            format!("{signature} (also {aliases}) - {description}", signature = self.signature(), aliases = aliases.join(", "), description = self.description)
+        }
+    }
+
+    fn signature(&self) -> String {
+        let mut signature = format!("/{name}", name = self.name);
+        for arg in self.args {
+            signature.push(' ');
+            signature.push_str(&arg.placeholder());
+        }
+        signature
+    }
+
+    /// What is left to type after the `typed` arguments, like `<nick> <text>` right after `/msg `
+    fn hint(&self, typed: usize) -> Option<String> {
+        let rest: Vec<_> = self.args.get(typed..)?.iter().map(Arg::placeholder).collect();
+        if rest.is_empty() {
+            None
+        } else {
+            Some(rest.join(" "))
+        }
+    }
+
+    /// Splits the arguments by the whitespace. An argument with spaces goes in the double quotes,
+    /// except for the `rest` one, which is taken as it is
+    fn parse_arguments(&self, argument: &str) -> Result<Vec<String>, String> {
+        let (ranges, result) = self.split_arguments(argument);
+        result?;
+        Ok(ranges.into_iter().map(|range| argument[range].to_string()).collect())
+    }
+
+    /// Where the arguments are in the `argument`, without the quotes. Goes as far as it can even
+    /// if the arguments are wrong, so the line being typed is split the same way as when it is
+    /// sent (see Prompt::secret()). An unclosed quote goes until the end
+    fn split_arguments(&self, argument: &str) -> (Vec<Range<usize>>, Result<(), String>) {
+        let mut ranges = Vec::new();
+        let mut rest = argument.trim_start();
+        while !rest.is_empty() {
+            let start = argument.len() - rest.len();
+            let arg = if let Some(arg) = self.args.get(ranges.len()) {
+                arg
+            } else {
+                return (ranges, Err(format!("unexpected `{rest}`", rest = rest.trim_end())));
+            };
+            if arg.rest {
+                ranges.push(start..start + rest.trim_end().len());
+                break;
+            }
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let start = start + 1;
+                match quoted.split_once('"') {
+                    Some((word, tail)) => {
+                        ranges.push(start..start + word.len());
+                        if !tail.is_empty() && !tail.starts_with(char::is_whitespace) {
+                            return (ranges, Err(format!("expected a space after the closing quote of {arg}", arg = arg.placeholder())));
+                        }
+                        rest = tail.trim_start();
+                    }
+                    None => {
+                        ranges.push(start..argument.len());
+                        return (ranges, Err(format!("unclosed quote in {arg}", arg = arg.placeholder())));
+                    }
+                }
+            } else {
+                let end = start + rest.find(char::is_whitespace).unwrap_or(rest.len());
+                ranges.push(start..end);
+                rest = argument[end..].trim_start();
+            }
+        }
+        if let Some(arg) = self.args.get(ranges.len()).filter(|arg| arg.required) {
+            return (ranges, Err(format!("missing {arg}", arg = arg.placeholder())));
+        }
+        (ranges, Ok(()))
+    }
 }
 
 const COMMANDS: &[Command] = &[
     Command {
         name: "connect",
+        aliases: &["c"],
         run: connect_command,
-        description: "Connect to a server by <ip> with authorization <token>",
-        signature: "/connect <ip> <token>",
+        description: "Connect to a server at <address> (host or IP, optionally with :port, prefixed with tls:// for TLS) with authorization <token>, which is read from the file if given as @file and from ./TOKENS if not given at all. The TLS certificate is checked against the <fingerprint> if given",
+        args: &[Arg::optional("address"), Arg::optional("token|@file"), Arg::optional("fingerprint")],
+    },
+    Command {
+        name: "connect!",
+        aliases: &["c!"],
+        run: force_connect_command,
+        description: "Same as /connect, but tries even while the server has banned you",
+        args: &[Arg::optional("address"), Arg::optional("token|@file"), Arg::optional("fingerprint")],
     },
     Command {
         name: "disconnect",
+        aliases: &["d"],
         run: disconnect_command,
         description: "Disconnect from the server you are currently connected to",
-        signature: "/disconnect",
+        args: &[],
+    },
+    Command {
+        name: "reconnect",
+        aliases: &[],
+        run: reconnect_command,
+        description: "Connect to the last server right away. The Client reconnects on its own when the connection is lost, /disconnect stops it",
+        args: &[],
+    },
+    Command {
+        name: "nick",
+        aliases: &[],
+        run: nick_command,
+        description: "Change the name other users see next to your messages",
+        args: &[Arg::required("name")],
+    },
+    Command {
+        name: "msg",
+        aliases: &["m"],
+        run: msg_command,
+        description: "Send the <text> only to the user with the <nick>",
+        args: &[Arg::required("nick"), Arg::required("text").rest()],
+    },
+    Command {
+        name: "list",
+        aliases: &[],
+        run: list_command,
+        description: "List the users who are online",
+        args: &[],
+    },
+    Command {
+        name: "join",
+        aliases: &["j"],
+        run: join_command,
+        description: "Move to the <room>. Only the people in the same room see your messages",
+        args: &[Arg::required("room")],
+    },
+    Command {
+        name: "leave",
+        aliases: &[],
+        run: leave_command,
+        description: "Go back to the default room",
+        args: &[],
+    },
+    Command {
+        name: "away",
+        aliases: &[],
+        run: away_command,
+        description: "Let the room know you are away until you send the next message",
+        args: &[Arg::optional("reason").rest()],
+    },
+    Command {
+        name: "ping",
+        aliases: &[],
+        run: ping_command,
+        description: "Measure the round trip to the server. The ping counts as a message for the rate limit of the server",
+        args: &[],
+    },
+    Command {
+        name: "outbox",
+        aliases: &[],
+        run: outbox_command,
+        description: "Tell how many messages typed while offline are waiting to be sent, or drop them with clear. Esc drops the last one",
+        args: &[Arg::optional("clear")],
+    },
+    Command {
+        name: "filter",
+        aliases: &[],
+        run: filter_command,
+        description: "Show only the errors or only the info lines of the chat, or all of them again. The hidden lines stay in the chat",
+        args: &[Arg::required("errors|info|all")],
+    },
+    Command {
+        name: "clear",
+        aliases: &[],
+        run: clear_command,
+        description: "Wipe the chat, or keep only the last <lines> of it. Ctrl+Shift+K wipes it as well",
+        args: &[Arg::optional("lines")],
+    },
+    Command {
+        name: "mentions",
+        aliases: &[],
+        run: mentions_command,
+        description: "Print the last <n> messages mentioning your nick, 10 by default. Alt+M scrolls the chat to them one by one",
+        args: &[Arg::optional("n")],
+    },
+    Command {
+        name: "open",
+        aliases: &[],
+        run: open_command,
+        description: "Open the link with the <number> shown next to it in the browser, or the latest link",
+        args: &[Arg::optional("number")],
+    },
+    Command {
+        name: "save",
+        aliases: &[],
+        run: save_command,
+        description: "Write the chat to the file at <path>, named after the current time if not given. Never overwrites a file",
+        args: &[Arg::optional("path")],
+    },
+    Command {
+        name: "save!",
+        aliases: &[],
+        run: force_save_command,
+        description: "Write the chat to the file at <path> even if it exists",
+        args: &[Arg::optional("path")],
+    },
+    Command {
+        name: "log",
+        aliases: &[],
+        run: log_command,
+        description: "Start or stop appending every new line of the chat to the file at <path>, named after the current time if not given",
+        args: &[Arg::required("on|off"), Arg::optional("path")],
+    },
+    Command {
+        name: "mouse",
+        aliases: &[],
+        run: mouse_command,
+        description: "Scroll the chat with the wheel and click into the prompt, or leave the mouse to the terminal for selecting the text",
+        args: &[Arg::required("on|off")],
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        run: set_command,
+        description: "Change a setting, like the server /connect goes to without the arguments, the nick taken upon connecting, or how the new messages are notified about (bell, title, highlight_only). Without the arguments lists the settings, save writes them to the config file",
+        args: &[Arg::optional("key|save"), Arg::optional("value").rest()],
+    },
+    Command {
+        name: "alias",
+        aliases: &[],
+        run: alias_command,
+        description: "Make /<name> run the <command> with its arguments, followed by whatever is typed after /<name>. Without the <command> prints the alias, without the arguments lists all of them. The aliases are kept in the config file if there is one",
+        args: &[Arg::optional("name"), Arg::optional("command").rest()],
+    },
+    Command {
+        name: "unalias",
+        aliases: &[],
+        run: unalias_command,
+        description: "Forget the alias /<name>",
+        args: &[Arg::required("name")],
+    },
+    Command {
+        name: "timestamps",
+        aliases: &[],
+        run: timestamps_command,
+        description: "Show or hide the time next to each message",
+        args: &[Arg::required("on|off")],
+    },
+    Command {
+        name: "theme",
+        aliases: &[],
+        run: theme_command,
+        description: "Switch the colors to one of the themes, or list them without the name",
+        args: &[Arg::optional("name")],
     },
     Command {
         name: "quit",
+        aliases: &["q"],
         run: quit_command,
-        description: "Close the chat",
-        signature: "/quit",
+        description: "Close the chat. Asks first while connected or with messages waiting to be sent",
+        args: &[],
+    },
+    Command {
+        name: "quit!",
+        aliases: &["q!"],
+        run: force_quit_command,
+        description: "Close the chat without asking",
+        args: &[],
     },
     Command {
         name: "help",
+        aliases: &[],
         run: help_command,
         description: "Print help",
-        signature: "/help [command]",
+        args: &[Arg::optional("command")],
     },
 ];
 
-// TODO: find_command should be const fn so you could look up specific commands at compile time
+// TODO: find_command should be const fn so you could look up specific commands at compile time
+/// Where the token is in the `command` line without its `/`, split the same way as when the line
+/// is sent. The aliases are followed into what they expand to
+fn connect_token(command: &str) -> Option<Range<usize>> {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    let command = find_command(name)?;
+    let (arguments, _) = command.split_arguments(argument);
+    let token = match command.name {
+        "connect" | "connect!" => arguments.get(1).filter(|token| !argument[(*token).clone()].starts_with('@')).cloned()?,
+        "alias" => {
+            let expansion = arguments.get(1)?;
+            let start = expansion.start + usize::from(argument[expansion.clone()].starts_with('/'));
+            let token = connect_token(&argument[start..expansion.end])?;
+            token.start + start..token.end + start
+        }
+        _ => return None,
+    };
+    let offset = name.len() + 1;
+    Some(token.start + offset..token.end + offset)
+}
+
 fn find_command(name: &str) -> Option<&Command> {
-    COMMANDS.iter().find(|command| command.name == name)
+    COMMANDS.iter().find(|command| command.name == name || command.aliases.contains(&name))
 }
 
 fn apply_patches(qc: &mut impl QueueableCommand, patches: &[Patch]) -> io::Result<()> {
     let mut fg_curr = Color::White;
     let mut bg_curr = Color::Black;
+    let mut underline_curr = false;
     let mut x_prev = 0;
     let mut y_prev = 0;
     qc.queue(SetForegroundColor(fg_curr))?;
     qc.queue(SetBackgroundColor(bg_curr))?;
-    for Patch{cell: Cell{ch, fg, bg}, x, y} in patches {
+    qc.queue(SetAttribute(Attribute::NoUnderline))?;
+    for Patch{cell: Cell{ch, fg, bg, underline}, x, y} in patches {
+        if *ch == WIDE_TAIL {
+            continue;
+        }
         if !(y_prev == *y && x_prev + 1 == *x) {
             qc.queue(MoveTo(*x as u16, *y as u16))?;
         }
@@ -447,46 +3743,264 @@ fn apply_patches(qc: &mut impl QueueableCommand, patches: &[Patch]) -> io::Resul
             bg_curr = *bg;
             qc.queue(SetBackgroundColor(bg_curr))?;
         }
+        if underline_curr != *underline {
+            underline_curr = *underline;
+            qc.queue(SetAttribute(if underline_curr { Attribute::Underlined } else { Attribute::NoUnderline }))?;
+        }
         qc.queue(Print(ch))?;
     }
+    // NOTE: the prompt is printed by the terminal itself, so nothing may be left underlined
+    qc.queue(SetAttribute(Attribute::NoUnderline))?;
     Ok(())
 }
 
+/// None unless SCROLLBACK_ENV is set. It goes over the config file
+fn scrollback_from_env() -> Result<Option<usize>, String> {
+    match env::var(SCROLLBACK_ENV) {
+        Ok(value) => match value.parse() {
+            Ok(limit) if limit > 0 => Ok(Some(limit)),
+            _ => Err(format!("{SCROLLBACK_ENV} must be a positive number of lines, but it is {value:?}")),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+fn config_path() -> Option<String> {
+    let dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => dir,
+        _ => format!("{home}/.config", home = env::var("HOME").ok()?),
+    };
+    Some(format!("{dir}/{CONFIG_FILE_PATH}"))
+}
+
+/// Reads `key = value` lines like the config of the Server. Everything after `#` is a comment.
+/// The bad lines are reported and skipped, a missing file is the same as an empty one
+fn load_config(client: &mut Client, file_path: &str) {
+    let content = match fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(err) => {
+            if err.kind() != ErrorKind::NotFound {
+                chat_error!(&mut client.chat, "Could not load the config from {file_path}: {err}");
+            }
+            return;
+        }
+    };
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split_once('#').map(|(line, _)| line).unwrap_or(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once('=').map(|(key, value)| (key.trim(), value.trim())) {
+            Some((key, value)) => match client.set(key, value) {
+                Ok(true) => {}
+                Ok(false) => chat_error!(&mut client.chat, "{file_path}:{line_number}: unknown key {key}"),
+                Err(err) => chat_error!(&mut client.chat, "{file_path}:{line_number}: {key}: {err}"),
+            }
+            None => chat_error!(&mut client.chat, "{file_path}:{line_number}: expected key = value"),
+        }
+    }
+}
+
+/// Writes the settings over the config file, the comments in it are lost. Returns the path
+fn save_config(client: &Client) -> io::Result<String> {
+    let file_path = config_path().ok_or_else(|| io::Error::other("neither $XDG_CONFIG_HOME nor $HOME is set"))?;
+    if let Some((dir, _)) = file_path.rsplit_once('/') {
+        fs::create_dir_all(dir)?;
+    }
+    let mut content = String::new();
+    for (key, value) in client.config() {
+        content.push_str(&format!("{key} = {value}\n"));
+    }
+    fs::write(&file_path, content)?;
+    Ok(file_path)
+}
+
+/// Clears the terminal and forgets what was on it, so the next frame is drawn in full. Repairs
+/// whatever other programs printed over the chat, since the diff of the frames can't see it
+fn force_redraw(qc: &mut impl Write, buf_prev: &mut Buffer) -> io::Result<()> {
+    buf_prev.clear();
+    buf_prev.flush(qc)
+}
+
 fn main() -> io::Result<()> {
+    set_safe_mode(safe_mode_from_env());
     let mut client = Client::default();
     let mut stdout = stdout();
-    let _screen_state = ScreenState::enable()?;
+    let screen_state = ScreenState::enable()?;
+    // NOTE: set by Ctrl+Z as well as by SIGTSTP from the outside, like `kill -TSTP`. The raw mode
+    // turns Ctrl+Z into a key instead of the signal
+    let suspend = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(SIGTSTP, Arc::clone(&suspend))?;
+    #[cfg(unix)]
+    let mut waiter = Waiter::new()?;
     let (mut w, mut h) = terminal::size()?;
-    let mut buf_curr = Buffer::new(w as usize, h as usize);
-    let mut buf_prev = Buffer::new(w as usize, h as usize);
+    let mut buf_curr = Buffer::new(w as usize, h as usize, client.settings.theme.blank());
+    let mut buf_prev = Buffer::new(w as usize, h as usize, client.settings.theme.blank());
     let mut prompt = Prompt::default();
-    let mut buf = [0; 64];
-    help_command(&mut client, "");
+    let mut history = History::default();
+    let mut completion: Option<Completion> = None;
+    let mut buf = [0; 4*1024];
+    help_command(&mut client, &[]);
+    // NOTE: after the help, so the errors in the config are not scrolled away
+    if let Some(file_path) = config_path() {
+        load_config(&mut client, &file_path);
+    }
+    match scrollback_from_env() {
+        Ok(Some(limit)) => client.chat.capacity = limit,
+        Ok(None) => {}
+        Err(err) => {
+            let capacity = client.chat.capacity();
+            chat_error!(&mut client.chat, "{err}, using {capacity} instead");
+        }
+    }
+    if client.settings.autoconnect {
+        if let Some(server) = &client.settings.server {
+            chat_info!(&mut client.chat, "Connecting to {server} as the config says", server = Sens(server));
+            connect_command(&mut client, &[]);
+        } else {
+            chat_error!(&mut client.chat, "autoconnect is on, but there is no server in the config");
+        }
+    }
     buf_prev.flush(&mut stdout)?;
+    // NOTE: the bars are rendered again only when their text changes, like the countdowns
+    let mut bars_prev = None;
+    let mut title_prev = None;
+    let mut timeout = Some(Duration::ZERO);
     while !client.quit {
-        while poll(Duration::ZERO)? {
+        let mut dirty = false;
+        // NOTE: more data may be waiting after a full read of the socket
+        let mut more = false;
+        #[cfg(unix)]
+        {
+            waiter.wait(client.connection.stream().map(|s| &*s), timeout)?;
+            timeout = Some(Duration::ZERO);
+        }
+        #[cfg(not(unix))]
+        if client.connection.is_established() {
+            timeout = Some(timeout.map_or(SOCKET_POLL_INTERVAL, |timeout| timeout.min(SOCKET_POLL_INTERVAL)));
+        }
+        let mut wait = timeout.unwrap_or(COUNTDOWN_TICK);
+        while poll(wait)? {
+            wait = Duration::ZERO;
+            dirty = true;
             match read()? {
                 Event::Resize(nw, nh) => {
                     w = nw;
                     h = nh;
                     buf_curr.resize(w as usize, h as usize);
                     buf_prev.resize(w as usize, h as usize);
-                    buf_prev.flush(&mut stdout)?;
+                    force_redraw(&mut stdout, &mut buf_prev)?;
+                }
+                Event::Paste(data) => {
+                    let (data, cut) = sanitize_paste(&data);
+                    if cut {
+                        chat_info!(&mut client.chat, "Only the first {PASTE_LIMIT} bytes of the paste were taken");
+                    }
+                    if let Mode::Search(search) = &mut client.mode {
+                        search.query.insert_str(&data);
+                        search.update(&mut client.chat);
+                    } else {
+                        prompt.insert_str(&data);
+                    }
+                }
+                Event::FocusGained => {
+                    client.unfocused = false;
+                    client.unread = 0;
+                }
+                Event::FocusLost => client.unfocused = true,
+                Event::Mouse(event) => match event.kind {
+                    MouseEventKind::ScrollUp => client.chat.scroll_up(WHEEL_SCROLL),
+                    MouseEventKind::ScrollDown => {
+                        client.chat.scroll_down(WHEEL_SCROLL);
+                        if client.chat.scroll == 0 {
+                            client.unread = 0;
+                        }
+                    }
+                    // NOTE: only the prompt reacts to the clicks, the same x and w as in the render below
+                    MouseEventKind::Down(MouseButton::Left) if event.row + 1 == h => if let Some(w) = w.checked_sub(1) {
+                        completion = None;
+                        prompt.click(1, w as usize, event.column as usize);
+                    }
+                    _ => {}
+                },
+                Event::Key(event) if event.kind == KeyEventKind::Press && matches!(client.mode, Mode::ConfirmQuit(_)) => {
+                    // NOTE: a second Ctrl+C confirms as well, any other key changes the mind
+                    match event.code {
+                        KeyCode::Char('y' | 'Y') => client.quit = true,
+                        KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => client.quit = true,
+                        _ => client.mode = Mode::Prompt,
+                    }
+                }
+                Event::Key(event) if event.kind == KeyEventKind::Press && matches!(client.mode, Mode::Search(_)) => {
+                    client.unread = 0;
+                    if event.code == KeyCode::Char('l') && event.modifiers.contains(KeyModifiers::CONTROL) {
+                        force_redraw(&mut stdout, &mut buf_prev)?;
+                    } else if let Mode::Search(search) = &mut client.mode {
+                        if !search.key(event, &mut client.chat) {
+                            client.mode = Mode::Prompt;
+                        }
+                    }
                 }
-                Event::Paste(data) => prompt.insert_str(&data),
-                Event::Key(event) => if event.kind == KeyEventKind::Press {
+                Event::Key(event) if event.kind == KeyEventKind::Press => {
+                    // NOTE: the user is obviously looking
+                    client.unread = 0;
+                    // NOTE: any other key accepts the variant in the prompt
+                    if !matches!(event.code, KeyCode::Tab | KeyCode::BackTab) {
+                        completion = None;
+                    }
                     match event.code {
                         KeyCode::Char(x) => if event.modifiers.contains(KeyModifiers::CONTROL) {
                             match x {
-                                'c' => client.quit = true,
+                                'c' => client.interrupt(!prompt.buffer.is_empty()),
+                                // NOTE: most terminals send Ctrl+Shift+K as Ctrl+K, unless they report
+                                // the modifiers on their own
+                                'k' | 'K' if event.modifiers.contains(KeyModifiers::SHIFT) => client.chat.clear(),
                                 'k' => prompt.delete_until_end(),
+                                'u' => prompt.delete_until_start(),
+                                'w' => prompt.delete_word_backward(),
+                                'y' => prompt.yank(),
+                                // NOTE: the terminals send Ctrl+_ as Ctrl+7, and most of them send
+                                // Ctrl+Shift+Z as Ctrl+Z, so there is Alt+Z for the redo as well
+                                'z' | 'Z' if event.modifiers.contains(KeyModifiers::SHIFT) => prompt.redo(),
+                                // NOTE: suspends like any other program where the shells have the
+                                // job control, and undoes elsewhere
+                                'z' if cfg!(unix) => suspend.store(true, Ordering::Relaxed),
+                                'z' | '_' | '7' => prompt.undo(),
+                                'a' => prompt.home(),
+                                'e' => prompt.end(),
+                                'l' => force_redraw(&mut stdout, &mut buf_prev)?,
+                                'f' => {
+                                    let mut search = Search::new(&client.chat);
+                                    search.update(&mut client.chat);
+                                    client.mode = Mode::Search(search);
+                                }
+                                _ => {}
+                            }
+                        } else if event.modifiers.contains(KeyModifiers::ALT) {
+                            match x {
+                                'm' if !client.chat.jump_to_mention() => {
+                                    chat_info!(&mut client.chat, "Nobody mentioned you yet");
+                                }
+                                'z' => prompt.redo(),
                                 _ => {}
                             }
                         } else {
                             prompt.insert(x);
                         }
-                        // TODO: message history scrolling via up/down
-                        // TODO: basic readline navigation keybindings
+                        KeyCode::Up => if event.modifiers.contains(KeyModifiers::SHIFT) {
+                            client.chat.scroll_up(1);
+                        } else if let Some(entry) = history.up(&prompt.buffer) {
+                            prompt.set(entry);
+                        }
+                        KeyCode::Down => if event.modifiers.contains(KeyModifiers::SHIFT) {
+                            client.chat.scroll_down(1);
+                        } else if let Some(entry) = history.down() {
+                            prompt.set(entry);
+                        }
+                        KeyCode::PageUp => client.chat.scroll_up(client.chat.height),
+                        KeyCode::PageDown => client.chat.scroll_down(client.chat.height),
                         KeyCode::Left => if event.modifiers.contains(KeyModifiers::CONTROL) {
                             prompt.left_word();
                         } else {
@@ -497,113 +4011,1278 @@ fn main() -> io::Result<()> {
                         } else {
                             prompt.right_char();
                         }
-                        KeyCode::Backspace => prompt.backspace(),
-                        // TODO: delete current character by KeyCode::Delete
-                        // TODO: delete word by Ctrl+W
-                        KeyCode::Tab => {
-                            if let Some((prefix, &[])) = parse_command(prompt.before_cursor()) {
-                                let prefix = prefix.iter().collect::<String>();
-                                let rest = prompt.after_cursor().iter().collect::<String>();
-                                if let Some(command) = COMMANDS.iter().find(|command| command.name.starts_with(&prefix)) {
-                                    // TODO: tab autocompletion should scroll through different
-                                    // variants on each TAB press
-                                    prompt.clear();
-                                    prompt.insert('/');
-                                    prompt.insert_str(command.name);
-                                    prompt.insert_str(&rest);
-                                    prompt.cursor = command.name.len() + 1;
-                                }
+                        KeyCode::Backspace => if event.modifiers.contains(KeyModifiers::ALT) {
+                            prompt.delete_word_backward();
+                        } else {
+                            prompt.backspace();
+                        }
+                        KeyCode::Delete => if event.modifiers.contains(KeyModifiers::CONTROL) {
+                            prompt.delete_word_forward();
+                        } else {
+                            prompt.delete_char();
+                        }
+                        KeyCode::Home => prompt.home(),
+                        KeyCode::Esc => client.unqueue_last(),
+                        KeyCode::End => {
+                            prompt.end();
+                            client.chat.scroll_to_bottom();
+                        }
+                        KeyCode::Tab | KeyCode::BackTab => {
+                            if completion.is_none() {
+                                completion = Completion::start(prompt.before_cursor(), prompt.after_cursor(), &client.settings.aliases, &client.recent_nicks);
+                            }
+                            if let Some(completion) = &mut completion {
+                                let (line, cursor) = completion.cycle(event.code == KeyCode::Tab);
+                                prompt.set(&line);
+                                prompt.cursor = cursor;
                             }
                         }
                         KeyCode::Enter => {
+                            // NOTE: the line with the token is not remembered at all, see Prompt::secret()
+                            history.push(if prompt.secret().is_some() {
+                                String::new()
+                            } else {
+                                prompt.buffer.iter().collect()
+                            });
                             if let Some((name, argument)) = parse_command(&prompt.buffer) {
                                 let name = name.iter().collect::<String>();
                                 let argument = argument.iter().collect::<String>();
-                                if let Some(command) = find_command(&name) {
-                                    (command.run)(&mut client, &argument);
-                                } else {
-                                    chat_error!(&mut client.chat, "Unknown command `/{name}`");
+                                match client.expand_alias(name, argument) {
+                                    Ok((name, argument)) => if let Some(command) = find_command(&name) {
+                                        match command.parse_arguments(&argument) {
+                                            Ok(args) => (command.run)(&mut client, &args),
+                                            Err(err) => chat_error!(&mut client.chat, "Incorrect usage of {name} command: {err}. Try {signature}", name = command.name, signature = command.signature()),
+                                        }
+                                    } else {
+                                        chat_error!(&mut client.chat, "Unknown command `/{name}`");
+                                    }
+                                    Err(err) => chat_error!(&mut client.chat, "{err}"),
                                 }
                             } else {
-                                if let Some(ref mut stream) = &mut client.stream {
-                                    let prompt = prompt.buffer.iter().collect::<String>();
-                                    stream.write(prompt.as_bytes())?;
-                                    // TODO: don't display the message if it was not delivered
-                                    // Maybe the server should actually send your own message back.
-                                    // Not sending it back made sense in the telnet times.
-                                    chat_msg!(&mut client.chat, "{text}", text = &prompt);
-                                } else {
-                                    chat_info!(&mut client.chat, "You are offline. Use {signature} to connect to a server.", signature = find_command("connect").expect("connect command").signature);
+                                // NOTE: the Server drops the blank messages and trims the trailing
+                                // whitespace, so the echo matches only what is actually sent
+                                let text = prompt.buffer.iter().collect::<String>().trim_end().to_string();
+                                if !text.is_empty() {
+                                    client.send_message(text);
                                 }
                             }
                             prompt.clear();
                         }
                         _ => {},
                     }
-                },
+                }
                 _ => {},
             }
         }
 
-        if let Some(ref mut s) = &mut client.stream {
+        if let Some(s) = client.connection.stream() {
+            if let Err(err) = s.flush() {
+                chat_error!(&mut client.chat, "Could not send the pending data: {err}");
+            }
             match s.read(&mut buf) {
                 Ok(n) => {
+                    dirty = true;
+                    more = n == buf.len();
                     if n > 0 {
-                        if let Some(line) = sanitize_terminal_output(&buf[..n]) {
-                            client.chat.push(line, Color::White)
+                        client.frames.extend(&buf[..n]);
+                        while let Some(frame) = client.frames.next_frame() {
+                            client.frame_received(&frame);
+                        }
+                        // NOTE: the rest of the line is dropped as it arrives, the same way the
+                        // Server drops the oversized messages of the clients
+                        if client.frames.discard_oversized(SERVER_FRAME_LIMIT) {
+                            chat_error!(&mut client.chat, "Server sent a line longer than {SERVER_FRAME_LIMIT} bytes, dropped it");
                         }
                     } else {
-                        client.stream = None;
+                        client.connection_lost();
                         chat_info!(&mut client.chat, "Server closed the connection");
                     }
                 }
                 Err(err) => if err.kind() != ErrorKind::WouldBlock {
-                    client.stream = None;
-                    chat_error!(&mut client.chat, "Connection Error: {err}");
+                    dirty = true;
+                    client.connection_lost();
+                    chat_error!(&mut client.chat, "Connection Error: {err}", err = Sens(err));
                 }
             }
         }
 
-        buf_curr.clear();
-        status_bar(&mut buf_curr, "4at", 0, 0, w.into());
-        // TODO: vertical scrolling for chat window
-        // TODO: horizontal scrolling for chat window
-        if let Some(h) = h.checked_sub(3) {
-            client.chat.render(&mut buf_curr, Rect {
-                x: 0,
-                y: 1,
-                w: w as usize,
-                h: h as usize,
-            });
+        if let Mode::ConfirmQuit(since) = client.mode {
+            if since.elapsed() >= QUIT_CONFIRM_TIMEOUT {
+                client.mode = Mode::Prompt;
+            }
+        }
+        #[cfg(unix)]
+        if suspend.swap(false, Ordering::Relaxed) {
+            screen_state.suspend(!client.mouse_off)?;
+            // NOTE: the terminal may have been resized or scribbled over meanwhile
+            (w, h) = terminal::size()?;
+            buf_curr.resize(w as usize, h as usize);
+            buf_prev.resize(w as usize, h as usize);
+            force_redraw(&mut stdout, &mut buf_prev)?;
+            // NOTE: the title of the shell was put back meanwhile
+            title_prev = None;
+            dirty = true;
+        }
+        client.poll_reconnect();
+        client.poll_ban();
+        client.poll_connecting();
+        client.flush_outbox();
+        client.expire_pending();
+
+        let room = match (&client.connection, &client.room) {
+            (Connection::Online(_), Some(room)) => vec![format!("#{room}")],
+            _ => vec![],
+        };
+        // NOTE: the empty cells are in the colors of the theme too, so the new one is drawn in full
+        let blank = client.settings.theme.blank();
+        if buf_prev.blank != blank {
+            buf_curr.blank = blank.clone();
+            buf_prev.blank = blank;
+            force_redraw(&mut stdout, &mut buf_prev)?;
+            dirty = true;
+        }
+        let bars = (room, client.status());
+        if dirty || client.chat.changed || bars_prev.as_ref() != Some(&bars) {
+            let (room, (left, right)) = &bars;
+            let theme = &client.settings.theme;
+            buf_curr.clear();
+            status_bar(&mut buf_curr, theme, &["4at".to_string()], room, 0, 0, w.into());
+            // TODO: horizontal scrolling for chat window
+            if let Some(h) = h.checked_sub(3) {
+                let highlight = if let Mode::Search(search) = &client.mode {
+                    &search.query.buffer[..]
+                } else {
+                    &[]
+                };
+                client.chat.render(&mut buf_curr, Rect {
+                    x: 0,
+                    y: 1,
+                    w: w as usize,
+                    h: h as usize,
+                }, highlight, theme);
+            }
+            if let Some(h) = h.checked_sub(2) {
+                status_bar(&mut buf_curr, theme, left, right, 0, h as usize, w.into());
+            }
+            let hint = match client.mode {
+                Mode::Search(_) => None,
+                _ => argument_hint(prompt.before_cursor(), prompt.after_cursor()),
+            };
+            // NOTE: the query of the search takes the place of the prompt
+            let (input, label) = match &mut client.mode {
+                Mode::Search(search) => (&mut search.query, SEARCH_LABEL),
+                _ => (&mut prompt, "-"),
+            };
+            if let Some(y) = h.checked_sub(1) {
+                let label_chars: Vec<_> = label.chars().collect();
+                let x = buf_curr.put_cells(0, y as usize, fit_columns(&label_chars, w.into()), theme.foreground, theme.background);
+                if let Some(w) = (w as usize).checked_sub(x) {
+                    input.render(&mut buf_curr, x, y as usize, w, theme);
+                    // NOTE: the text is shown from the start and the cursor is at the end, so
+                    // the text fits and the hint goes right after it
+                    if let Some(hint) = hint.filter(|_| input.scroll == 0) {
+                        let hint_chars: Vec<_> = hint.chars().collect();
+                        let n = columns(&input.shown());
+                        if let Some(room) = w.checked_sub(n + 2) {
+                            buf_curr.put_cells(x + 1 + n, y as usize, fit_columns(&hint_chars, room), theme.dim, theme.background);
+                        }
+                    }
+                }
+            }
+
+            apply_patches(&mut stdout, &buf_prev.diff(&buf_curr))?;
+
+            if let Some(y) = h.checked_sub(1) {
+                let x = columns(&label.chars().collect::<Vec<_>>()).min(w.into());
+                if let Some(w) = (w as usize).checked_sub(x) {
+                    input.sync_terminal_cursor(&mut stdout, x, y as usize, w)?;
+                }
+            }
+            let title = client.title();
+            if let Some(title) = title.as_ref().filter(|title| title_prev.as_ref() != Some(*title)) {
+                stdout.queue(SetTitle(title))?;
+            }
+            title_prev = title;
+            stdout.flush()?;
+            mem::swap(&mut buf_curr, &mut buf_prev);
+            bars_prev = Some(bars);
         }
-        let status_label = if client.stream.is_some() {
-            "Status: Online"
+
+        timeout = if more {
+            Some(Duration::ZERO)
         } else {
-            "Status: Offline"
+            client.timeout()
         };
-        if let Some(h) = h.checked_sub(2) {
-            status_bar(&mut buf_curr, status_label, 0, h as usize, w.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ServerConfig, ServerConnection};
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::pki_types::pem::PemObject;
+
+    #[test]
+    fn idle_client_sleeps_until_something_happens() {
+        let mut client = Client::default();
+        assert_eq!(client.timeout(), None);
+        client.connection = Connection::Backoff(Instant::now() + Duration::from_secs(10));
+        assert!(client.timeout().is_some_and(|timeout| timeout <= COUNTDOWN_TICK));
+        client.connection = Connection::Offline;
+        client.pending.push_back(("hi".to_string(), Instant::now(), 0));
+        assert!(client.timeout().is_some_and(|timeout| timeout <= ECHO_TIMEOUT && timeout > COUNTDOWN_TICK));
+    }
+
+    #[test]
+    fn addresses_are_parsed() {
+        let resolve = |address| resolve_address(address).map(|addrs| addrs[0].to_string()).map_err(|err| err.kind());
+        assert_eq!(resolve("127.0.0.1"), Ok(format!("127.0.0.1:{DEFAULT_PORT}")));
+        assert_eq!(resolve("127.0.0.1:7000"), Ok("127.0.0.1:7000".to_string()));
+        assert_eq!(resolve("::1"), Ok(format!("[::1]:{DEFAULT_PORT}")));
+        assert_eq!(resolve("[::1]"), Ok(format!("[::1]:{DEFAULT_PORT}")));
+        assert_eq!(resolve("[::1]:7000"), Ok("[::1]:7000".to_string()));
+        assert_eq!(resolve("[::1]7000"), Err(ErrorKind::InvalidInput));
+        assert_eq!(resolve("127.0.0.1:port"), Err(ErrorKind::InvalidInput));
+        assert_eq!(resolve("[::1]:70000"), Err(ErrorKind::InvalidInput));
+        assert!(resolve_address("localhost:7000").is_ok_and(|addrs| addrs.iter().all(|addr| addr.port() == 7000 && addr.ip().is_loopback())));
+
+        assert_eq!(address_host("example.com:7000"), "example.com");
+        assert_eq!(address_host("example.com"), "example.com");
+        assert_eq!(address_host("[::1]:7000"), "::1");
+        assert_eq!(address_host("::1"), "::1");
+    }
+
+    /// The characters of the row of the buffer without the trailing spaces
+    fn row(buffer: &Buffer, y: usize) -> String {
+        let cells = &buffer.cells[y*buffer.width..(y + 1)*buffer.width];
+        let row: String = cells.iter().map(|cell| cell.ch).filter(|ch| *ch != WIDE_TAIL).collect();
+        row.trim_end().to_string()
+    }
+
+    fn render(chat: &mut ChatLog, w: usize, h: usize) -> Buffer {
+        let theme = Theme::default();
+        let mut buffer = Buffer::new(w, h, theme.blank());
+        chat.render(&mut buffer, Rect { x: 0, y: 0, w, h }, &[], &theme);
+        buffer
+    }
+
+    #[test]
+    fn stamps_are_kept_aside() {
+        let mut links = Links::default();
+        let item = ChatItem::new("[23:59:59] <alice> hi".to_string(), Style::Message, &mut links);
+        assert_eq!(item.time.as_deref(), Some("23:59:59"));
+        assert_eq!(item.text, "<alice> hi");
+        assert_eq!(item.line(), "[23:59:59] <alice> hi");
+        let item = ChatItem::new("[not a stamp] hi".to_string(), Style::Info, &mut links);
+        assert_eq!(item.time, None);
+        assert_eq!(item.line(), "[not a stamp] hi");
+
+        let mut chat = ChatLog::default();
+        chat.push("[23:59:59] <alice> hi".to_string(), Style::Message);
+        chat.push("[00:00:00] <bob> hi".to_string(), Style::Message);
+        let buffer = render(&mut chat, 30, 2);
+        assert_eq!(row(&buffer, 0), "23:59:59 <alice> hi");
+        assert_eq!(row(&buffer, 1), "00:00:00 <bob> hi");
+        assert_eq!(buffer.cells[0].fg, Theme::default().dim);
+        chat.timestamps = false;
+        let buffer = render(&mut chat, 30, 2);
+        assert_eq!(row(&buffer, 0), "<alice> hi");
+    }
+
+    /// Feeds the frames to the Client as if they came from the Server
+    fn receive(client: &mut Client, frames: &[&str]) {
+        for frame in frames {
+            client.frame_received(frame.as_bytes());
+        }
+    }
+
+    /// The lines of the chat as they are saved, with their styles
+    fn chat_lines(chat: &ChatLog) -> Vec<(Style, String)> {
+        chat.items.iter().map(|item| (item.style, item.line())).collect()
+    }
+
+    #[test]
+    fn missed_lines_are_counted() {
+        let mut client = Client::default();
+        receive(&mut client, &[
+            "\u{1}5",
+            "\u{1}6 [10:00:00] <alice> one",
+            "\u{1}9 [10:00:01] <alice> four",
+            // NOTE: the shadowed clients see the number of their echo again
+            "\u{1}9 [10:00:02] <bob> four again",
+            "\u{1}10 [10:00:03] <alice> five",
+        ]);
+        assert_eq!(chat_lines(&client.chat), vec![
+            (Style::Message, "[10:00:00] <alice> one".to_string()),
+            (Style::Error, "⚠ 2 messages missed".to_string()),
+            (Style::Message, "[10:00:01] <alice> four".to_string()),
+            (Style::Message, "[10:00:02] <bob> four again".to_string()),
+            (Style::Message, "[10:00:03] <alice> five".to_string()),
+        ]);
+
+        // NOTE: another room starts its own numbering, which is announced with the bare number
+        client.chat = ChatLog::default();
+        receive(&mut client, &["\u{1}100", "\u{1}101 * bob joined #other", "\u{1}1", "\u{1}2 hello"]);
+        assert!(chat_lines(&client.chat).iter().all(|(style, _)| *style != Style::Error));
+
+        // NOTE: the lines without the number don't count
+        receive(&mut client, &["You are #7", "\u{1}3 hi"]);
+        assert!(chat_lines(&client.chat).iter().all(|(style, _)| *style != Style::Error));
+    }
+
+    /// The Prompt with the text and the cursor put where `|` is
+    fn prompt(text: &str) -> Prompt {
+        Prompt {
+            buffer: text.chars().filter(|ch| *ch != '|').collect(),
+            cursor: text.chars().position(|ch| ch == '|').expect("cursor"),
+            ..Prompt::default()
+        }
+    }
+
+    /// The text of the Prompt with `|` where the cursor is
+    fn shown(prompt: &Prompt) -> String {
+        let mut text: String = prompt.before_cursor().iter().collect();
+        text.push('|');
+        text.extend(prompt.after_cursor());
+        text
+    }
+
+    type PromptEdit = fn(&mut Prompt);
+
+    #[test]
+    fn prompt_is_edited_at_the_boundaries() {
+        let cases: [(&str, PromptEdit, &str); 16] = [
+            ("hel|lo", Prompt::delete_char, "hel|o"),
+            ("hello|", Prompt::delete_char, "hello|"),
+            ("|hello", Prompt::delete_char, "|ello"),
+            ("|hello", Prompt::backspace, "|hello"),
+            ("hello world|", Prompt::delete_word_backward, "hello |"),
+            ("hello world  |", Prompt::delete_word_backward, "hello |"),
+            ("hello wo|rld", Prompt::delete_word_backward, "hello |rld"),
+            ("|hello", Prompt::delete_word_backward, "|hello"),
+            ("|hello world", Prompt::delete_word_forward, "| world"),
+            ("hello|  world", Prompt::delete_word_forward, "hello|"),
+            ("hello|", Prompt::delete_word_forward, "hello|"),
+            ("hel|lo", Prompt::home, "|hello"),
+            ("hel|lo", Prompt::end, "hello|"),
+            ("hel|lo", Prompt::delete_until_start, "|lo"),
+            ("|hello", Prompt::delete_until_start, "|hello"),
+            ("hel|lo", Prompt::delete_until_end, "hel|"),
+        ];
+        for (before, edit, after) in cases {
+            let mut prompt = prompt(before);
+            edit(&mut prompt);
+            assert_eq!(shown(&prompt), after, "{before:?}");
+        }
+    }
+
+    #[test]
+    fn prompt_scroll_follows_the_edits() {
+        let mut prompt = prompt("0123456789abcdefghij|");
+        prompt.sync_scroll_with_cursor(10);
+        assert_eq!(prompt.scroll, 10);
+        prompt.home();
+        prompt.sync_scroll_with_cursor(10);
+        assert_eq!(prompt.scroll, 0);
+        prompt.end();
+        prompt.sync_scroll_with_cursor(10);
+        assert_eq!(prompt.scroll, 10);
+        // NOTE: the scroll goes back once the text fits, so there is no `<` with nothing behind it
+        prompt.delete_word_backward();
+        prompt.sync_scroll_with_cursor(10);
+        assert_eq!(prompt.scroll, 0);
+    }
+
+    /// Every cell of the buffer row by row, the tails of the wide characters included
+    fn cells(buffer: &Buffer) -> Vec<String> {
+        buffer.cells.chunks(buffer.width).map(|row| row.iter().map(|cell| cell.ch).collect()).collect()
+    }
+
+    #[test]
+    fn cells_are_cut_at_the_end_of_row() {
+        let theme = Theme::default();
+        let mut buffer = Buffer::new(5, 3, theme.blank());
+        let text: Vec<char> = "hello world".chars().collect();
+        assert_eq!(buffer.put_cells(2, 1, &text, Color::Red, Color::Blue), 3);
+        assert_eq!(cells(&buffer), ["     ", "  hel", "     "]);
+        assert_eq!(buffer.cells[7], Cell { ch: 'h', fg: Color::Red, bg: Color::Blue, underline: false });
+        assert_eq!(buffer.cells[10], theme.blank());
+
+        assert_eq!(buffer.put_cells(0, 2, &text[..5], Color::Red, Color::Blue), 5);
+        assert_eq!(buffer.put_cells(5, 0, &text, Color::Red, Color::Blue), 0);
+        assert_eq!(cells(&buffer), ["     ", "  hel", "hello"]);
+
+        buffer.underline(3, 1, 10);
+        assert!(buffer.cells[8..10].iter().all(|cell| cell.underline));
+        assert!(!buffer.cells[7].underline);
+        assert!(buffer.cells[10..].iter().all(|cell| !cell.underline));
+    }
+
+    #[test]
+    fn wide_characters_take_two_cells() {
+        let theme = Theme::default();
+        let mut buffer = Buffer::new(5, 2, theme.blank());
+        let text: Vec<char> = "日本\u{200b}語".chars().collect();
+        assert_eq!(buffer.put_cells(0, 0, &text, Color::Red, Color::Blue), 4);
+        assert_eq!(cells(&buffer)[0], "日\0本\0 ");
+        // NOTE: the wide character does not fit into the last column
+        assert_eq!(buffer.put_cells(4, 1, &text, Color::Red, Color::Blue), 0);
+        // NOTE: the half of a wide character is not left on its own
+        buffer.put_cell(1, 0, 'x', Color::Red, Color::Blue);
+        buffer.put_cell(2, 0, 'y', Color::Red, Color::Blue);
+        assert_eq!(cells(&buffer)[0], " xy  ");
+
+        let mut prompt = prompt("日本語テスト|");
+        let mut output = Vec::new();
+        prompt.sync_terminal_cursor(&mut output, 0, 0, 10).expect("cursor");
+        assert_eq!(prompt.scroll, 2);
+        assert_eq!(String::from_utf8_lossy(&output), "\x1b[1;10H");
+        // NOTE: the prompt is scrolled to 語, the click on the right half of テ puts the cursor after it
+        prompt.click(0, 10, 4);
+        assert_eq!(shown(&prompt), "日本語テ|スト");
+        prompt.click(0, 10, 3);
+        assert_eq!(shown(&prompt), "日本語|テスト");
+
+        let mut chat = ChatLog::default();
+        chat.push("<alice> 🎉 hi".to_string(), Style::Message);
+        let buffer = render(&mut chat, 20, 1);
+        assert_eq!(row(&buffer, 0), "<alice> 🎉 hi");
+        assert_eq!(buffer.cells[9].ch, WIDE_TAIL);
+    }
+
+    /// The variants the Completion cycles through, starting from the prompt with `|` at the cursor
+    fn completions(text: &str, nicks: &[&str], forward: bool) -> Option<Vec<String>> {
+        let prompt = prompt(text);
+        let aliases = HashMap::from([("home".to_string(), "connect localhost".to_string())]);
+        let nicks: Vec<String> = nicks.iter().map(|nick| nick.to_string()).collect();
+        let mut completion = Completion::start(prompt.before_cursor(), prompt.after_cursor(), &aliases, &nicks)?;
+        let variants = (0..=completion.matches.len()).map(|_| {
+            let (mut line, cursor) = completion.cycle(forward);
+            line.insert(line.char_indices().nth(cursor).map(|(i, _)| i).unwrap_or(line.len()), '|');
+            line
+        }).collect();
+        Some(variants)
+    }
+
+    #[test]
+    fn completion_cycles_through_the_matches() {
+        assert_eq!(completions("/con|", &[], true).unwrap(), ["/connect|", "/connect!|", "/con|"]);
+        assert_eq!(completions("/con|", &[], false).unwrap(), ["/connect!|", "/connect|", "/con|"]);
+        assert_eq!(completions("/q| now", &[], true).unwrap(), ["/quit| now", "/quit!| now", "/q| now"]);
+        assert_eq!(completions("/ho|", &[], true).unwrap(), ["/home|", "/ho|"]);
+        // NOTE: the aliases of the commands complete to the full names
+        assert_eq!(completions("/m|", &[], true).unwrap(), ["/msg|", "/mentions|", "/mouse|", "/m|"]);
+        assert_eq!(completions("/help disc|", &[], true).unwrap(), ["/help disconnect|", "/help disc|"]);
+
+        assert_eq!(completions("al|", &["Alice", "bob", "alan"], true).unwrap(), ["Alice: |", "alan: |", "al|"]);
+        assert_eq!(completions("hi AL| there", &["Alice"], true).unwrap(), ["hi Alice| there", "hi AL| there"]);
+        assert_eq!(completions("/msg b|", &["Alice", "bob"], true).unwrap(), ["/msg bob|", "/msg b|"]);
+
+        assert!(completions("|", &["Alice"], true).is_none());
+        assert!(completions("hi |", &["Alice"], true).is_none());
+        assert!(completions("/xyz|", &[], true).is_none());
+        assert!(completions("/msg bob hi a|", &["Alice"], true).is_none());
+        assert!(completions("/set b|", &[], true).is_none());
+    }
+
+    /// The Client with the connection to a listener nobody accepts, which is enough for it to
+    /// consider itself online
+    fn online_client(address: &str) -> Client {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("listener");
+        let stream = TcpStream::connect(listener.local_addr().expect("address")).expect("connect");
+        Client {
+            connection: Connection::Online(Stream::Plain(stream)),
+            target: Some(Target { address: address.to_string(), token: "token".to_string(), pinned: None }),
+            ..Client::default()
+        }
+    }
+
+    #[test]
+    fn status_bar_tells_who_and_where() {
+        assert_eq!(Client::default().status(), (vec!["Offline".to_string()], vec![]));
+
+        let mut client = online_client("example.com:6969");
+        client.nick = Some("alice".to_string());
+        client.away = true;
+        client.unread = 3;
+        let (left, right) = client.status();
+        assert_eq!(left, ["Online as alice (away)", "3 unread"]);
+        assert_eq!(right, ["example.com:6969"]);
+
+        let theme = Theme::default();
+        let mut buffer = Buffer::new(60, 1, theme.blank());
+        status_bar(&mut buffer, &theme, &left, &right, 0, 0, 60);
+        assert_eq!(row(&buffer, 0), format!("Online as alice (away) | 3 unread{:>27}", "example.com:6969"));
+        assert!(buffer.cells.iter().all(|cell| cell.bg == theme.status_bg));
+        // NOTE: the right side goes away first when there is no room for both
+        let mut buffer = Buffer::new(40, 1, theme.blank());
+        status_bar(&mut buffer, &theme, &left, &right, 0, 0, 40);
+        assert_eq!(row(&buffer, 0), "Online as alice (away) | 3 unread");
+    }
+
+    #[test]
+    fn chat_is_cleared() {
+        let mut client = Client::default();
+        for i in 0..5 {
+            client.chat.push(format!("line {i}"), Style::Message);
+        }
+        client.chat.height = 2;
+        client.chat.scroll_up(2);
+        client.chat.push("line 5".to_string(), Style::Message);
+        assert_eq!(client.chat.unseen, 1);
+        render(&mut client.chat, 20, 2);
+
+        clear_command(&mut client, &["2".to_string()]);
+        assert_eq!(chat_lines(&client.chat), [(Style::Message, "line 4".to_string()), (Style::Message, "line 5".to_string())]);
+        assert_eq!((client.chat.scroll, client.chat.unseen), (0, 0));
+        assert!(client.chat.changed);
+        // NOTE: the lines keep their numbers, which the pending messages refer to
+        assert_eq!(client.chat.next_index(), 6);
+
+        clear_command(&mut client, &[]);
+        assert_eq!(client.chat.len(), 0);
+        client.chat.push("after".to_string(), Style::Message);
+        let buffer = render(&mut client.chat, 20, 3);
+        assert_eq!(row(&buffer, 0), "after");
+
+        clear_command(&mut client, &["many".to_string()]);
+        assert_eq!(chat_lines(&client.chat)[1].0, Style::Error);
+    }
+
+    #[test]
+    fn scrollback_is_capped() {
+        let mut chat = ChatLog::default();
+        for i in 0..2*SCROLLBACK_LIMIT {
+            chat.push(format!("line {i}"), Style::Message);
+        }
+        assert_eq!(chat.len(), chat.capacity());
+        // NOTE: the one more line is the notice about the limit, which comes only once
+        assert!(chat.overflowed);
+        assert_eq!(chat.next_index(), 2*SCROLLBACK_LIMIT + 1);
+        let buffer = render(&mut chat, 20, 2);
+        assert!(row(&buffer, 1).starts_with(&format!("line {n} ", n = 2*SCROLLBACK_LIMIT - 1)));
+
+        // NOTE: scrolled all the way up the view stays at the oldest line that is left
+        let mut chat = ChatLog { capacity: 10, ..ChatLog::default() };
+        for i in 0..10 {
+            chat.push(format!("line {i}"), Style::Message);
+        }
+        chat.height = 3;
+        chat.scroll_up(100);
+        assert_eq!(chat.scroll, 7);
+        for i in 10..15 {
+            chat.push(format!("line {i}"), Style::Message);
+        }
+        assert_eq!(chat.len(), 10);
+        assert_eq!(chat.scroll, 7);
+        let buffer = render(&mut chat, 20, 3);
+        assert!(row(&buffer, 0).starts_with("line 6 "));
+    }
+
+    #[test]
+    fn mentions_are_whole_words() {
+        let mut chat = ChatLog::default();
+        assert!(!chat.push_message("<bob> hi alice".to_string(), &[]));
+        chat.set_nick(Some("Alice"));
+        let cases = [
+            ("<bob> hi alice", true),
+            ("[10:00:00] <bob> @ALICE, look", true),
+            ("<bob> alice_ is someone else", false),
+            ("<bob> alicent", false),
+            ("<bob> malice", false),
+            // NOTE: the nick of the author does not count
+            ("<alice> hi", false),
+        ];
+        for (message, mention) in cases {
+            assert_eq!(chat.push_message(message.to_string(), &[]), mention, "{message:?}");
+        }
+        assert_eq!(chat.mentions, [1, 2]);
+        assert_eq!(chat.items[1].style, Style::Mention);
+        assert_eq!(chat.items[3].style, Style::Message);
+        assert_eq!(chat.last_mentions(1).iter().map(|item| item.line()).collect::<Vec<_>>(), ["[10:00:00] <bob> @ALICE, look"]);
+
+        chat.set_nick(Some("bob"));
+        assert!(chat.push_message("<carol> bob?".to_string(), &[]));
+        assert!(!chat.push_message("<carol> alice?".to_string(), &[]));
+
+        // NOTE: each jump goes further back and starts over from the latest one after the oldest
+        chat.height = 1;
+        let bottoms: Vec<usize> = (0..4).map(|_| {
+            assert!(chat.jump_to_mention());
+            chat.next_index() - 1 - chat.scroll
+        }).collect();
+        assert_eq!(bottoms, [7, 2, 1, 7]);
+
+        let mut client = Client::default();
+        mentions_command(&mut client, &[]);
+        assert_eq!(chat_lines(&client.chat), [(Style::Info, "Nobody mentioned you yet".to_string())]);
+
+        // NOTE: taking back a queued message comes before the mention, which stays where it is
+        client.chat.set_nick(Some("alice"));
+        client.send_message("queued".to_string());
+        client.chat.push_message("<bob> alice?".to_string(), &[]);
+        client.chat.push("more".to_string(), Style::Info);
+        client.unqueue_last();
+        mentions_command(&mut client, &["1".to_string()]);
+        assert_eq!(chat_lines(&client.chat).pop(), Some((Style::Mention, "<bob> alice?".to_string())));
+        client.chat.height = 1;
+        client.chat.scroll_to_bottom();
+        assert!(client.chat.jump_to_mention());
+        let bottom = client.chat.next_index() - 1 - client.chat.scroll;
+        assert_eq!(client.chat.items[bottom - client.chat.dropped].line(), "<bob> alice?");
+    }
+
+    #[test]
+    fn links_are_found() {
+        let cases: [(&str, &[&str]); 9] = [
+            ("see https://example.com", &["https://example.com"]),
+            ("http://a.b/c?d=e#f and https://x.y", &["http://a.b/c?d=e#f", "https://x.y"]),
+            ("(https://example.com/wiki/Rust_(language)).", &["https://example.com/wiki/Rust_(language)"]),
+            ("is it https://example.com?", &["https://example.com"]),
+            ("\"https://example.com\",", &["https://example.com"]),
+            ("https://", &[]),
+            ("xhttp://example.com", &[]),
+            ("httpx://example.com http", &[]),
+            ("ftp://example.com", &[]),
+        ];
+        for (text, expected) in cases {
+            let links: Vec<&str> = find_links(text).into_iter().map(|range| &text[range]).collect();
+            assert_eq!(links, expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn links_are_numbered_for_open() {
+        let mut client = Client::default();
+        open_command(&mut client, &[]);
+        assert_eq!(chat_lines(&client.chat)[0], (Style::Error, "There are no links in the chat yet".to_string()));
+
+        let long = format!("https://example.com/{path}", path = "a".repeat(2*LINK_DISPLAY_LIMIT));
+        client.chat.push(format!("<bob> {long} and https://one.com"), Style::Message);
+        client.chat.push("<bob> https://one.com again".to_string(), Style::Message);
+        let item = client.chat.items.back().expect("line");
+        let spans: Vec<&str> = item.spans.iter().map(|span| span.text.as_str()).collect();
+        assert_eq!(spans, ["<bob> ", "https://one.com", " [2]", " again"]);
+        assert!(item.spans[1].kind == SpanKind::Link);
+
+        // NOTE: the long link is cut on the screen only
+        let item = &client.chat.items[1];
+        assert_eq!(item.spans[1].text.chars().count(), LINK_DISPLAY_LIMIT);
+        assert!(item.spans[1].text.ends_with('…'));
+        assert_eq!(client.chat.links.get(Some(1)), Some(long.as_str()));
+        assert_eq!(client.chat.links.get(None), Some("https://one.com"));
+
+        open_command(&mut client, &["3".to_string()]);
+        open_command(&mut client, &["one".to_string()]);
+        let errors: Vec<String> = chat_lines(&client.chat).into_iter().filter(|(style, _)| *style == Style::Error).map(|(_, line)| line).collect();
+        assert_eq!(errors[1], "There is no link [3]");
+        assert!(errors[2].starts_with("Usage: /open"));
+
+        let mut links = Links::default();
+        for i in 0..LINKS_LIMIT + 1 {
+            links.add(&format!("https://{i}.com"));
         }
-        if let Some(y) = h.checked_sub(1) {
-            let x = 1;
-            if let Some(w) = w.checked_sub(1) {
-                prompt.render(&mut buf_curr, x, y as usize, w as usize);
+        assert_eq!(links.get(Some(1)), None);
+        assert_eq!(links.get(Some(LINKS_LIMIT + 1)), Some(format!("https://{LINKS_LIMIT}.com").as_str()));
+    }
+
+    #[test]
+    fn nick_colors_are_deterministic() {
+        // NOTE: the published FNV-1a values, so the colors stay the same with any build
+        assert_eq!(nick_color(""), 0xcbf29ce484222325);
+        assert_eq!(nick_color("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(nick_color("alice"), nick_color("alice"));
+        assert_ne!(nick_color("alice"), nick_color("bob"));
+        for theme in THEMES {
+            for color in theme.nicks {
+                assert!(![theme.background, theme.info, theme.error, theme.own_message, theme.mention, theme.link].contains(color), "{name}: {color:?}", name = theme.name);
             }
-            buf_curr.put_cell(0, y as usize, '-', Color::White, Color::Black);
         }
 
-        apply_patches(&mut stdout, &buf_prev.diff(&buf_curr))?;
+        let theme = Theme::default();
+        let mut chat = ChatLog::default();
+        chat.push_message("<alice> hi".to_string(), &[]);
+        chat.push("you: hi".to_string(), Style::Own);
+        let buffer = render(&mut chat, 20, 2);
+        assert_eq!(row(&buffer, 0), "<alice> hi");
+        assert!(buffer.cells[..7].iter().all(|cell| cell.fg == theme.nick(nick_color("alice"))));
+        assert_eq!(buffer.cells[8].fg, theme.foreground);
+        assert!(buffer.cells[20..27].iter().all(|cell| cell.fg == theme.own_message));
+    }
+
+    fn press(search: &mut Search, chat: &mut ChatLog, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        search.key(KeyEvent::new(code, modifiers), chat)
+    }
+
+    #[test]
+    fn search_steps_through_the_matches() {
+        let chars = |text: &str| text.chars().collect::<Vec<_>>();
+        assert_eq!(find_ignore_case(&chars("Foo foo FOOfoo"), &chars("fOo")), [0, 4, 8, 11]);
+        assert_eq!(find_ignore_case(&chars("ÄÖ äö"), &chars("äö")), [0, 3]);
+        assert_eq!(find_ignore_case(&chars("aaa"), &chars("aa")), [0]);
+        assert!(find_ignore_case(&chars("foo"), &[]).is_empty());
+
+        let mut chat = ChatLog::default();
+        for i in 0..10 {
+            let text = if i%3 == 2 { "Foo" } else { "bar" };
+            chat.push(format!("{i} {text}"), Style::Message);
+        }
+        chat.height = 3;
+        chat.scroll_up(1);
+        let mut search = Search::new(&chat);
+        assert!(search.status().starts_with("Search:"));
+        for x in "FOO".chars() {
+            assert!(press(&mut search, &mut chat, KeyCode::Char(x), KeyModifiers::NONE));
+        }
+        assert_eq!(search.matches, [2, 5, 8]);
+        assert_eq!(search.status(), "match 3/3");
+        assert_eq!(chat.scroll, 0);
+        // NOTE: the match goes to the top of the view, as far as there are lines below it
+        press(&mut search, &mut chat, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!((search.status(), chat.scroll), ("match 1/3".to_string(), 5));
+        press(&mut search, &mut chat, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!((search.status(), chat.scroll), ("match 2/3".to_string(), 2));
+        press(&mut search, &mut chat, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        press(&mut search, &mut chat, KeyCode::Char('r'), KeyModifiers::CONTROL);
+        assert_eq!((search.status(), chat.scroll), ("match 3/3".to_string(), 0));
+
+        press(&mut search, &mut chat, KeyCode::Char('x'), KeyModifiers::NONE);
+        assert_eq!((search.status(), chat.scroll), ("no matches".to_string(), 1));
+        press(&mut search, &mut chat, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(search.status(), "match 3/3");
+        assert!(!press(&mut search, &mut chat, KeyCode::Esc, KeyModifiers::NONE));
+        assert_eq!(chat.scroll, 1);
+
+        let theme = Theme::default();
+        let mut buffer = Buffer::new(10, 3, theme.blank());
+        chat.render(&mut buffer, Rect { x: 0, y: 0, w: 10, h: 3 }, &chars("foo"), &theme);
+        assert!(row(&buffer, 2).starts_with("8 Foo "));
+        assert!(buffer.cells[22..25].iter().all(|cell| cell.bg != theme.background));
+        assert_eq!(buffer.cells[20].bg, theme.background);
+    }
+
+    #[test]
+    fn kills_in_a_row_are_yanked_together() {
+        let killed = |edited: &Prompt| edited.kill_buffer.iter().collect::<String>();
+
+        let mut edited = prompt("say hello big world|");
+        edited.delete_word_backward();
+        edited.delete_word_backward();
+        assert_eq!(shown(&edited), "say hello |");
+        assert_eq!(killed(&edited), "big world");
+        edited.home();
+        edited.yank();
+        assert_eq!(shown(&edited), "big world|say hello ");
+
+        let mut edited = prompt("one two| three four");
+        edited.delete_word_forward();
+        edited.delete_word_forward();
+        assert_eq!(killed(&edited), " three four");
+        // NOTE: Ctrl+K at the end changes nothing and keeps what was killed
+        edited.delete_until_end();
+        assert_eq!(killed(&edited), " three four");
+        edited.left_word();
+        edited.yank();
+        assert_eq!(shown(&edited), "one three four| two");
+
+        // NOTE: a kill in the other direction or after a move starts over
+        let mut edited = prompt("abc def| ghi");
+        edited.delete_until_end();
+        edited.delete_word_backward();
+        assert_eq!(killed(&edited), "def");
+        edited.left_char();
+        edited.delete_until_start();
+        assert_eq!(killed(&edited), "abc");
+        assert_eq!(shown(&edited), "| ");
+
+        let mut edited = prompt("|text");
+        edited.yank();
+        assert_eq!(shown(&edited), "|text");
+    }
+
+    #[test]
+    fn undo_goes_word_by_word() {
+        let mut edited = Prompt::default();
+        for x in "hello world".chars() {
+            edited.insert(x);
+        }
+        edited.undo();
+        assert_eq!(shown(&edited), "hello |");
+        edited.undo();
+        assert_eq!(shown(&edited), "|");
+        edited.undo();
+        assert_eq!(shown(&edited), "|");
+        edited.redo();
+        edited.redo();
+        assert_eq!(shown(&edited), "hello world|");
+        edited.redo();
+        assert_eq!(shown(&edited), "hello world|");
+
+        // NOTE: Backspace pressed again is the same step, a move in between starts a new one
+        edited.backspace();
+        edited.backspace();
+        edited.left_char();
+        edited.backspace();
+        assert_eq!(shown(&edited), "hello w|r");
+        edited.undo();
+        assert_eq!(shown(&edited), "hello wo|r");
+        edited.undo();
+        assert_eq!(shown(&edited), "hello world|");
+
+        // NOTE: a new change forgets what could be redone
+        edited.undo();
+        edited.insert('!');
+        edited.redo();
+        assert_eq!(shown(&edited), "hello !|");
+
+        // NOTE: the paste and the kill are one step each, and so is the line sent with Enter
+        edited.insert_str("pasted text");
+        edited.delete_word_backward();
+        edited.clear();
+        assert_eq!(shown(&edited), "|");
+        edited.undo();
+        assert_eq!(shown(&edited), "hello !pasted |");
+        edited.undo();
+        assert_eq!(shown(&edited), "hello !pasted text|");
+        edited.undo();
+        assert_eq!(shown(&edited), "hello !|");
+
+        let mut edited = Prompt::default();
+        for _ in 0..2*UNDO_LIMIT {
+            edited.insert('x');
+            edited.left_char();
+        }
+        while !edited.undo.is_empty() {
+            edited.undo();
+        }
+        assert_eq!(edited.buffer.len(), UNDO_LIMIT);
+    }
+
+    /// The arguments or the error of parse_arguments()
+    type Parsed = Result<&'static [&'static str], &'static str>;
+
+    #[test]
+    fn arguments_are_checked_against_the_spec() {
+        let parse = |name: &str, argument: &str| find_command(name).expect("command").parse_arguments(argument);
+        let cases: [(&str, &str, Parsed); 13] = [
+            ("msg", "bob  hi  there ", Ok(&["bob", "hi  there"])),
+            ("msg", "\"bob\" hi", Ok(&["bob", "hi"])),
+            ("msg", "bob", Err("missing <text>")),
+            ("msg", "  ", Err("missing <nick>")),
+            ("save", "\"my chat.log\"", Ok(&["my chat.log"])),
+            ("save", "\"\"", Ok(&[""])),
+            ("save", "\"my chat.log", Err("unclosed quote in [path]")),
+            ("save", "\"my\"chat.log", Err("expected a space after the closing quote of [path]")),
+            ("save", "a b", Err("unexpected `b`")),
+            ("connect", "", Ok(&[])),
+            ("connect", "localhost @TOKEN", Ok(&["localhost", "@TOKEN"])),
+            ("disconnect", "now please", Err("unexpected `now please`")),
+            ("disconnect", " ", Ok(&[])),
+        ];
+        for (name, argument, expected) in cases {
+            let expected = expected.map(|args| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>()).map_err(str::to_string);
+            assert_eq!(parse(name, argument), expected, "/{name} {argument}");
+        }
+        assert_eq!(find_command("msg").expect("msg").signature(), "/msg <nick> <text>");
+        assert_eq!(find_command("m").expect("msg").hint(1).as_deref(), Some("<text>"));
+    }
+
+    #[test]
+    fn invalid_utf8_is_told_once() {
+        let mut client = Client::default();
+        client.frame_received(b"<bob> caf\xc3");
+        client.frame_received(b"<bob> \xff\xfe");
+        assert_eq!(chat_lines(&client.chat), [
+            (Style::Error, "The server sent text that is not valid UTF-8, the broken characters are shown as \u{FFFD}".to_string()),
+            (Style::Message, "<bob> caf\u{FFFD}".to_string()),
+            (Style::Message, "<bob> \u{FFFD}\u{FFFD}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn sgr_colors_are_taken_on_request() {
+        let cases = [
+            ("31", None, Some(Color::DarkRed)),
+            ("1;91", None, Some(Color::Red)),
+            ("0", Some(Color::Red), None),
+            ("", Some(Color::Red), None),
+            ("39", Some(Color::Red), None),
+            ("1", Some(Color::Red), Some(Color::Red)),
+            ("38;5;208", None, Some(Color::AnsiValue(208))),
+            ("38;2;1;2;3", None, Some(Color::Rgb { r: 1, g: 2, b: 3 })),
+            ("48;5;208", Some(Color::Red), Some(Color::Red)),
+            ("38;2;1", None, None),
+            ("31;42;32", None, Some(Color::DarkGreen)),
+        ];
+        for (params, color, expected) in cases {
+            assert_eq!(sgr_color(params, color), expected, "{params:?}");
+        }
+
+        let mut client = Client::default();
+        client.frame_received(b"<bob> \x1b[31mred\x1b[0m plain");
+        client.settings.ansi = true;
+        client.frame_received(b"<bob> \x1b[31mred\x1b[0m plain");
+        assert_eq!(chat_lines(&client.chat), [
+            (Style::Message, "<bob> red plain".to_string()),
+            (Style::Message, "<bob> red plain".to_string()),
+        ]);
+        let kinds = |item: &ChatItem| item.spans.iter().map(|span| (span.text.clone(), span.kind == SpanKind::Colored(Color::DarkRed))).collect::<Vec<_>>();
+        assert_eq!(kinds(&client.chat.items[0]), [("<bob>".to_string(), false), (" red plain".to_string(), false)]);
+        assert_eq!(kinds(&client.chat.items[1]), [("<bob>".to_string(), false), (" ".to_string(), false), ("red".to_string(), true), (" plain".to_string(), false)]);
+    }
+
+    #[test]
+    fn scrollbar_thumb_is_in_proportion() {
+        let cases = [
+            (10, 10, 0, None),
+            (5, 10, 0, None),
+            (100, 0, 0, None),
+            (100, 10, 0, Some(0..1)),
+            (100, 10, 90, Some(9..10)),
+            (100, 10, 1000, Some(9..10)),
+            // NOTE: a line away from either end is not at the end of the bar
+            (100, 10, 1, Some(1..2)),
+            (100, 10, 89, Some(8..9)),
+            (20, 10, 5, Some(3..8)),
+            (11, 10, 1, Some(1..10)),
+            (1_000_000, 3, 500_000, Some(1..2)),
+        ];
+        for (total, height, top, thumb) in cases {
+            assert_eq!(scrollbar_thumb(total, height, top), thumb, "{total} {height} {top}");
+        }
+
+        let mut chat = ChatLog::default();
+        for i in 0..3 {
+            chat.push(format!("line {i} is long"), Style::Message);
+        }
+        let buffer = render(&mut chat, 10, 3);
+        assert_eq!(row(&buffer, 0), "line 0 is");
+        chat.push("line 3 is long".to_string(), Style::Message);
+        let buffer = render(&mut chat, 10, 3);
+        assert_eq!(cells(&buffer), ["line 1 is│", "line 2 is█", "line 3 is█"]);
+        chat.scroll_up(1);
+        let buffer = render(&mut chat, 10, 3);
+        assert_eq!(cells(&buffer), ["line 0 is█", "line 1 is█", "line 2 is│"]);
+        assert!(buffer.cells[9].fg == Theme::default().dim);
+    }
+
+    #[test]
+    fn ban_countdown_is_read_from_the_notice() {
+        let secs = Duration::from_secs_f64;
+        let cases = [
+            ("You are banned MF: 57.3 secs left", secs(57.3)),
+            ("You are banned MF: flooding the chat, 120 secs left", secs(120.0)),
+            ("You are banned MF: 5s left", secs(5.0)),
+            ("You are banned MF: 1 sec left", secs(1.0)),
+            ("You are banned", BAN_LIMIT),
+            ("disconnected: banned for sending too long messages", BAN_LIMIT),
+            ("You are banned MF: 10 minutes left", BAN_LIMIT),
+            ("You are banned MF: NaN secs left", BAN_LIMIT),
+            ("You are banned MF: 1e999 secs left", BAN_LIMIT),
+            ("You are banned MF: -5 secs left", BAN_LIMIT),
+        ];
+        for (notice, left) in cases {
+            assert_eq!(ban_left(notice), left, "{notice:?}");
+        }
+
+        let address = "example.com:6969".to_string();
+        let mut client = Client {
+            target: Some(Target { address: address.clone(), token: "token".to_string(), pinned: None }),
+            ..Client::default()
+        };
+        client.notice_received("You are banned MF: 60 secs left");
+        assert!(matches!(client.connection, Connection::Refused("banned")));
+        assert!(client.status().0[0].starts_with("Offline, banned for "));
+        connect_command(&mut client, &[address]);
+        assert!(matches!(client.connection, Connection::Refused(_)));
+        let (style, line) = chat_lines(&client.chat).pop().expect("refusal");
+        assert_eq!(style, Style::Error);
+        assert!(line.starts_with("You are banned from example.com:6969 for "), "{line}");
+
+        client.banned_until = Some(Instant::now());
+        client.poll_ban();
+        assert!(matches!(client.connection, Connection::Offline));
+        assert_eq!(chat_lines(&client.chat).pop(), Some((Style::Info, "The ban on example.com:6969 is over. Use /reconnect to get back".to_string())));
+    }
+
+    #[test]
+    fn filter_hides_lines_without_dropping_them() {
+        let mut client = Client::default();
+        let lines = [
+            ("<bob> hi", Style::Message),
+            ("note", Style::Info),
+            ("* bob joined", Style::System),
+            ("oops", Style::Error),
+            ("you: hi", Style::Own),
+            ("<bob> alice!", Style::Mention),
+        ];
+        for (line, style) in lines {
+            client.chat.push(line.to_string(), style);
+        }
+        let rows = |chat: &mut ChatLog| {
+            let buffer = render(chat, 20, 7);
+            (0..7).map(|y| row(&buffer, y)).filter(|row| !row.is_empty()).collect::<Vec<_>>()
+        };
+
+        client.chat.scroll = 1;
+        filter_command(&mut client, &["errors".to_string()]);
+        assert_eq!(client.chat.scroll, 0);
+        assert_eq!(rows(&mut client.chat), ["oops"]);
+        assert_eq!(client.status().0[1], "showing errors only");
+        filter_command(&mut client, &["info".to_string()]);
+        assert_eq!(rows(&mut client.chat), ["note", "* bob joined"]);
+        filter_command(&mut client, &["warnings".to_string()]);
+        assert_eq!(rows(&mut client.chat), ["note", "* bob joined"]);
+        filter_command(&mut client, &["all".to_string()]);
+        assert_eq!(client.chat.len(), 7);
+        let shown = rows(&mut client.chat);
+        assert_eq!(shown[..6], lines.map(|(line, _)| line));
+        assert!(shown[6].starts_with("Usage: /filter"));
+
+        // NOTE: the colors come from the Theme at the time of rendering
+        let light = Theme::find("light").expect("light theme");
+        let mut buffer = Buffer::new(20, 7, light.blank());
+        client.chat.render(&mut buffer, Rect { x: 0, y: 0, w: 20, h: 7 }, &[], light);
+        let colors: Vec<Color> = (0..7).map(|y| buffer.cells[y*20].fg).collect();
+        assert_eq!(colors[..3], [light.foreground, light.info, light.info]);
+        assert_eq!(colors[3..6], [light.error, light.own_message, light.mention]);
+        assert_eq!(colors[6], light.error);
+    }
+
+    #[test]
+    fn status_bar_is_cut_with_ellipsis() {
+        let theme = Theme::default();
+        let segments = |segments: &[&str]| segments.iter().map(|segment| segment.to_string()).collect::<Vec<_>>();
+        let cases: [(&[&str], &[&str], usize, &str); 9] = [
+            (&["Online as alice"], &[], 10, "Online as…"),
+            (&["Online"], &[], 10, "Online    "),
+            (&["日本語のステータス"], &[], 7, "日\0本\0語\0…"),
+            // NOTE: the half of a wide character does not fit before the ellipsis
+            (&["日本語のステータス"], &[], 6, "日\0本\0… "),
+            (&["a"], &["b", "c"], 10, "a    b | c"),
+            (&["a"], &["b", "c"], 6, "a    b"),
+            (&["a", "b"], &["日本"], 10, "a | b 日\0本\0"),
+            (&["long"], &[], 1, "…"),
+            (&["long"], &["right"], 0, ""),
+        ];
+        for (left, right, w, expected) in cases {
+            let mut buffer = Buffer::new(w + 2, 1, theme.blank());
+            buffer.put_cells(0, 0, &['x'; 16][..w + 2], Color::Red, Color::Red);
+            status_bar(&mut buffer, &theme, &segments(left), &segments(right), 1, 0, w);
+            assert_eq!(cells(&buffer)[0], format!("x{expected}x"), "{left:?} {right:?} {w}");
+            assert!(buffer.cells[1..w + 1].iter().all(|cell| cell.fg == theme.status_fg && cell.bg == theme.status_bg));
+        }
+    }
+
+    #[test]
+    fn pastes_are_one_line() {
+        let cases = [
+            ("plain", "plain"),
+            ("one\ntwo\r\nthree\rfour", "one two three four"),
+            ("tab\tted", "tab ted"),
+            ("\x1b[31mred\x07\x00", "[31mred"),
+            ("\u{85}next\u{9B}", "next"),
+            ("日本語\n", "日本語 "),
+        ];
+        for (text, pasted) in cases {
+            assert_eq!(sanitize_paste(text), (pasted.to_string(), false), "{text:?}");
+        }
+        let (pasted, cut) = sanitize_paste(&"x".repeat(PASTE_LIMIT));
+        assert_eq!((pasted.len(), cut), (PASTE_LIMIT, false));
+        // NOTE: a character is never split at the limit
+        let (pasted, cut) = sanitize_paste(&"é".repeat(PASTE_LIMIT));
+        assert_eq!((pasted.len(), cut), (PASTE_LIMIT, true));
+        let (pasted, cut) = sanitize_paste(&format!("xx{long}", long = "日".repeat(PASTE_LIMIT)));
+        assert_eq!((pasted.len(), cut), (PASTE_LIMIT - 2, true));
+    }
+
+    #[test]
+    fn unqueued_lines_keep_the_others_in_place() {
+        let mut client = Client::default();
+        client.chat.set_nick(Some("alice"));
+        client.send_message("one".to_string());
+        client.send_message("two".to_string());
+        assert!(client.chat.push_message("<bob> hi alice".to_string(), &[]));
+        client.chat.push("Reconnected".to_string(), Style::Info);
+        client.chat.changed = false;
+
+        client.unqueue_last();
+        assert!(client.chat.changed);
+        assert_eq!(client.outbox.len(), 1);
+        assert_eq!(client.chat.last_mentions(1).iter().map(|item| item.line()).collect::<Vec<_>>(), ["<bob> hi alice"]);
+        assert_eq!(chat_lines(&client.chat)[1..], [
+            (Style::Pending, "you: one".to_string()),
+            (Style::Info, "Unqueued: two".to_string()),
+            (Style::Mention, "<bob> hi alice".to_string()),
+            (Style::Info, "Reconnected".to_string()),
+        ]);
+
+        outbox_command(&mut client, &["clear".to_string()]);
+        assert!(client.outbox.is_empty());
+        assert_eq!(chat_lines(&client.chat)[1], (Style::Info, "Unqueued: one".to_string()));
+        assert_eq!(client.chat.find(&['h', 'i']), [3]);
+    }
+
+    #[test]
+    fn token_is_masked_wherever_it_connects() {
+        let cases = [
+            ("/connect host TOKEN|", "/connect host *****"),
+            ("/c host\tTOKEN|", "/c host\t*****"),
+            ("/connect host \"TO KEN\"|", "/connect host \"******\""),
+            ("/connect host \"TO KEN|", "/connect host \"******"),
+            ("/connect! host TOKEN extra|", "/connect! host ***** extra"),
+            ("/alias home connect host TOKEN|", "/alias home connect host *****"),
+            ("/alias home /c host TOKEN|", "/alias home /c host *****"),
+            ("/alias \"home\" c host TOKEN|", "/alias \"home\" c host *****"),
+            ("/connect host @./TOKEN|", "/connect host @./TOKEN"),
+            ("/alias home connect host @./TOKEN|", "/alias home connect host @./TOKEN"),
+            ("/connect host|", "/connect host"),
+            ("/msg host TOKEN|", "/msg host TOKEN"),
+            ("connect host TOKEN|", "connect host TOKEN"),
+        ];
+        for (text, masked) in cases {
+            let edited = prompt(text);
+            assert_eq!(edited.shown().iter().collect::<String>(), masked, "{text:?}");
+            // NOTE: the masked lines are the ones left out of the history
+            assert_eq!(edited.secret().is_some(), edited.buffer.iter().collect::<String>() != masked, "{text:?}");
+        }
+
+        // NOTE: the asterisks are narrow, so the cursor goes by them rather than by the token
+        let mut edited = prompt("/connect host 日本|語");
+        let theme = Theme::default();
+        let mut buffer = Buffer::new(30, 1, theme.blank());
+        edited.render(&mut buffer, 0, 0, 30, &theme);
+        assert_eq!(row(&buffer, 0), " /connect host ***");
+        let mut output = Vec::new();
+        edited.sync_terminal_cursor(&mut output, 0, 0, 30).expect("cursor");
+        assert_eq!(String::from_utf8_lossy(&output), "\x1b[1;18H");
+        edited.click(0, 30, 16);
+        assert_eq!(shown(&edited), "/connect host 日|本語");
+
+        let mut client = Client::default();
+        assert!(client.add_alias("home", "connect host TOKEN").is_err());
+        assert!(client.add_alias("home", "/c host \"TO KEN\"").is_err());
+        assert_eq!(client.add_alias("home", "connect host @./TOKEN"), Ok(()));
+        assert!(client.settings.aliases.values().all(|expansion| !expansion.contains(" TOKEN")));
+    }
+
+    #[test]
+    fn aliases_expand_into_commands() {
+        let mut client = Client::default();
+        assert_eq!(client.add_alias("/home", " /connect host @./TOKEN "), Ok(()));
+        assert_eq!(client.settings.aliases["home"], "connect host @./TOKEN");
+        assert_eq!(client.add_alias("hi", "msg  alice hello "), Ok(()));
+        assert_eq!(client.add_alias("bare", "list"), Ok(()));
+        assert_eq!(client.add_alias("greet", "hi"), Ok(()));
+        for (name, expansion) in [("help", "list"), ("/c", "list"), ("", "list"), ("two words", "list"), ("empty", " / ")] {
+            assert!(client.add_alias(name, expansion).is_err(), "{name:?}");
+        }
+
+        let expanded = |name: &str, argument: &str| client.expand_alias(name.to_string(), argument.to_string());
+        let ok = |name: &str, argument: &str| Ok((name.to_string(), argument.to_string()));
+        assert_eq!(expanded("home", ""), ok("connect", "host @./TOKEN"));
+        assert_eq!(expanded("home", "  extra "), ok("connect", "host @./TOKEN extra"));
+        assert_eq!(expanded("hi", "bob"), ok("msg", "alice hello bob"));
+        assert_eq!(expanded("bare", ""), ok("list", ""));
+        assert_eq!(expanded("bare", " more "), ok("list", "more"));
+        assert_eq!(expanded("greet", "bob"), ok("msg", "alice hello bob"));
+        // NOTE: the commands are left as they are
+        assert_eq!(expanded("msg", " alice hi"), ok("msg", " alice hi"));
+
+        // NOTE: the chain from a9 down to a0 is as long as the limit allows, a10 is one too many
+        for i in 0..=ALIAS_DEPTH_LIMIT {
+            let expansion = if i == 0 { "list".to_string() } else { format!("a{}", i - 1) };
+            assert_eq!(client.add_alias(&format!("a{i}"), &expansion), Ok(()));
+        }
+        let last = format!("a{}", ALIAS_DEPTH_LIMIT - 1);
+        assert_eq!(client.expand_alias(last, String::new()), ok("list", ""));
+        assert!(client.expand_alias(format!("a{ALIAS_DEPTH_LIMIT}"), String::new()).is_err());
+        assert_eq!(client.add_alias("loop", "loop"), Ok(()));
+        assert!(client.expand_alias("loop".to_string(), String::new()).is_err());
+    }
+
+    #[test]
+    fn outbox_is_flushed_at_the_message_rate() {
+        let mut client = Client::default();
+        client.send_message("one".to_string());
+        client.send_message("two".to_string());
+        let lines = chat_lines(&client.chat);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].1.starts_with("You are offline."));
+        assert_eq!(lines[1..], [(Style::Pending, "you: one".to_string()), (Style::Pending, "you: two".to_string())]);
+        let queued: Vec<_> = client.outbox.iter().map(|(text, index)| (text.as_str(), *index)).collect();
+        assert_eq!(queued, [("one", 1), ("two", 2)]);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("listener");
+        client.connection = Connection::Online(Stream::Plain(TcpStream::connect(listener.local_addr().expect("address")).expect("connect")));
+        let (mut server, _) = listener.accept().expect("accept");
+        server.set_read_timeout(Some(Duration::from_secs(5))).expect("timeout");
+        let mut frame = [0; 4];
+
+        // NOTE: the last message went out just now, so the next one waits
+        client.outbox_sent_at = Some(Instant::now());
+        client.flush_outbox();
+        assert_eq!(client.outbox.len(), 2);
 
-        if let Some(y) = h.checked_sub(1) {
-            let x = 1;
-            if let Some(w) = w.checked_sub(1) {
-                prompt.sync_terminal_cursor(&mut stdout, x, y as usize, w as usize)?;
+        client.outbox_sent_at = Instant::now().checked_sub(MESSAGE_RATE);
+        client.flush_outbox();
+        server.read_exact(&mut frame).expect("frame");
+        assert_eq!(&frame, b"one\n");
+        assert_eq!(client.outbox.len(), 1);
+        let pending: Vec<_> = client.pending.iter().map(|(text, _, index)| (text.as_str(), *index)).collect();
+        assert_eq!(pending, [("one", 1)]);
+        assert_eq!(chat_lines(&client.chat)[1..], [(Style::Own, "you: one".to_string()), (Style::Pending, "you: two".to_string())]);
+
+        client.flush_outbox();
+        assert_eq!(client.outbox.len(), 1);
+
+        client.outbox_sent_at = Instant::now().checked_sub(MESSAGE_RATE);
+        client.flush_outbox();
+        server.read_exact(&mut frame).expect("frame");
+        assert_eq!(&frame, b"two\n");
+        assert!(client.outbox.is_empty());
+        let pending: Vec<_> = client.pending.iter().map(|(text, _, index)| (text.as_str(), *index)).collect();
+        assert_eq!(pending, [("one", 1), ("two", 2)]);
+        assert_eq!(chat_lines(&client.chat)[1..], [(Style::Own, "you: one".to_string()), (Style::Own, "you: two".to_string())]);
+    }
+
+    /// Runs the handshake between the Client with the fingerprint pinned and the Server with the
+    /// certificate from tests/data in memory, the records going back and forth until both are done
+    fn pinned_handshake(pinned: &str) -> Result<(), rustls::Error> {
+        let cert = CertificateDer::from_pem_slice(include_bytes!("../tests/data/localhost.pem")).expect("certificate");
+        let key = PrivateKeyDer::from_pem_slice(include_bytes!("../tests/data/localhost.key")).expect("key");
+        let config = ServerConfig::builder().with_no_client_auth().with_single_cert(vec![cert], key).expect("server config");
+        let mut server = ServerConnection::new(Arc::new(config)).expect("server");
+        let server_name = ServerName::try_from("localhost").expect("server name");
+        let mut client = ClientConnection::new(Arc::new(tls_config(Some(pinned))), server_name).expect("client");
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut records = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut records).expect("client records");
             }
+            server.read_tls(&mut records.as_slice()).expect("server read");
+            server.process_new_packets()?;
+            let sent = records.len();
+            records.clear();
+            while server.wants_write() {
+                server.write_tls(&mut records).expect("server records");
+            }
+            client.read_tls(&mut records.as_slice()).expect("client read");
+            client.process_new_packets()?;
+            assert!(sent + records.len() > 0, "the handshake is stuck");
         }
-        stdout.flush()?;
-        mem::swap(&mut buf_curr, &mut buf_prev);
+        Ok(())
+    }
 
-        thread::sleep(Duration::from_millis(16));
+    #[test]
+    fn pinned_certificate_is_trusted_by_fingerprint() {
+        let pinned = "ac5529e3605032c103aad8d80ccb3e73de3eeded682a69f220cdbc20b018a79b";
+        assert_eq!(pinned_handshake(pinned), Ok(()));
+        let colons = "AC:55:29:E3:60:50:32:C1:03:AA:D8:D8:0C:CB:3E:73:de:3e:ed:ed:68:2a:69:f2:20:cd:bc:20:b0:18:a7:9b";
+        assert_eq!(pinned_handshake(colons), Ok(()));
+        let other = pinned.replace("ac55", "ac56");
+        assert!(matches!(pinned_handshake(&other), Err(rustls::Error::General(_))));
     }
-    Ok(())
 }
@@ -0,0 +1,10 @@
+// NOTE: the Server reports its errors right where they happen, so like the binaries it just
+// returns Result<T, ()> once they are reported
+#[allow(clippy::result_unit_err)]
+pub mod chat;
+pub mod moderation;
+pub mod protocol;
+pub mod proxy;
+pub mod sens;
+pub mod tls;
+pub mod websocket;
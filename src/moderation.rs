@@ -0,0 +1,308 @@
+//! Rate limiting, strikes and bans of the Server.
+//!
+//! Nothing in here touches the sockets or the clock directly, the current time is always passed
+//! in, so the rules can be checked in isolation.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many messages a client can send in a quick succession
+pub const MESSAGE_BURST: f64 = 5.0;
+pub const STRIKE_DECAY: Duration = Duration::from_secs(5*60);
+/// How long the bans last, unless the Server is configured otherwise
+pub const BAN_LIMIT: Duration = Duration::from_secs(10*60);
+/// How many of the most recent strikes are remembered for /baninfo
+pub const STRIKE_HISTORY: usize = 5;
+
+/// Lets through MESSAGE_BURST messages at once refilling at the pace of one message per `rate`
+pub struct MessageBucket {
+    level: f64,
+    refilled_at: Instant,
+    rate: Duration,
+}
+
+impl MessageBucket {
+    pub fn new(now: Instant, rate: Duration) -> Self {
+        Self {
+            level: MESSAGE_BURST,
+            refilled_at: now,
+            rate,
+        }
+    }
+
+    /// How long until the bucket lets through one more message
+    pub fn wait(&self) -> Duration {
+        self.rate.mul_f64((1.0 - self.level).max(0.0))
+    }
+
+    /// Returns false if there is no room for one more message right now
+    pub fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.level = (self.level + elapsed.as_secs_f64()/self.rate.as_secs_f64()).min(MESSAGE_BURST);
+        self.refilled_at = now;
+        if self.level >= 1.0 {
+            self.level -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum StrikeReason {
+    RateLimit,
+    InvalidUtf8,
+    AuthFailure,
+    Slowloris,
+    ConnectFlood,
+    WaitingFlood,
+    Oversize,
+    Admin,
+}
+
+impl StrikeReason {
+    pub const ALL: [Self; 8] = [
+        Self::RateLimit,
+        Self::InvalidUtf8,
+        Self::AuthFailure,
+        Self::Slowloris,
+        Self::ConnectFlood,
+        Self::WaitingFlood,
+        Self::Oversize,
+        Self::Admin,
+    ];
+
+    /// Used in the file of the bans and in the logs
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::RateLimit => "rate-limit",
+            Self::InvalidUtf8 => "invalid-utf8",
+            Self::AuthFailure => "auth-failure",
+            Self::Slowloris => "slowloris",
+            Self::ConnectFlood => "connect-flood",
+            Self::WaitingFlood => "waiting-flood",
+            Self::Oversize => "oversize",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// Shown to the banned clients
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::RateLimit => "flooding the chat",
+            Self::InvalidUtf8 => "sending invalid UTF-8",
+            Self::AuthFailure => "too many auth failures",
+            Self::Slowloris => "not authorizing in time",
+            Self::ConnectFlood => "connecting too often",
+            Self::WaitingFlood => "too many unauthorized connections",
+            Self::Oversize => "sending too long messages",
+            Self::Admin => "decision of an admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        // NOTE: the older versions of the Server saved Oversize bans under this name
+        if name == "frame-limit" {
+            return Some(Self::Oversize);
+        }
+        Self::ALL.into_iter().find(|reason| reason.name() == name)
+    }
+}
+
+pub struct Strike {
+    pub time: Instant,
+    pub reason: StrikeReason,
+}
+
+pub enum Sinner {
+    /// Amount of strikes and the most recent of them
+    Striked(usize, VecDeque<Strike>),
+    /// Banned until the specified time. The reason is unknown for the bans saved by the older
+    /// versions of the Server. The last field counts the reconnects that were told about the ban
+    Banned(Instant, Option<StrikeReason>, usize),
+    /// Nobody but the Sinner sees their messages. Lasts until lifted by an admin, unless the
+    /// Sinner keeps getting the strikes, which are counted like for Striked
+    Shadowed(usize, VecDeque<Strike>),
+}
+
+impl Default for Sinner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sinner {
+    pub fn new() -> Self {
+        Self::Striked(0, VecDeque::new())
+    }
+
+    pub fn shadowed() -> Self {
+        Self::Shadowed(0, VecDeque::new())
+    }
+
+    pub fn is_shadowed(&self) -> bool {
+        matches!(self, Self::Shadowed(..))
+    }
+
+    pub fn forgive(&mut self) {
+        // NOTE: behaving well is exactly what the shadowed Sinners have to do to not notice anything
+        if self.is_shadowed() {
+            *self = Self::shadowed()
+        } else {
+            *self = Self::new()
+        }
+    }
+
+    /// Returns true if the Sinner is banned for `ban_limit` after exceeding `strike_limit`.
+    /// The ban replaces the shadow ban, the Sinner knows it got caught by then anyway
+    pub fn strike(&mut self, reason: StrikeReason, strike_limit: usize, ban_limit: Duration, now: Instant) -> bool {
+        match self {
+            Self::Striked(x, strikes) | Self::Shadowed(x, strikes) => {
+                if *x >= strike_limit {
+                    // NOTE: the Server keeps ban_limit within reason, the default is just in case
+                    let until = now.checked_add(ban_limit).unwrap_or(now + BAN_LIMIT);
+                    *self = Self::Banned(until, Some(reason), 0);
+                    true
+                } else {
+                    *x += 1;
+                    strikes.push_back(Strike { time: now, reason });
+                    if strikes.len() > STRIKE_HISTORY {
+                        strikes.pop_front();
+                    }
+                    false
+                }
+            }
+            Self::Banned(..) => true,
+        }
+    }
+
+    /// Whether the Sinner has nothing left to be remembered for
+    pub fn expired(&self, now: Instant) -> bool {
+        match self {
+            Self::Striked(0, _) => true,
+            // NOTE: the strikes are forgiven after a while even if the IP never comes back to
+            // send a proper message, otherwise the old strikes would stick around forever
+            Self::Striked(_, strikes) => strikes.back().map(|strike| {
+                now.saturating_duration_since(strike.time) >= STRIKE_DECAY
+            }).unwrap_or(true),
+            Self::Banned(until, ..) => ban_expired(*until, now),
+            Self::Shadowed(..) => false,
+        }
+    }
+}
+
+pub fn ban_expired(until: Instant, now: Instant) -> bool {
+    now >= until
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_ban_limit_does_not_overflow() {
+        let now = Instant::now();
+        let mut sinner = Sinner::new();
+        assert!(!sinner.strike(StrikeReason::RateLimit, 1, Duration::MAX, now));
+        assert!(sinner.strike(StrikeReason::RateLimit, 1, Duration::MAX, now));
+        assert!(matches!(sinner, Sinner::Banned(until, Some(StrikeReason::RateLimit), 0) if until == now + BAN_LIMIT));
+    }
+
+    #[test]
+    fn shadowed_sinner_escalates_to_ban() {
+        let now = Instant::now();
+        let mut sinner = Sinner::shadowed();
+        assert!(!sinner.strike(StrikeReason::RateLimit, 2, BAN_LIMIT, now));
+        assert!(!sinner.strike(StrikeReason::RateLimit, 2, BAN_LIMIT, now));
+        assert!(matches!(sinner, Sinner::Shadowed(2, _)));
+        assert!(sinner.strike(StrikeReason::RateLimit, 2, BAN_LIMIT, now));
+        assert!(matches!(sinner, Sinner::Banned(..)));
+    }
+
+    #[test]
+    fn forgiven_shadowed_sinner_stays_shadowed() {
+        let now = Instant::now();
+        let mut sinner = Sinner::shadowed();
+        sinner.strike(StrikeReason::InvalidUtf8, 10, BAN_LIMIT, now);
+        sinner.forgive();
+        assert!(matches!(sinner, Sinner::Shadowed(0, ref strikes) if strikes.is_empty()));
+        assert!(!sinner.expired(now + STRIKE_DECAY));
+    }
+
+    #[test]
+    fn strikes_add_up_to_ban_that_expires() {
+        let now = Instant::now();
+        let mut sinner = Sinner::new();
+        assert!(sinner.expired(now));
+        for n in 1..=3 {
+            assert!(!sinner.strike(StrikeReason::AuthFailure, 3, BAN_LIMIT, now));
+            assert!(matches!(sinner, Sinner::Striked(x, ref strikes) if x == n && strikes.len() == n));
+        }
+        assert!(!sinner.expired(now));
+        assert!(sinner.strike(StrikeReason::AuthFailure, 3, BAN_LIMIT, now));
+        assert!(matches!(sinner, Sinner::Banned(until, Some(StrikeReason::AuthFailure), 0) if until == now + BAN_LIMIT));
+        // NOTE: the strikes while banned change nothing
+        assert!(sinner.strike(StrikeReason::RateLimit, 3, BAN_LIMIT, now + BAN_LIMIT/2));
+        assert!(!sinner.expired(now + BAN_LIMIT/2));
+        assert!(sinner.expired(now + BAN_LIMIT));
+    }
+
+    #[test]
+    fn old_strikes_decay() {
+        let now = Instant::now();
+        let mut sinner = Sinner::new();
+        sinner.strike(StrikeReason::Slowloris, 10, BAN_LIMIT, now);
+        sinner.strike(StrikeReason::Slowloris, 10, BAN_LIMIT, now + STRIKE_DECAY/2);
+        // NOTE: the decay counts from the most recent strike
+        assert!(!sinner.expired(now + STRIKE_DECAY));
+        assert!(sinner.expired(now + STRIKE_DECAY/2 + STRIKE_DECAY));
+        sinner.forgive();
+        assert!(matches!(sinner, Sinner::Striked(0, ref strikes) if strikes.is_empty()));
+    }
+
+    #[test]
+    fn strike_history_is_capped() {
+        let now = Instant::now();
+        let mut sinner = Sinner::new();
+        for _ in 0..2*STRIKE_HISTORY {
+            sinner.strike(StrikeReason::InvalidUtf8, 100, BAN_LIMIT, now);
+        }
+        assert!(matches!(sinner, Sinner::Striked(x, ref strikes) if x == 2*STRIKE_HISTORY && strikes.len() == STRIKE_HISTORY));
+    }
+
+    #[test]
+    fn bucket_lets_bursts_through() {
+        let now = Instant::now();
+        let mut bucket = MessageBucket::new(now, Duration::from_secs(1));
+        for _ in 0..MESSAGE_BURST as usize {
+            assert!(bucket.take(now));
+        }
+        assert!(!bucket.take(now));
+        assert_eq!(bucket.wait(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bucket_throttles_sustained_flood() {
+        let start = Instant::now();
+        let rate = Duration::from_secs(1);
+        let mut bucket = MessageBucket::new(start, rate);
+        // NOTE: 4 messages a second for a minute, while only one a second is refilled
+        let taken = (0..4*60).filter(|&i| bucket.take(start + rate/4*i)).count();
+        assert_eq!(taken, MESSAGE_BURST as usize + 59);
+    }
+
+    #[test]
+    fn bucket_recovers_after_idle() {
+        let now = Instant::now();
+        let rate = Duration::from_secs(1);
+        let mut bucket = MessageBucket::new(now, rate);
+        while bucket.take(now) {}
+        assert!(!bucket.take(now + rate/2));
+        assert!(bucket.take(now + rate));
+        // NOTE: a long silence does not fill the bucket beyond the burst
+        let later = now + rate*3600;
+        let taken = (0..100).filter(|_| bucket.take(later)).count();
+        assert_eq!(taken, MESSAGE_BURST as usize);
+    }
+}
@@ -3,10 +3,11 @@ use std::net::TcpStream;
 use std::env;
 use std::result;
 use std::process::ExitCode;
-use std::io::Write;
 use getrandom::getrandom;
+use std::io::{Read, Write};
 use std::thread;
 use std::time::Duration;
+use fourat::protocol::{write_frame, FRAME_SEPARATOR, DISCONNECT_TAG};
 
 type Result<T> = result::Result<T, ()>;
 
@@ -30,19 +31,15 @@ fn command_dragon(command_name: &str, args: &mut env::Args) -> Result<()> {
 
     if let Some(token) = token {
         println!("INFO: Sending token...");
-        write!(&server, "{token}").map_err(|err| {
+        write_frame(&mut server, &token).map_err(|err| {
             eprintln!("ERROR: could not authorize with the token: {err}");
         })?;
     }
 
-    // TODO: we should not need this sleep if we just had a properly
-    // defined protocol that specifies message separators
-    thread::sleep(Duration::from_millis(100));
-
     const DRAGON_BUFFER_SIZE: usize = 1024;
     let mut buffer = vec![0; DRAGON_BUFFER_SIZE];
     loop {
-        let _ = getrandom(&mut buffer).map_err(|err| {
+        getrandom(&mut buffer).map_err(|err| {
             eprintln!("ERROR: could not generate random data: {err}");
         })?;
 
@@ -100,6 +97,91 @@ fn command_gnome(command_name: &str, args: &mut env::Args) -> Result<()> {
     }
 }
 
+fn command_sloth(command_name: &str, args: &mut env::Args) -> Result<()> {
+    let (address, token) = match (args.next(), args.next()) {
+        (Some(address), Some(token)) => (address, token),
+        _ => {
+            eprintln!("Usage: {command_name} <address> <token> [messages]");
+            eprintln!("ERROR: no address or token is provided. Example: 127.0.0.1:6969 0123456789ABCDEF0123456789ABCDEF");
+            return Err(());
+        }
+    };
+    const SLOTH_DEFAULT_MESSAGES: usize = 10000;
+    let messages = match args.next() {
+        Some(messages) => messages.parse::<usize>().map_err(|err| {
+            eprintln!("ERROR: invalid amount of messages {messages}: {err}");
+        })?,
+        None => SLOTH_DEFAULT_MESSAGES,
+    };
+
+    let mut sloth = TcpStream::connect(&address).map_err(|err| {
+        eprintln!("ERROR: could not connect to {address}: {err}");
+    })?;
+    write_frame(&mut sloth, &token).map_err(|err| {
+        eprintln!("ERROR: could not authorize the sloth: {err}");
+    })?;
+    let mut flooder = TcpStream::connect(&address).map_err(|err| {
+        eprintln!("ERROR: could not connect to {address}: {err}");
+    })?;
+    write_frame(&mut flooder, &token).map_err(|err| {
+        eprintln!("ERROR: could not authorize the flooder: {err}");
+    })?;
+    // NOTE: the flooder reads everything it gets, so it is not a laggard itself
+    let mut flooder_reader = flooder.try_clone().map_err(|err| {
+        eprintln!("ERROR: could not clone the connection of the flooder: {err}");
+    })?;
+    let reader = thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok(n) = flooder_reader.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+
+    eprintln!("INFO: the sloth never reads, while the flooder sends {messages} messages");
+    eprintln!("INFO: run the Server with a tiny --message-rate, otherwise the flooder gets rate limited");
+    let message = "x".repeat(1000);
+    for i in 0..messages {
+        if let Err(err) = write_frame(&mut flooder, &message) {
+            eprintln!("ERROR: the flooder could not send message {i}: {err}");
+            return Err(());
+        }
+        // NOTE: the Server reads everything that is available at once, so without the pauses it
+        // would queue more echoes for the flooder than it is allowed to have as well
+        thread::sleep(Duration::from_micros(200));
+    }
+    // NOTE: giving the Server some time to notice the sloth
+    thread::sleep(Duration::from_secs(1));
+
+    let mut received = Vec::new();
+    let _ = sloth.set_read_timeout(Some(Duration::from_secs(5)));
+    let closed = sloth.read_to_end(&mut received).is_ok();
+    let last_line = received
+        .split(|x| *x == FRAME_SEPARATOR)
+        .rfind(|line| !line.is_empty())
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .unwrap_or_default();
+    if !closed {
+        eprintln!("ERROR: the sloth was not disconnected after {n} bytes", n = received.len());
+        return Err(());
+    }
+    if received.last().is_some_and(|x| *x != FRAME_SEPARATOR) {
+        eprintln!("ERROR: the sloth got a torn line at the end");
+        return Err(());
+    }
+    match last_line.strip_prefix(DISCONNECT_TAG) {
+        Some(notice) => eprintln!("INFO: the sloth got {n} bytes and then {notice}", n = received.len()),
+        None => eprintln!("INFO: the sloth got {n} bytes and then the connection was closed", n = received.len()),
+    }
+    if reader.is_finished() {
+        eprintln!("ERROR: the flooder was disconnected too");
+        return Err(());
+    }
+    eprintln!("INFO: the flooder is still connected");
+    Ok(())
+}
+
 const COMMANDS: &[Command] = &[
     Command {
         name: "dragon",
@@ -116,6 +198,11 @@ const COMMANDS: &[Command] = &[
         description: "Keeps opening and closing connections",
         run: command_gnome,
     },
+    Command {
+        name: "sloth",
+        description: "Authorizes and never reads, while another connection floods the chat",
+        run: command_sloth,
+    },
 ];
 
 fn usage(program: &str) {
@@ -0,0 +1,477 @@
+//! Wire protocol shared by the Server, the Client and Pandora.
+//!
+//! Every message is a line of text terminated by `\n`. Reads from a socket may return
+//! any number of bytes, so the receiving side accumulates them in a `FrameReader` and
+//! only acts on complete frames.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+pub const FRAME_SEPARATOR: u8 = b'\n';
+pub const DEFAULT_PORT: u16 = 6969;
+/// Prepended by the Server to the copy of a message it sends back to its author, so the
+/// Client knows the message was delivered. The Server strips all the control characters
+/// from the messages, so nobody can forge it.
+pub const ECHO_TAG: char = '\u{6}';
+/// A frame of just this character is a keepalive ping. The Server sends it to the clients
+/// periodically and the Client answers with the same frame.
+pub const PING_TAG: char = '\u{5}';
+/// Prepended by the Server to the direct messages, so the Client can tell them apart from the
+/// messages everyone in the room sees.
+pub const DM_TAG: char = '\u{2}';
+/// Prepended by the Server to the messages that were sent before the client joined, so the
+/// Client can render them differently.
+pub const HISTORY_TAG: char = '\u{3}';
+/// Prepended by the Server to the lines telling the client that it did something wrong (sent
+/// messages too fast, got a strike, got banned, etc), so the Client can render them as errors.
+pub const ERROR_TAG: char = '\u{15}';
+/// Prepended by the Server to the last line it sends before closing the connection, so the
+/// Client can tell the user why it got disconnected.
+pub const DISCONNECT_TAG: char = '\u{4}';
+/// Prepended by the Server to every line of a room together with the number of the line in the
+/// room and a space, like `\u{1}42 line`, so the Client can tell when it missed some of them. A
+/// frame of just the tag and the number tells the number of the last line of the room the
+/// client has just entered.
+pub const SEQUENCE_TAG: char = '\u{1}';
+/// How often a client gets to send one more message, unless the Server is configured otherwise
+pub const MESSAGE_RATE: Duration = Duration::from_secs(1);
+/// Prepended by the Server to its answer to `/ping <nonce>`, which is just the nonce, so the
+/// Client can measure the round trip.
+pub const PONG_TAG: char = '\u{16}';
+/// The room everyone gets into upon authorization
+pub const DEFAULT_ROOM: &str = "general";
+/// The line the Server sends once it accepted the token. The Client holds everything but the
+/// token until it sees it
+pub const WELCOME: &str = "Welcome to the Club buddy!";
+/// Most combining marks kept on top of one character. Real scripts need two or three, "zalgo"
+/// text piles up dozens of them to spill over the neighbouring lines
+pub const MARK_LIMIT: usize = 3;
+/// Widest message the Server broadcasts, in the columns of the terminal
+pub const WIDTH_LIMIT: usize = 512;
+/// Longest message a client may send. The Server drops the longer ones with a strike
+pub const FRAME_LIMIT: usize = 1024;
+
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    /// Dropping everything until the end of the current frame
+    discarding: bool,
+}
+
+impl FrameReader {
+    pub fn extend(&mut self, bytes: &[u8]) {
+        let bytes = if self.discarding {
+            match bytes.iter().position(|x| *x == FRAME_SEPARATOR) {
+                Some(end) => {
+                    self.discarding = false;
+                    &bytes[end + 1..]
+                }
+                None => return,
+            }
+        } else {
+            bytes
+        };
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Drops the incomplete frame together with the rest of it that is yet to arrive.
+    pub fn discard_frame(&mut self) {
+        self.buffer.clear();
+        self.discarding = true;
+    }
+
+    /// Discards the incomplete frame once it grows over `limit` bytes, so nobody can make the
+    /// other side buffer a frame that never ends. Returns whether the frame got discarded
+    pub fn discard_oversized(&mut self, limit: usize) -> bool {
+        if self.pending() > limit {
+            self.discard_frame();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Amount of bytes received that are not a part of any complete frame yet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let end = self.buffer.iter().position(|x| *x == FRAME_SEPARATOR)?;
+        let mut frame: Vec<u8> = self.buffer.drain(..=end).collect();
+        frame.pop();
+        // NOTE: be nice to telnet and netcat users
+        if frame.last() == Some(&b'\r') {
+            frame.pop();
+        }
+        Some(frame)
+    }
+}
+
+pub fn write_frame(sink: &mut impl Write, text: &str) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(text.len() + 1);
+    frame.extend_from_slice(text.as_bytes());
+    frame.push(FRAME_SEPARATOR);
+    sink.write_all(&frame)
+}
+
+/// Decodes a frame as UTF-8 and strips all the control characters along with the ANSI escape
+/// sequences they start (so nobody can recolor or retitle the terminals of other users, and no
+/// `[31m` is left behind), the invisible format characters and the combining marks beyond
+/// MARK_LIMIT.
+/// Returns None if the frame is not valid UTF-8.
+pub fn sanitize(frame: &[u8]) -> Option<String> {
+    str::from_utf8(frame).ok().map(|text| strip(text, &mut Vec::new()))
+}
+
+/// What sanitize_lossy() makes of a frame
+pub struct Sanitized {
+    pub text: String,
+    /// The SGR sequences (`ESC [ 1;31 m`, the colors and such) stripped from the text: where in
+    /// the text they were and their parameters
+    pub sgr: Vec<(usize, String)>,
+    /// The frame was valid UTF-8
+    pub valid: bool,
+}
+
+/// The same as sanitize(), but the bytes that are not valid UTF-8 become U+FFFD instead of
+/// failing the whole frame
+pub fn sanitize_lossy(frame: &[u8]) -> Sanitized {
+    let text = String::from_utf8_lossy(frame);
+    let mut sgr = Vec::new();
+    Sanitized {
+        text: strip(&text, &mut sgr),
+        sgr,
+        valid: matches!(text, Cow::Borrowed(_)),
+    }
+}
+
+/// Where strip() is inside of an escape sequence
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    /// Right after ESC
+    Start,
+    /// `ESC [`, the parameters go until the final character in `@`..=`~`
+    Csi,
+    /// `ESC ]` (OSC, like the title of the window) and the other strings (`ESC P`, `ESC X`,
+    /// `ESC ^`, `ESC _`), which go until BEL or ST (`ESC \`)
+    String,
+}
+
+fn strip(text: &str, sgr: &mut Vec<(usize, String)>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut marks = 0;
+    let mut escape = Escape::None;
+    let mut params = String::new();
+    for x in text.chars() {
+        let consumed = match escape {
+            Escape::None => false,
+            Escape::Start => {
+                escape = match x {
+                    '[' => Escape::Csi,
+                    ']' | 'P' | 'X' | '^' | '_' => Escape::String,
+                    // NOTE: the intermediate characters, like in `ESC ( B`
+                    ' '..='/' => Escape::Start,
+                    _ => Escape::None,
+                };
+                params.clear();
+                (' '..='~').contains(&x)
+            }
+            Escape::Csi => match x {
+                ' '..='?' => {
+                    params.push(x);
+                    true
+                }
+                '@'..='~' => {
+                    if x == 'm' {
+                        sgr.push((result.len(), params.clone()));
+                    }
+                    escape = Escape::None;
+                    true
+                }
+                // NOTE: the sequence is broken off, the character is not a part of it
+                _ => {
+                    escape = Escape::None;
+                    false
+                }
+            },
+            Escape::String => {
+                match x {
+                    '\u{7}' | '\u{9C}' => escape = Escape::None,
+                    // NOTE: ESC ends the string, whether it is `ESC \` or not
+                    '\u{1B}' => escape = Escape::Start,
+                    _ => {}
+                }
+                true
+            }
+        };
+        if consumed {
+            continue;
+        }
+        match x {
+            '\u{1B}' => escape = Escape::Start,
+            // NOTE: the C1 versions of `ESC [` and `ESC ]`, `ESC P`, `ESC X`, `ESC ^`, `ESC _`
+            '\u{9B}' => {
+                escape = Escape::Csi;
+                params.clear();
+            }
+            '\u{9D}' | '\u{90}' | '\u{98}' | '\u{9E}' | '\u{9F}' => escape = Escape::String,
+            _ => {}
+        }
+        if x.is_control() || is_format(x) {
+            continue;
+        }
+        if x.width() == Some(0) {
+            marks += 1;
+            if marks > MARK_LIMIT {
+                continue;
+            }
+        } else {
+            marks = 0;
+        }
+        result.push(x);
+    }
+    result
+}
+
+/// The characters of the Cf category: the bidi overrides that reorder the text around them,
+/// the zero-width spaces and joiners, the tags, etc.
+fn is_format(x: char) -> bool {
+    matches!(x,
+        '\u{AD}' | '\u{600}'..='\u{605}' | '\u{61C}' | '\u{6DD}' | '\u{70F}' | '\u{180E}' |
+        '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}' |
+        '\u{2066}'..='\u{206F}' | '\u{FEFF}' | '\u{FFF9}'..='\u{FFFB}' | '\u{110BD}' |
+        '\u{1BCA0}'..='\u{1BCA3}' | '\u{1D173}'..='\u{1D17A}' | '\u{E0001}' | '\u{E0020}'..='\u{E007F}')
+}
+
+/// Cuts the text down to at most `limit` columns of the terminal, ending it with `…` if
+/// anything was cut
+pub fn fit_width(text: &str, limit: usize) -> String {
+    if text.width() <= limit {
+        return text.to_string();
+    }
+    let mut result = String::new();
+    let mut width = 0;
+    for x in text.chars() {
+        width += x.width().unwrap_or(0);
+        if width > limit.saturating_sub(1) {
+            break;
+        }
+        result.push(x);
+    }
+    result.push('…');
+    result
+}
+
+/// Formats the time of the day in UTC as HH:MM:SS
+pub fn clock(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()%(24*60*60);
+    format!("{:02}:{:02}:{:02}", secs/3600, secs/60%60, secs%60)
+}
+
+/// Formats the time as RFC3339 in UTC
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs();
+    // NOTE: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs/(24*60*60)) as i64 + 719468;
+    let era = days/146097;
+    let doe = days - era*146097;
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096)/365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2)/153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era*400 + if month <= 2 { 1 } else { 0 };
+    format!("{year:04}-{month:02}-{day:02}T{clock}Z", clock = clock(time))
+}
+
+/// Prepends the time to the line as `[HH:MM:SS] line`. The Server stamps every line broadcast to
+/// a room (including the history), the Client takes the stamp apart with `split_stamp`.
+pub fn stamp(time: SystemTime, line: &str) -> String {
+    format!("[{clock}] {line}", clock = clock(time))
+}
+
+/// Splits `[HH:MM:SS] line` into `HH:MM:SS` and the line. Returns None if the line is not stamped.
+pub fn split_stamp(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let (clock, line) = rest.split_at_checked(8)?;
+    let line = line.strip_prefix("] ")?;
+    let valid = clock.bytes().enumerate().all(|(i, x)| if i%3 == 2 { x == b':' } else { x.is_ascii_digit() });
+    if valid {
+        Some((clock, line))
+    } else {
+        None
+    }
+}
+
+/// Splits `\u{1}42 line` into 42 and the line. The number is None if the frame does not start
+/// with SEQUENCE_TAG.
+pub fn split_sequence(frame: &[u8]) -> (Option<u64>, &[u8]) {
+    let rest = if let Some(rest) = frame.strip_prefix(&[SEQUENCE_TAG as u8]) {
+        rest
+    } else {
+        return (None, frame)
+    };
+    let (number, line) = match rest.iter().position(|x| *x == b' ') {
+        Some(space) => (&rest[..space], &rest[space + 1..]),
+        None => (rest, &[][..]),
+    };
+    match str::from_utf8(number).ok().and_then(|number| number.parse().ok()) {
+        Some(number) => (Some(number), line),
+        None => (None, frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_frame_is_discarded_until_separator() {
+        let mut frames = FrameReader::default();
+        frames.extend(b"ok\nxxxx");
+        assert_eq!(frames.next_frame().as_deref(), Some(&b"ok"[..]));
+        assert!(!frames.discard_oversized(4));
+        frames.extend(b"x");
+        assert!(frames.discard_oversized(4));
+        assert_eq!(frames.pending(), 0);
+        // NOTE: the rest of the oversized frame keeps arriving, but is not buffered
+        frames.extend(b"xxxxxxxx");
+        assert_eq!(frames.pending(), 0);
+        frames.extend(b"xx\nnext\n");
+        assert_eq!(frames.next_frame().as_deref(), Some(&b"next"[..]));
+        assert_eq!(frames.next_frame(), None);
+    }
+
+    #[test]
+    fn rfc3339_formats_utc() {
+        let at = |secs| rfc3339(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1700000000), "2023-11-14T22:13:20Z");
+        assert_eq!(at(4107542399), "2100-02-28T23:59:59Z");
+    }
+
+    #[test]
+    fn clock_rolls_over_at_midnight() {
+        let midnight = UNIX_EPOCH + Duration::from_secs(1700006400);
+        assert_eq!(clock(midnight - Duration::from_secs(1)), "23:59:59");
+        assert_eq!(clock(midnight), "00:00:00");
+        assert_eq!(clock(midnight + Duration::from_millis(999)), "00:00:00");
+        assert_eq!(rfc3339(midnight - Duration::from_secs(1)), "2023-11-14T23:59:59Z");
+        assert_eq!(rfc3339(midnight), "2023-11-15T00:00:00Z");
+    }
+
+    #[test]
+    fn stamps_split_back() {
+        let line = stamp(UNIX_EPOCH + Duration::from_secs(1700000000), "<alice> [12:34:56] hi");
+        assert_eq!(line, "[22:13:20] <alice> [12:34:56] hi");
+        assert_eq!(split_stamp(&line), Some(("22:13:20", "<alice> [12:34:56] hi")));
+        assert_eq!(split_stamp("[22:13:20] "), Some(("22:13:20", "")));
+        assert_eq!(split_stamp("<alice> hi"), None);
+        assert_eq!(split_stamp("[22:13:20]hi"), None);
+        assert_eq!(split_stamp("[22-13-20] hi"), None);
+        assert_eq!(split_stamp("[2:13:20] hi"), None);
+        assert_eq!(split_stamp("[22:13:2x] hi"), None);
+        assert_eq!(split_stamp("[22:13:"), None);
+        assert_eq!(split_stamp("[ää:13:20] hi"), None);
+    }
+
+    #[test]
+    fn nasty_strings_are_cleaned() {
+        let table = [
+            ("plain text", "plain text"),
+            ("\u{202E}txet.exe", "txet.exe"),
+            ("left\u{2067}right\u{2069}", "leftright"),
+            ("zero\u{200B}width\u{FEFF}", "zerowidth"),
+            ("👨\u{200D}👩\u{200D}👧", "👨👩👧"),
+            ("flag\u{E0067}\u{E0062}\u{E007F}", "flag"),
+            ("soft\u{AD}hyphen", "softhyphen"),
+            ("bell\u{7}\u{0}\t", "bell"),
+            ("e\u{301}\u{301}\u{301}\u{301}\u{301}\u{301}", "e\u{301}\u{301}\u{301}"),
+            ("a\u{301}\u{301}\u{301}\u{301}b\u{301}", "a\u{301}\u{301}\u{301}b\u{301}"),
+            ("\u{301}\u{301}\u{301}\u{301}", "\u{301}\u{301}\u{301}"),
+            ("日本語", "日本語"),
+        ];
+        for (nasty, clean) in table {
+            assert_eq!(sanitize(nasty.as_bytes()).as_deref(), Some(clean), "{nasty:?}");
+        }
+        assert_eq!(sanitize(b"\xff\xfe"), None);
+    }
+
+    #[test]
+    fn long_text_is_cut_to_width() {
+        assert_eq!(fit_width("hello", 5), "hello");
+        assert_eq!(fit_width("hello world", 5), "hell…");
+        assert_eq!(fit_width("日本語", 6), "日本語");
+        assert_eq!(fit_width("日本語", 5), "日本…");
+        assert_eq!(fit_width("日本語", 4), "日…");
+        // NOTE: the combining marks take no columns, so they stay with their letter
+        assert_eq!(fit_width("e\u{301}e\u{301}e\u{301}", 3), "e\u{301}e\u{301}e\u{301}");
+        let long = "x".repeat(2*WIDTH_LIMIT);
+        assert_eq!(fit_width(&long, WIDTH_LIMIT).width(), WIDTH_LIMIT);
+    }
+
+    #[test]
+    fn invalid_utf8_is_shown_lossily() {
+        let table: [(&[u8], &str); 5] = [
+            (b"abc\xe6\x97", "abc\u{FFFD}"),
+            (b"\xc0\xafslash", "\u{FFFD}\u{FFFD}slash"),
+            (b"\xed\xa0\x80surrogate", "\u{FFFD}\u{FFFD}\u{FFFD}surrogate"),
+            (b"\x1b[31mred\xff\x1b[0m", "red\u{FFFD}"),
+            (b"\x1b\xffx", "\u{FFFD}x"),
+        ];
+        for (frame, text) in table {
+            let sanitized = sanitize_lossy(frame);
+            assert_eq!(sanitized.text, text, "{frame:?}");
+            assert!(!sanitized.valid);
+        }
+        let sanitized = sanitize_lossy("日本語".as_bytes());
+        assert_eq!(sanitized.text, "日本語");
+        assert!(sanitized.valid);
+    }
+
+    #[test]
+    fn escape_sequences_are_stripped_whole() {
+        let table = [
+            ("\x1b[31mred\x1b[0m", "red"),
+            ("\x1b[1;38;5;208mbold\x1b[m", "bold"),
+            ("\x1b]0;title\x07after", "after"),
+            ("\x1b]8;;https://x.y\x1b\\link\x1b]8;;\x1b\\", "link"),
+            ("\x1bPq#0;2;0;0;0\x1b\\sixel", "sixel"),
+            ("\x1b(Bcharset", "charset"),
+            ("\u{9B}31mc1\u{9D}title\u{9C}", "c1"),
+            ("\x1b[31\nbroken", "broken"),
+            ("trailing\x1b", "trailing"),
+            ("\x1b\x1b[2Jdouble", "double"),
+            ("no [31m escape", "no [31m escape"),
+        ];
+        for (nasty, clean) in table {
+            assert_eq!(sanitize(nasty.as_bytes()).as_deref(), Some(clean), "{nasty:?}");
+        }
+
+        let sanitized = sanitize_lossy(b"a\x1b[31mb\x1b[1;32mc\x1b[0m\x1b[2Jd");
+        assert_eq!(sanitized.text, "abcd");
+        assert_eq!(sanitized.sgr, [(1, "31".to_string()), (2, "1;32".to_string()), (3, "0".to_string())]);
+    }
+
+    #[test]
+    fn no_control_leaks_from_fragments() {
+        let fragments = ["text", "\x1b", "[", "31", ";", "m", "]", "0;", "\x07", "\\", "P", "\u{9B}", "\u{9D}", "\u{9C}", "\r\n", "\x08", "日本", "\u{202E}", "\x7f", "\u{85}"];
+        // NOTE: a plain LCG, so the cases are the same on every run
+        let mut state = 0x2545F4914F6CDD1Du64;
+        for _ in 0..10_000 {
+            let mut nasty = String::new();
+            for _ in 0..8 {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                nasty.push_str(fragments[(state >> 33) as usize%fragments.len()]);
+            }
+            let clean = sanitize(nasty.as_bytes()).expect("valid UTF-8");
+            assert!(!clean.chars().any(|x| x.is_control() || is_format(x)), "{nasty:?} -> {clean:?}");
+        }
+    }
+}
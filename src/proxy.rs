@@ -0,0 +1,89 @@
+//! Version 1 of the PROXY protocol, the one line that HAProxy and friends put in front of the
+//! connection to tell who actually connected to them:
+//!
+//! PROXY TCP4 203.0.113.7 192.0.2.1 56324 6969\r\n
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+/// The longest possible header including the CRLF
+pub const HEADER_LIMIT: usize = 107;
+const PREFIX: &[u8] = b"PROXY ";
+
+/// Parses the header at the beginning of the bytes. Returns how long the header is and the
+/// address of the client, which is None for `PROXY UNKNOWN` (e.g. the health checks of the
+/// proxy). Ok(None) means the header did not arrive completely yet
+pub fn parse_header(bytes: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, String> {
+    let n = bytes.len().min(PREFIX.len());
+    if bytes[..n] != PREFIX[..n] {
+        return Err("no PROXY header".to_string());
+    }
+    let end = if let Some(end) = bytes.windows(2).take(HEADER_LIMIT - 1).position(|window| window == b"\r\n") {
+        end
+    } else if bytes.len() >= HEADER_LIMIT {
+        return Err("PROXY header is too long".to_string());
+    } else {
+        return Ok(None);
+    };
+    let line = str::from_utf8(&bytes[..end]).map_err(|_| "PROXY header is not ASCII".to_string())?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let addr = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4", src, _dst, sport, _dport] => Some(parse_addr::<Ipv4Addr>(src, sport)?),
+        ["PROXY", "TCP6", src, _dst, sport, _dport] => Some(parse_addr::<Ipv6Addr>(src, sport)?),
+        _ => return Err(format!("malformed PROXY header {line:?}")),
+    };
+    Ok(Some((end + 2, addr)))
+}
+
+fn parse_addr<T: str::FromStr + Into<IpAddr>>(ip: &str, port: &str) -> Result<SocketAddr, String> {
+    let ip: T = ip.parse().map_err(|_| format!("invalid address {ip:?} in PROXY header"))?;
+    let port: u16 = port.parse().map_err(|_| format!("invalid port {port:?} in PROXY header"))?;
+    Ok(SocketAddr::new(ip.into(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp4_header() {
+        let header = b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 6969\r\ntoken\n";
+        let expected: SocketAddr = "203.0.113.7:56324".parse().expect("address");
+        assert_eq!(parse_header(header), Ok(Some((header.len() - "token\n".len(), Some(expected)))));
+    }
+
+    #[test]
+    fn tcp6_header() {
+        let header = b"PROXY TCP6 2001:db8::7 2001:db8::1 56324 6969\r\n";
+        let expected: SocketAddr = "[2001:db8::7]:56324".parse().expect("address");
+        assert_eq!(parse_header(header), Ok(Some((header.len(), Some(expected)))));
+        // NOTE: the families must match the protocol the header claims
+        assert!(parse_header(b"PROXY TCP6 203.0.113.7 192.0.2.1 56324 6969\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 2001:db8::7 2001:db8::1 56324 6969\r\n").is_err());
+    }
+
+    #[test]
+    fn unknown_header() {
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n"), Ok(Some((15, None))));
+        assert_eq!(parse_header(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n").map(|header| header.map(|(_, addr)| addr)), Ok(Some(None)));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let header = b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 6969\r\n";
+        for end in 0..header.len() {
+            assert_eq!(parse_header(&header[..end]), Ok(None), "{end} bytes");
+        }
+    }
+
+    #[test]
+    fn malformed_header() {
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_header(b"token\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 203.0.113.7 192.0.2.1 56324\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 203.0.113.7 192.0.2.1 99999 6969\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 6969\n").is_ok_and(|header| header.is_none()));
+        assert!(parse_header(&[b"PROXY ".as_slice(), &[b'x'; HEADER_LIMIT]].concat()).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! Redaction of the sensitive data (IP addresses, tokens, errors mentioning them) in the
+//! output of the programs, so it can be streamed or shared safely.
+
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Any value other than `0` turns the safe mode on
+pub const SAFE_MODE_ENV: &str = "FOURAT_SAFE_MODE";
+
+/// Set by the programs at startup
+pub static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+pub fn safe_mode_from_env() -> bool {
+    env::var_os(SAFE_MODE_ENV).map(|value| value != "0").unwrap_or(false)
+}
+
+pub fn set_safe_mode(safe_mode: bool) {
+    SAFE_MODE.store(safe_mode, Ordering::Relaxed);
+}
+
+/// Displays as `[REDACTED]` in the safe mode
+pub struct Sens<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Sens<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self(inner) = self;
+        if SAFE_MODE.load(Ordering::Relaxed) {
+            "[REDACTED]".fmt(f)
+        } else {
+            inner.fmt(f)
+        }
+    }
+}
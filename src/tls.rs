@@ -0,0 +1,28 @@
+//! Bits of TLS shared by the Server and the Client.
+
+use std::fmt::Write;
+
+/// SHA-256 of the DER encoded certificate in lowercase hex. The Server reports it at startup, so
+/// the Client can pin a self-signed certificate instead of validating it against the CAs.
+pub fn fingerprint(cert: &[u8]) -> String {
+    let mut fingerprint = String::new();
+    for x in ring::digest::digest(&ring::digest::SHA256, cert).as_ref() {
+        let _ = write!(&mut fingerprint, "{x:02x}");
+    }
+    fingerprint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::CertificateDer;
+    use rustls::pki_types::pem::PemObject;
+
+    #[test]
+    fn fingerprint_is_sha256_of_der() {
+        assert_eq!(fingerprint(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        // NOTE: the same as `openssl x509 -in tests/data/localhost.pem -noout -fingerprint -sha256`
+        let cert = CertificateDer::from_pem_slice(include_bytes!("../tests/data/localhost.pem")).expect("certificate");
+        assert_eq!(fingerprint(&cert), "ac5529e3605032c103aad8d80ccb3e73de3eeded682a69f220cdbc20b018a79b");
+    }
+}
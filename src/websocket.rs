@@ -0,0 +1,396 @@
+//! Just enough of WebSocket (RFC 6455) for the browsers to chat: the upgrade handshake and the
+//! text frames. The Server feeds the received text into the same FrameReader as the raw TCP
+//! clients, so the rest of it does not know the difference.
+
+use crate::protocol::FRAME_SEPARATOR;
+
+/// Upgrade requests bigger than that are not from a browser
+pub const HANDSHAKE_LIMIT: usize = 4*1024;
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+/// The most the control frames may carry
+const CONTROL_PAYLOAD_LIMIT: usize = 125;
+
+/// State of the WebSocket session on top of a connection
+#[derive(Default)]
+pub struct WebSocket {
+    upgraded: bool,
+    /// The client sent the close frame, nothing is read after it
+    closed: bool,
+    /// Bytes received that do not make up a complete request or frame yet
+    incoming: Vec<u8>,
+    /// The message that still comes in fragments
+    fragments: Vec<u8>,
+    /// Whether the first fragment of a message came and the last one did not yet. The fragments
+    /// may be empty, so `fragments` can't tell that
+    fragmented: bool,
+    /// Payload of the complete messages, each followed by FRAME_SEPARATOR
+    messages: Vec<u8>,
+}
+
+impl WebSocket {
+    /// Handles the bytes received from the client. Whatever has to be sent back (the handshake
+    /// response, pongs, the close frame) is appended to `reply`. Messages longer than `limit` are
+    /// refused without waiting for them to arrive completely
+    pub fn receive(&mut self, bytes: &[u8], reply: &mut Vec<u8>, limit: usize) -> Result<(), String> {
+        if self.closed {
+            return Ok(());
+        }
+        self.incoming.extend_from_slice(bytes);
+        if !self.upgraded {
+            let size = if let Some(size) = find(&self.incoming, b"\r\n\r\n") {
+                size + 4
+            } else if self.incoming.len() > HANDSHAKE_LIMIT {
+                reply.extend_from_slice(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n");
+                return Err("upgrade request is too big".to_string());
+            } else {
+                return Ok(());
+            };
+            let response = match upgrade(&self.incoming[..size]) {
+                Ok(response) => response,
+                Err(err) => {
+                    reply.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+                    return Err(err);
+                }
+            };
+            reply.extend_from_slice(response.as_bytes());
+            self.incoming.drain(..size);
+            self.upgraded = true;
+        }
+        while let Some((frame, size)) = parse_frame(&self.incoming, limit)? {
+            self.incoming.drain(..size);
+            match frame.opcode {
+                // NOTE: binary messages end up in the same place as the text ones, the invalid
+                // UTF-8 is dealt with by the Server
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    match (frame.opcode == OPCODE_CONTINUATION, self.fragmented) {
+                        (true, false) => return Err("continuation frame without a message".to_string()),
+                        (false, true) => return Err("new message before the last fragment of the previous one".to_string()),
+                        _ => {}
+                    }
+                    if self.fragments.len() + frame.payload.len() > limit {
+                        return Err("message is too big".to_string());
+                    }
+                    self.fragments.extend_from_slice(&frame.payload);
+                    self.fragmented = !frame.fin;
+                    if frame.fin {
+                        self.messages.append(&mut self.fragments);
+                        self.messages.push(FRAME_SEPARATOR);
+                    }
+                }
+                OPCODE_CLOSE => {
+                    // NOTE: the close frame is echoed back with the same status code
+                    let status = &frame.payload[..frame.payload.len().min(2)];
+                    encode_frame(OPCODE_CLOSE, status, reply);
+                    self.closed = true;
+                    return Ok(());
+                }
+                OPCODE_PING => encode_frame(OPCODE_PONG, &frame.payload, reply),
+                OPCODE_PONG => {}
+                opcode => return Err(format!("unknown opcode {opcode:#x}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the received messages into the buffer. Returns how many bytes were moved
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let n = buffer.len().min(self.messages.len());
+        buffer[..n].copy_from_slice(&self.messages[..n]);
+        self.messages.drain(..n);
+        n
+    }
+
+    /// Whether the client sent the close frame and there is nothing left to read
+    pub fn is_closed(&self) -> bool {
+        self.closed && self.messages.is_empty()
+    }
+
+    /// Wraps the line into a text frame. Nothing can be sent before the handshake, so the line is
+    /// dropped in that case
+    pub fn send(&self, line: &str, outgoing: &mut Vec<u8>) {
+        if self.upgraded && !self.closed {
+            encode_frame(OPCODE_TEXT, line.as_bytes(), outgoing);
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses the frame at the beginning of the buffer. Returns the frame and how many bytes it took,
+/// None if the frame did not arrive completely yet
+fn parse_frame(buffer: &[u8], limit: usize) -> Result<Option<(Frame, usize)>, String> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0F;
+    if buffer[1] & 0x80 == 0 {
+        return Err("frame from the client is not masked".to_string());
+    }
+    let (len, mut offset) = match buffer[1] & 0x7F {
+        126 => {
+            if buffer.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4)
+        }
+        127 => {
+            if buffer.len() < 10 {
+                return Ok(None);
+            }
+            (u64::from_be_bytes(buffer[2..10].try_into().expect("8 bytes")), 10)
+        }
+        len => (len as u64, 2),
+    };
+    // NOTE: the control frames (close, ping, pong) may come between the fragments of a message,
+    // so they can't be fragmented themselves
+    if opcode & 0x8 != 0 {
+        if !fin {
+            return Err(format!("control frame {opcode:#x} is fragmented"));
+        }
+        if len > CONTROL_PAYLOAD_LIMIT as u64 {
+            return Err(format!("control frame {opcode:#x} of {len} bytes is too big"));
+        }
+    }
+    if len > limit as u64 {
+        return Err(format!("frame of {len} bytes is too big"));
+    }
+    let len = len as usize;
+    if buffer.len() < offset + 4 + len {
+        return Ok(None);
+    }
+    let mask = &buffer[offset..offset + 4];
+    offset += 4;
+    let payload = buffer[offset..offset + len].iter().enumerate().map(|(i, x)| x ^ mask[i%4]).collect();
+    Ok(Some((Frame { fin, opcode, payload }, offset + len)))
+}
+
+/// The frames from the Server are never masked nor fragmented
+fn encode_frame(opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(0x80 | opcode);
+    let len = payload.len();
+    if len < 126 {
+        out.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+}
+
+/// Returns the response to the upgrade request
+fn upgrade(request: &[u8]) -> Result<String, String> {
+    let request = std::str::from_utf8(request).map_err(|_| "upgrade request is not UTF-8".to_string())?;
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    if !request_line.starts_with("GET ") {
+        return Err(format!("unexpected request {request_line:?}"));
+    }
+    let key = lines.filter_map(|line| line.split_once(':')).find_map(|(name, value)| {
+        if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+            Some(value.trim())
+        } else {
+            None
+        }
+    }).ok_or_else(|| "upgrade request has no Sec-WebSocket-Key".to_string())?;
+    Ok(format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n", accept = accept_key(key)))
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{key}{GUID}").as_bytes());
+    base64(digest.as_ref())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, x)| n | (*x as u32) << (16 - 8*i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6*i) & 0x3F) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    const LIMIT: usize = 1024;
+
+    /// The frame the way the browsers send it, masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = Vec::new();
+        encode_frame(opcode, payload, &mut frame);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+        let offset = frame.len() - payload.len();
+        frame.splice(offset..offset, mask);
+        for (i, x) in frame[offset + 4..].iter_mut().enumerate() {
+            *x ^= mask[i%4];
+        }
+        frame
+    }
+
+    fn upgraded() -> WebSocket {
+        let mut socket = WebSocket::default();
+        let mut reply = Vec::new();
+        socket.receive(REQUEST, &mut reply, LIMIT).expect("handshake");
+        socket
+    }
+
+    fn messages(socket: &mut WebSocket) -> String {
+        let mut buffer = [0; LIMIT];
+        let n = socket.read(&mut buffer);
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    }
+
+    #[test]
+    fn framing_rules_are_enforced() {
+        let long = [b'x'; CONTROL_PAYLOAD_LIMIT + 1];
+        let cases: [(&[Vec<u8>], &str); 5] = [
+            (&[client_frame(false, OPCODE_PING, b"")], "control frame 0x9 is fragmented"),
+            (&[client_frame(true, OPCODE_CLOSE, &long)], "control frame 0x8 of 126 bytes is too big"),
+            (&[client_frame(true, OPCODE_CONTINUATION, b"x")], "continuation frame without a message"),
+            (&[client_frame(false, OPCODE_TEXT, b"a"), client_frame(true, OPCODE_TEXT, b"b")], "new message before the last fragment of the previous one"),
+            (&[client_frame(true, OPCODE_TEXT, b"a"), client_frame(true, OPCODE_CONTINUATION, b"b")], "continuation frame without a message"),
+        ];
+        for (frames, err) in cases {
+            let mut socket = upgraded();
+            let mut reply = Vec::new();
+            assert_eq!(socket.receive(&frames.concat(), &mut reply, LIMIT), Err(err.to_string()));
+        }
+
+        // NOTE: an empty first fragment still starts a message
+        let mut socket = upgraded();
+        let mut reply = Vec::new();
+        let frames = [client_frame(false, OPCODE_TEXT, b""), client_frame(true, OPCODE_CONTINUATION, b"hi")].concat();
+        assert_eq!(socket.receive(&frames, &mut reply, LIMIT), Ok(()));
+        assert_eq!(messages(&mut socket), "hi\n");
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+
+        let mut socket = WebSocket::default();
+        let mut reply = Vec::new();
+        // NOTE: the request may come in pieces as well
+        socket.receive(&REQUEST[..20], &mut reply, LIMIT).expect("first half");
+        assert!(reply.is_empty());
+        socket.receive(&REQUEST[20..], &mut reply, LIMIT).expect("second half");
+        let reply = String::from_utf8(reply).expect("response");
+        assert!(reply.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(reply.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let mut socket = WebSocket::default();
+        let mut reply = Vec::new();
+        assert!(socket.receive(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n", &mut reply, LIMIT).is_err());
+        assert!(reply.starts_with(b"HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn masked_frame_is_read_across_receives() {
+        let mut socket = upgraded();
+        let mut reply = Vec::new();
+        let frame = client_frame(true, OPCODE_TEXT, "héllo".as_bytes());
+        let (first, second) = frame.split_at(4);
+        socket.receive(first, &mut reply, LIMIT).expect("first part");
+        assert_eq!(messages(&mut socket), "");
+        socket.receive(second, &mut reply, LIMIT).expect("second part");
+        assert_eq!(messages(&mut socket), "héllo\n");
+        assert!(reply.is_empty());
+
+        // NOTE: the 16 bit length
+        let long = "x".repeat(300);
+        socket.receive(&client_frame(true, OPCODE_TEXT, long.as_bytes()), &mut reply, LIMIT).expect("long frame");
+        assert_eq!(messages(&mut socket), format!("{long}\n"));
+    }
+
+    #[test]
+    fn ping_is_answered_between_fragments() {
+        let mut socket = upgraded();
+        let mut reply = Vec::new();
+        let frames = [
+            client_frame(false, OPCODE_TEXT, b"hel"),
+            client_frame(true, OPCODE_PING, b"are you there"),
+            client_frame(false, OPCODE_CONTINUATION, b"lo "),
+            client_frame(true, OPCODE_PONG, b""),
+            client_frame(true, OPCODE_CONTINUATION, b"world"),
+        ].concat();
+        socket.receive(&frames, &mut reply, LIMIT).expect("frames");
+        assert_eq!(messages(&mut socket), "hello world\n");
+        let mut pong = Vec::new();
+        encode_frame(OPCODE_PONG, b"are you there", &mut pong);
+        assert_eq!(reply, pong);
+    }
+
+    #[test]
+    fn close_is_echoed() {
+        let mut socket = upgraded();
+        let mut reply = Vec::new();
+        let frames = [client_frame(true, OPCODE_TEXT, b"bye"), client_frame(true, OPCODE_CLOSE, &[0x03, 0xE8, b'o', b'k']), client_frame(true, OPCODE_TEXT, b"ignored")].concat();
+        socket.receive(&frames, &mut reply, LIMIT).expect("frames");
+        assert_eq!(reply, [0x88, 0x02, 0x03, 0xE8]);
+        assert!(!socket.is_closed());
+        assert_eq!(messages(&mut socket), "bye\n");
+        assert!(socket.is_closed());
+        let mut outgoing = Vec::new();
+        socket.send("too late", &mut outgoing);
+        assert!(outgoing.is_empty());
+    }
+
+    #[test]
+    fn broken_frames_are_refused() {
+        let mut socket = upgraded();
+        let mut reply = Vec::new();
+        let mut unmasked = Vec::new();
+        encode_frame(OPCODE_TEXT, b"hi", &mut unmasked);
+        assert_eq!(socket.receive(&unmasked, &mut reply, LIMIT), Err("frame from the client is not masked".to_string()));
+
+        // NOTE: the length alone is enough to refuse the frame, the payload is never waited for
+        let mut socket = upgraded();
+        let header = [0x81, 0x80 | 127, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert_eq!(socket.receive(&header, &mut reply, LIMIT), Err(format!("frame of {len} bytes is too big", len = 1u64 << 32)));
+
+        let mut socket = upgraded();
+        let fragment = client_frame(false, OPCODE_TEXT, &[b'x'; LIMIT]);
+        let frames = [fragment, client_frame(true, OPCODE_CONTINUATION, b"x")].concat();
+        assert_eq!(socket.receive(&frames, &mut reply, LIMIT), Err("message is too big".to_string()));
+
+        let mut socket = upgraded();
+        assert_eq!(socket.receive(&client_frame(true, 0x3, b""), &mut reply, LIMIT), Err("unknown opcode 0x3".to_string()));
+    }
+}
@@ -0,0 +1,29 @@
+//! The safe mode is a global flag, so it is flipped in a process of its own rather than next to
+//! the tests of the Server, which format their addresses with Sens as well.
+
+use std::env;
+use std::net::SocketAddr;
+use fourat::sens::{Sens, SAFE_MODE_ENV, set_safe_mode, safe_mode_from_env};
+
+#[test]
+fn safe_mode_redacts() {
+    let addr: SocketAddr = "[2001:db8::1]:6969".parse().expect("address");
+    set_safe_mode(false);
+    assert_eq!(format!("{}", Sens(addr)), "[2001:db8::1]:6969");
+    assert_eq!(format!("connected from {addr}", addr = Sens(addr)), "connected from [2001:db8::1]:6969");
+    set_safe_mode(true);
+    assert_eq!(format!("{}", Sens(addr)), "[REDACTED]");
+    assert_eq!(format!("{}", Sens("could not connect to 10.0.0.1")), "[REDACTED]");
+    set_safe_mode(false);
+    assert_eq!(format!("{}", Sens(addr.ip())), "2001:db8::1");
+
+    env::remove_var(SAFE_MODE_ENV);
+    assert!(!safe_mode_from_env());
+    env::set_var(SAFE_MODE_ENV, "0");
+    assert!(!safe_mode_from_env());
+    env::set_var(SAFE_MODE_ENV, "1");
+    assert!(safe_mode_from_env());
+    env::set_var(SAFE_MODE_ENV, "yes");
+    assert!(safe_mode_from_env());
+    env::remove_var(SAFE_MODE_ENV);
+}
@@ -0,0 +1,131 @@
+//! Runs the actual binaries the way the users do, talking to the Server over TCP with nothing
+//! but the protocol module of the library.
+
+use std::fs;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::io::{BufRead, BufReader, Read};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use fourat::protocol::{FrameReader, ECHO_TAG, WELCOME, write_frame, split_sequence, split_stamp};
+
+/// How long a test waits for the Server to say anything before giving up
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The Server running in its own directory, killed and cleaned up if the test fails midway
+struct Running {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Starts the Server on a free port and returns it with the port it listens to
+fn start_server(name: &str) -> (Running, u16) {
+    let dir = env::temp_dir().join(format!("4at-{name}-{pid}", pid = std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("test directory");
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--bind", "127.0.0.1", "--port", "0"])
+        .current_dir(&dir)
+        .env_remove("FOURAT_SAFE_MODE")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("server binary");
+    let mut running = Running { child, dir };
+    let stdout = running.child.stdout.take().expect("stdout");
+    let (sender, receiver) = mpsc::channel();
+    // NOTE: reading the output till the end, otherwise the Server fails to print into the closed pipe
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if let Some(port) = line.strip_prefix("INFO: listening to 127.0.0.1:").and_then(|port| port.parse::<u16>().ok()) {
+                let _ = sender.send(port);
+            }
+        }
+    });
+    let port = receiver.recv_timeout(READ_TIMEOUT).expect("the Server never said where it listens");
+    (running, port)
+}
+
+struct Peer {
+    stream: TcpStream,
+    frames: FrameReader,
+}
+
+impl Peer {
+    fn connect(port: u16, token: &str) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).expect("connect");
+        stream.set_read_timeout(Some(READ_TIMEOUT)).expect("read timeout");
+        let mut peer = Self { stream, frames: FrameReader::default() };
+        peer.send(token);
+        peer.wait_for(|line| line == WELCOME);
+        peer
+    }
+
+    fn send(&mut self, line: &str) {
+        write_frame(&mut self.stream, line).expect("send");
+    }
+
+    /// Reads the lines without the sequence numbers and the stamps until one matches
+    fn wait_for(&mut self, matches: impl Fn(&str) -> bool) -> String {
+        loop {
+            while let Some(frame) = self.frames.next_frame() {
+                let (_, frame) = split_sequence(&frame);
+                let line = String::from_utf8_lossy(frame).to_string();
+                let (echo, line) = match line.strip_prefix(ECHO_TAG) {
+                    Some(line) => (true, line),
+                    None => (false, line.as_str()),
+                };
+                let line = split_stamp(line).map(|(_, line)| line).unwrap_or(line);
+                let line = if echo { format!("{ECHO_TAG}{line}") } else { line.to_string() };
+                if matches(&line) {
+                    return line;
+                }
+            }
+            let mut buffer = [0; 1024];
+            let n = self.stream.read(&mut buffer).expect("the Server went silent");
+            assert!(n > 0, "the Server closed the connection");
+            self.frames.extend(&buffer[..n]);
+        }
+    }
+}
+
+fn first_word(file_path: &Path) -> String {
+    let content = fs::read_to_string(file_path).expect("token file");
+    content.split_whitespace().next().expect("token").to_string()
+}
+
+#[test]
+fn all_binaries_are_built() {
+    for binary in [env!("CARGO_BIN_EXE_server"), env!("CARGO_BIN_EXE_client"), env!("CARGO_BIN_EXE_pandora")] {
+        assert!(Path::new(binary).is_file(), "{binary} is missing");
+    }
+}
+
+#[test]
+fn server_delivers_messages_and_shuts_down() {
+    let (mut running, port) = start_server("server_delivers_messages_and_shuts_down");
+    let token = first_word(&running.dir.join("TOKENS"));
+    let admin_token = first_word(&running.dir.join("ADMIN_TOKEN"));
+
+    let mut alice = Peer::connect(port, &token);
+    let mut admin = Peer::connect(port, &admin_token);
+    alice.send("hello");
+    alice.wait_for(|line| line == format!("{ECHO_TAG}<#1> hello"));
+    admin.wait_for(|line| line == "<#1> hello");
+
+    admin.send("/shutdown");
+    let status = running.child.wait().expect("server exit");
+    assert!(status.success());
+    assert!(!running.dir.join("ADMIN_TOKEN").exists());
+}